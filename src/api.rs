@@ -0,0 +1,464 @@
+use crate::{
+    coalesce::{Answer, Coalescer, Stats},
+    ids::Entry,
+    resolver::{Deleted, Enrolled, EntryRef, QueryError, Resolver, Selection},
+    shutdown::Signals,
+};
+use anyhow::{bail, Result};
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        DefaultBodyLimit, FromRef, Query, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::future::try_join_all;
+use mpc_iris_code::Template;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    future::{Future, IntoFuture},
+    pin::pin,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::oneshot};
+
+/// Request size limit for batches, room for a few thousand templates.
+const MAX_BATCH_BYTES: usize = 32 << 20;
+
+/// HTTP API of the resolver.
+///
+/// `POST /query` takes a [`Template`] as JSON and returns its [`Answer`], the
+/// query result with its latency. `POST /query/batch` takes an array of
+/// templates and returns an array of answers in the same order. All queries
+/// are coalesced into shared database scans. Both take the optional URL
+/// parameters `k` and `threshold` of the [`Selection`] to return a list of
+/// closest entries instead of only the closest, and `timeout_ms` to override
+/// the resolver's query deadline. Results include the external
+/// identifiers of entries that have one. `POST /enroll` takes an array of
+/// templates with optional `id`s, appends them to the database and returns the
+/// [`Enrolled`] range of indices. `POST /delete` takes an array of entry
+/// indices or identifiers to exclude from results until compaction and
+/// returns [`Deleted`]. `GET /stats`
+/// returns cumulative latency [`Stats`]. Errors are returned as `{"error":
+/// "..."}` with status 400 for invalid requests, 503 when too few participants
+/// are reachable, 502 when a participant fails during the request, 504 when a
+/// query misses its deadline and 404 when the database is empty.
+pub fn router<U: Updater>(resolver: Arc<U>, coalescer: Arc<Coalescer>) -> Router {
+    Router::new()
+        .route("/query", post(query))
+        .route(
+            "/query/batch",
+            post(query_batch).layer(DefaultBodyLimit::max(MAX_BATCH_BYTES)),
+        )
+        .route(
+            "/enroll",
+            post(enroll::<U>).layer(DefaultBodyLimit::max(MAX_BATCH_BYTES)),
+        )
+        .route("/delete", post(delete::<U>))
+        .route("/stats", get(stats))
+        .with_state(ApiState {
+            resolver,
+            coalescer,
+        })
+}
+
+/// Updates the entries, see [`Resolver::enroll`] and [`Resolver::delete`].
+pub trait Updater: Send + Sync + 'static {
+    fn enroll(
+        &self,
+        entries: &[Entry],
+    ) -> impl Future<Output = Result<Enrolled, QueryError>> + Send;
+
+    fn delete(
+        &self,
+        entries: &[EntryRef],
+    ) -> impl Future<Output = Result<Deleted, QueryError>> + Send;
+}
+
+impl Updater for Resolver {
+    fn enroll(
+        &self,
+        entries: &[Entry],
+    ) -> impl Future<Output = Result<Enrolled, QueryError>> + Send {
+        Resolver::enroll(self, entries)
+    }
+
+    fn delete(
+        &self,
+        entries: &[EntryRef],
+    ) -> impl Future<Output = Result<Deleted, QueryError>> + Send {
+        Resolver::delete(self, entries)
+    }
+}
+
+/// Deadline of queries, counted from their submission.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+struct Deadline {
+    timeout_ms: Option<u64>,
+}
+
+impl Deadline {
+    fn timeout(&self) -> Result<Option<Duration>, ApiError> {
+        match self.timeout_ms {
+            Some(0) => Err(ApiError::Request("timeout_ms must be positive.".into())),
+            timeout_ms => Ok(timeout_ms.map(Duration::from_millis)),
+        }
+    }
+}
+
+/// Serve the API until shut down by `signals`. Fails if requests had to be
+/// cancelled.
+///
+/// On the first signal no further requests are accepted, and those in flight
+/// are given `grace` to finish. Queries are then cancelled, while enrollments
+/// and deletions in progress still complete.
+pub async fn serve(
+    listener: TcpListener,
+    resolver: Arc<Resolver>,
+    coalescer: Arc<Coalescer>,
+    mut signals: Signals,
+    grace: Duration,
+) -> Result<()> {
+    let (shutdown, signalled) = oneshot::channel();
+    let mut server = pin!(
+        axum::serve(listener, router(resolver.clone(), coalescer.clone()))
+            .with_graceful_shutdown(async {
+                let _ = signalled.await;
+            })
+            .into_future()
+    );
+    tokio::select! {
+        served = &mut server => return Ok(served?),
+        name = signals.recv() => eprintln!("Received {name}, shutting down."),
+    }
+    let _ = shutdown.send(());
+    let finished = signals.drain(grace, async {
+        if let Err(err) = server.await {
+            eprintln!("Server failed: {err:#}");
+        }
+    });
+    if finished.await {
+        eprintln!("All requests finished.");
+        return Ok(());
+    }
+    coalescer.cancel();
+    let _updates = resolver.pause_updates().await;
+    bail!("Cancelled requests in flight on shutdown.");
+}
+
+struct ApiState<U> {
+    resolver:  Arc<U>,
+    coalescer: Arc<Coalescer>,
+}
+
+impl<U> Clone for ApiState<U> {
+    fn clone(&self) -> Self {
+        Self {
+            resolver:  self.resolver.clone(),
+            coalescer: self.coalescer.clone(),
+        }
+    }
+}
+
+impl<U: Updater> FromRef<ApiState<U>> for Arc<U> {
+    fn from_ref(state: &ApiState<U>) -> Self {
+        state.resolver.clone()
+    }
+}
+
+impl<U> FromRef<ApiState<U>> for Arc<Coalescer> {
+    fn from_ref(state: &ApiState<U>) -> Self {
+        state.coalescer.clone()
+    }
+}
+
+async fn query(
+    State(coalescer): State<Arc<Coalescer>>,
+    selection: Result<Query<Selection>, QueryRejection>,
+    deadline: Result<Query<Deadline>, QueryRejection>,
+    template: Result<Json<Template>, JsonRejection>,
+) -> Result<Json<Answer>, ApiError> {
+    let Query(selection) = selection?;
+    selection.validate().map_err(ApiError::invalid)?;
+    let timeout = deadline?.timeout()?;
+    let Json(template) = template?;
+    Ok(Json(coalescer.query(template, selection, timeout).await?))
+}
+
+async fn query_batch(
+    State(coalescer): State<Arc<Coalescer>>,
+    selection: Result<Query<Selection>, QueryRejection>,
+    deadline: Result<Query<Deadline>, QueryRejection>,
+    templates: Result<Json<Vec<Template>>, JsonRejection>,
+) -> Result<Json<Vec<Answer>>, ApiError> {
+    let Query(selection) = selection?;
+    selection.validate().map_err(ApiError::invalid)?;
+    let timeout = deadline?.timeout()?;
+    let Json(templates) = templates?;
+    eprintln!("API batch of {} queries received.", templates.len());
+    let answers = try_join_all(
+        templates
+            .into_iter()
+            .map(|template| coalescer.query(template, selection, timeout)),
+    )
+    .await?;
+    Ok(Json(answers))
+}
+
+async fn enroll<U: Updater>(
+    State(resolver): State<Arc<U>>,
+    entries: Result<Json<Vec<Entry>>, JsonRejection>,
+) -> Result<Json<Enrolled>, ApiError> {
+    let Json(entries) = entries?;
+    eprintln!("API enrollment of {} templates received.", entries.len());
+    let enrolled = resolver.enroll(&entries).await.inspect_err(|err| {
+        eprintln!("{err}");
+    })?;
+    Ok(Json(enrolled))
+}
+
+async fn delete<U: Updater>(
+    State(resolver): State<Arc<U>>,
+    entries: Result<Json<Vec<EntryRef>>, JsonRejection>,
+) -> Result<Json<Deleted>, ApiError> {
+    let Json(entries) = entries?;
+    eprintln!("API deletion of {} entries received.", entries.len());
+    let deleted = resolver.delete(&entries).await.inspect_err(|err| {
+        eprintln!("{err}");
+    })?;
+    Ok(Json(deleted))
+}
+
+async fn stats(State(coalescer): State<Arc<Coalescer>>) -> Json<Stats> {
+    Json(coalescer.stats())
+}
+
+enum ApiError {
+    Request(String),
+    Query(QueryError),
+}
+
+impl ApiError {
+    fn invalid(err: anyhow::Error) -> Self {
+        Self::Request(err.to_string())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(err: JsonRejection) -> Self {
+        Self::Request(err.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(err: QueryRejection) -> Self {
+        Self::Request(err.body_text())
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(err: QueryError) -> Self {
+        Self::Query(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            Self::Request(message) => (StatusCode::BAD_REQUEST, message),
+            Self::Query(err) => {
+                let status = match err {
+                    QueryError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                    QueryError::Failed(_) => StatusCode::BAD_GATEWAY,
+                    QueryError::Empty => StatusCode::NOT_FOUND,
+                    QueryError::Rejected(_) => StatusCode::BAD_REQUEST,
+                    QueryError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                };
+                (status, err.to_string())
+            }
+        };
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{coalesce::Evaluator, resolver::QueryResult};
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use rand::{thread_rng, Rng};
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    /// Answers queries after a delay, or fails them. Only knows the
+    /// identifier "a".
+    struct Stub {
+        delay: Duration,
+        error: Option<QueryError>,
+    }
+
+    impl Evaluator for Stub {
+        fn query_batch(
+            &self,
+            queries: &[Template],
+            _selections: &[Selection],
+        ) -> impl Future<Output = Result<Vec<QueryResult>, QueryError>> + Send {
+            let (delay, error, count) = (self.delay, self.error.clone(), queries.len());
+            async move {
+                tokio::time::sleep(delay).await;
+                if let Some(err) = error {
+                    return Err(err);
+                }
+                Ok((0..count)
+                    .map(|index| QueryResult::Closest {
+                        index,
+                        id: None,
+                        distance: 0.25,
+                        rotation: 0,
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    impl Updater for Stub {
+        fn enroll(
+            &self,
+            entries: &[Entry],
+        ) -> impl Future<Output = Result<Enrolled, QueryError>> + Send {
+            let count = entries.len();
+            async move { Ok(Enrolled { first: 0, count }) }
+        }
+
+        fn delete(
+            &self,
+            entries: &[EntryRef],
+        ) -> impl Future<Output = Result<Deleted, QueryError>> + Send {
+            let unknown = entries.iter().find_map(|entry| match entry {
+                EntryRef::Id(id) if id != "a" => Some(id.clone()),
+                _ => None,
+            });
+            let deleted = entries.len();
+            async move {
+                if let Some(id) = unknown {
+                    return Err(QueryError::Rejected(Arc::new(anyhow::format_err!(
+                        "Unknown identifier {id:?}."
+                    ))));
+                }
+                Ok(Deleted {
+                    deleted,
+                    tombstones: deleted,
+                })
+            }
+        }
+    }
+
+    fn app(delay_ms: u64, error: Option<QueryError>) -> Router {
+        let stub = Arc::new(Stub {
+            delay: Duration::from_millis(delay_ms),
+            error,
+        });
+        let coalescer = Coalescer::new(stub.clone(), Duration::ZERO, 8, None);
+        router(stub, Arc::new(coalescer))
+    }
+
+    /// Send `body` to `uri`, returning the status and the JSON response.
+    async fn post(app: Router, uri: &str, body: impl Into<Body>) -> (StatusCode, Value) {
+        let request = Request::post(uri)
+            .header("content-type", "application/json")
+            .body(body.into())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn template() -> String {
+        serde_json::to_string(&thread_rng().gen::<Template>()).unwrap()
+    }
+
+    /// The message of an error response.
+    fn error(body: &Value) -> &str {
+        body["error"].as_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let (status, body) = post(app(0, None), "/query", template()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["index"], 0);
+        assert_eq!(body["latency"]["batch_size"], 1);
+
+        let batch = format!("[{},{}]", template(), template());
+        let (status, body) = post(app(0, None), "/query/batch", batch).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request() {
+        let (status, body) = post(app(0, None), "/query", "{\"pattern\":").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(!error(&body).is_empty());
+        let (status, body) = post(app(0, None), "/query?k=0", template()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        let (status, body) = post(app(0, None), "/query?timeout_ms=0", template()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error(&body).contains("timeout_ms"), "{body}");
+        let (status, _) = post(app(0, None), "/enroll", "[{}]").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let (status, body) = post(app(0, None), "/delete", "[\"a\", 3]").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["deleted"], 2);
+        let (status, body) = post(app(0, None), "/delete", "[\"b\"]").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error(&body).contains("Unknown identifier \"b\""), "{body}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline() {
+        let (status, body) = post(app(1000, None), "/query?timeout_ms=10", template()).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(!error(&body).is_empty());
+        let (status, _) = post(app(5, None), "/query?timeout_ms=10", template()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_query_errors() {
+        let err = || Arc::new(anyhow::format_err!("Participant 1 hung up."));
+        let errors = [
+            (QueryError::Failed(err()), StatusCode::BAD_GATEWAY),
+            (
+                QueryError::Unavailable(err()),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (QueryError::Empty, StatusCode::NOT_FOUND),
+        ];
+        for (err, expected) in errors {
+            let (status, body) = post(app(0, Some(err)), "/query", template()).await;
+            assert_eq!(status, expected, "{body}");
+            assert!(!error(&body).is_empty());
+        }
+        let (status, body) = post(
+            app(0, Some(QueryError::Failed(err()))),
+            "/query",
+            template(),
+        )
+        .await;
+        assert!(error(&body).contains("hung up"), "{status} {body}");
+    }
+}
@@ -28,6 +28,16 @@ impl Bits {
         copy
     }
 
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(index < BITS);
+        let (limb, bit) = (index / 64, index % 64);
+        if value {
+            self.0[limb] |= 1_u64 << bit;
+        } else {
+            self.0[limb] &= !(1_u64 << bit);
+        }
+    }
+
     pub fn count_ones(&self) -> u16 {
         self.0.iter().map(|n| n.count_ones() as u16).sum()
     }
@@ -231,6 +241,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_set() {
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let bits: Bits = rng.gen();
+            let mut copy = Bits::default();
+            for location in 0..BITS {
+                copy.set(location, bits[location]);
+            }
+            assert_eq!(copy, bits);
+        }
+    }
+
     #[test]
     fn test_rotated_inverse() {
         let mut rng = thread_rng();
@@ -0,0 +1,462 @@
+use crate::resolver::{QueryError, QueryResult, Resolver, Selection};
+use futures::{stream::FuturesUnordered, StreamExt};
+use mpc_iris_code::Template;
+use serde::Serialize;
+use std::{
+    future::Future,
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    time::{timeout_at, Instant},
+};
+
+/// Number of queries that can wait for a scan before submitters block.
+const QUEUE_SIZE: usize = 4096;
+
+/// Answer to a single query, with its latency.
+#[derive(Clone, Debug, Serialize)]
+pub struct Answer {
+    #[serde(flatten)]
+    pub result:  QueryResult,
+    pub latency: Latency,
+}
+
+/// Time a query spent waiting and being evaluated.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Latency {
+    /// Number of queries evaluated in the same round.
+    pub batch_size: usize,
+    /// Time from submission until the round started.
+    pub queued_ms:  f64,
+    /// Time spent evaluating the round.
+    pub scan_ms:    f64,
+}
+
+/// Cumulative latency statistics over all rounds.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Stats {
+    pub queries:         usize,
+    pub rounds:          usize,
+    pub failed:          usize,
+    pub mean_batch_size: f64,
+    pub mean_queued_ms:  f64,
+    pub max_queued_ms:   f64,
+    pub mean_scan_ms:    f64,
+    pub mean_total_ms:   f64,
+    pub max_total_ms:    f64,
+}
+
+/// Evaluates rounds of queries, see [`Resolver::query_batch`].
+pub trait Evaluator: Send + Sync + 'static {
+    fn query_batch(
+        &self,
+        queries: &[Template],
+        selections: &[Selection],
+    ) -> impl Future<Output = Result<Vec<QueryResult>, QueryError>> + Send;
+}
+
+impl Evaluator for Resolver {
+    fn query_batch(
+        &self,
+        queries: &[Template],
+        selections: &[Selection],
+    ) -> impl Future<Output = Result<Vec<QueryResult>, QueryError>> + Send {
+        Resolver::query_batch(self, queries, selections)
+    }
+}
+
+struct Pending {
+    query:     Template,
+    selection: Selection,
+    submitted: Instant,
+    reply:     oneshot::Sender<Result<Answer, QueryError>>,
+}
+
+/// Queues incoming queries and groups them into multi-query rounds.
+///
+/// A round starts with the oldest waiting query and collects further queries
+/// until `window` has passed since it was submitted or `max_batch` queries are
+/// collected. Queries arriving while a round is evaluated are queued for the
+/// next one, so under load rounds fill up without waiting for the window.
+///
+/// A query past its deadline is abandoned by its submitter. It is left out of
+/// rounds that have yet to start. A round of which half the queries are
+/// abandoned is stopped, cancelling the scans of the participants, and the
+/// remaining queries are restarted right away in a round of their own. A
+/// single query with a long deadline thus does not keep a scan for many
+/// others running, and restarts at least halve a round, so they end.
+pub struct Coalescer {
+    queue:   mpsc::Sender<Pending>,
+    stats:   Arc<Mutex<Stats>>,
+    /// Deadline of queries that do not set their own.
+    timeout: Option<Duration>,
+    /// Set on shutdown to fail all queries, see [`Self::cancel`].
+    cancel:  watch::Sender<bool>,
+}
+
+impl Coalescer {
+    pub fn new(
+        resolver: Arc<impl Evaluator>,
+        window: Duration,
+        max_batch: usize,
+        timeout: Option<Duration>,
+    ) -> Self {
+        assert!(max_batch > 0);
+        let (queue, receiver) = mpsc::channel(QUEUE_SIZE);
+        let stats = Arc::new(Mutex::new(Stats::default()));
+        let cancel = watch::Sender::new(false);
+        tokio::spawn(run(
+            resolver,
+            receiver,
+            window,
+            max_batch,
+            stats.clone(),
+            cancel.subscribe(),
+        ));
+        Self {
+            queue,
+            stats,
+            timeout,
+            cancel,
+        }
+    }
+
+    /// Submit a query and wait for the round that evaluates it, for at most
+    /// `timeout` from now or the default deadline.
+    pub async fn query(
+        &self,
+        query: Template,
+        selection: Selection,
+        timeout: Option<Duration>,
+    ) -> Result<Answer, QueryError> {
+        let (reply, answer) = oneshot::channel();
+        let submitted = Instant::now();
+        let pending = Pending {
+            query,
+            selection,
+            submitted,
+            reply,
+        };
+        let closed = || QueryError::Failed(Arc::new(anyhow::format_err!("Resolver stopped.")));
+        let evaluated = async {
+            self.queue.send(pending).await.map_err(|_| closed())?;
+            answer.await.map_err(|_| closed())?
+        };
+        match timeout.or(self.timeout) {
+            Some(timeout) => timeout_at(submitted + timeout, evaluated)
+                .await
+                .map_err(|_| QueryError::Timeout)?,
+            None => evaluated.await,
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Fail the round in progress and all queries submitted from now on, as
+    /// the resolver is shutting down.
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+}
+
+async fn run(
+    resolver: Arc<impl Evaluator>,
+    mut receiver: mpsc::Receiver<Pending>,
+    window: Duration,
+    max_batch: usize,
+    stats: Arc<Mutex<Stats>>,
+    mut cancel: watch::Receiver<bool>,
+) {
+    // Remaining queries of a stopped round, evaluated before any new ones.
+    let mut restart = Vec::new();
+    loop {
+        let mut round = if restart.is_empty() {
+            let Some(first) = receiver.recv().await else {
+                break;
+            };
+
+            // Collect queries until the window closes or the round is full.
+            let deadline = first.submitted + window;
+            let mut round = vec![first];
+            while round.len() < max_batch {
+                match timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(pending)) => round.push(pending),
+                    _ => break,
+                }
+            }
+            round
+        } else {
+            mem::take(&mut restart)
+        };
+
+        // Leave out queries abandoned while waiting.
+        round.retain(|pending| !pending.reply.is_closed());
+        if round.is_empty() {
+            continue;
+        }
+
+        let start = Instant::now();
+        let queries = round
+            .iter()
+            .map(|pending| pending.query)
+            .collect::<Vec<_>>();
+        eprintln!("Evaluating round of {} queries.", queries.len());
+        let selections = round
+            .iter()
+            .map(|pending| pending.selection)
+            .collect::<Vec<_>>();
+        let mut closed = round
+            .iter_mut()
+            .map(|pending| pending.reply.closed())
+            .collect::<FuturesUnordered<_>>();
+        let abandoned = async {
+            let mut abandoned = 0;
+            while closed.next().await.is_some() {
+                abandoned += 1;
+                if 2 * abandoned >= queries.len() {
+                    break;
+                }
+            }
+        };
+        let cancelled =
+            || QueryError::Unavailable(Arc::new(anyhow::format_err!("Resolver is shutting down.")));
+        let results = tokio::select! {
+            // Once cancelled, rounds fail without starting.
+            biased;
+            _ = cancel.wait_for(|&cancel| cancel) => Some(Err(cancelled())),
+            _ = abandoned => None,
+            results = resolver.query_batch(&queries, &selections) => Some(results),
+        };
+        drop(closed);
+        let scan = start.elapsed();
+        let latency = |pending: &Pending| Latency {
+            batch_size: queries.len(),
+            queued_ms:  millis(start - pending.submitted),
+            scan_ms:    millis(scan),
+        };
+
+        // Abandoned queries failed, the others start over.
+        let Some(results) = results else {
+            let (abandoned, remaining) = round
+                .into_iter()
+                .partition::<Vec<_>, _>(|pending| pending.reply.is_closed());
+            eprintln!(
+                "Round of {} queries abandoned, restarting {}.",
+                queries.len(),
+                remaining.len()
+            );
+            let latencies = abandoned.iter().map(latency).collect::<Vec<_>>();
+            stats.lock().unwrap().record(&latencies, false);
+            restart = remaining;
+            continue;
+        };
+
+        let latencies = round.iter().map(latency).collect::<Vec<_>>();
+        stats.lock().unwrap().record(&latencies, results.is_ok());
+        if let Err(err) = &results {
+            eprintln!("{err}");
+        }
+
+        // Reply to submitters, who may have gone away in the meantime.
+        match results {
+            Ok(results) => {
+                for ((pending, result), latency) in round.into_iter().zip(results).zip(latencies) {
+                    let _ = pending.reply.send(Ok(Answer { result, latency }));
+                }
+            }
+            Err(err) => {
+                for pending in round {
+                    let _ = pending.reply.send(Err(err.clone()));
+                }
+            }
+        }
+    }
+}
+
+impl Stats {
+    fn record(&mut self, latencies: &[Latency], ok: bool) {
+        let n = latencies.len();
+        let total = (self.queries + n) as f64;
+        let update = |mean: &mut f64, sum: f64| {
+            *mean += (sum - *mean * n as f64) / total;
+        };
+        let queued = latencies.iter().map(|l| l.queued_ms).sum();
+        let scan = latencies.iter().map(|l| l.scan_ms).sum();
+        update(&mut self.mean_queued_ms, queued);
+        update(&mut self.mean_scan_ms, scan);
+        update(&mut self.mean_total_ms, queued + scan);
+        for latency in latencies {
+            self.max_queued_ms = self.max_queued_ms.max(latency.queued_ms);
+            self.max_total_ms = self.max_total_ms.max(latency.queued_ms + latency.scan_ms);
+        }
+        self.queries += n;
+        self.rounds += 1;
+        if !ok {
+            self.failed += n;
+        }
+        self.mean_batch_size = self.queries as f64 / self.rounds as f64;
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use futures::future::join_all;
+    use rand::{thread_rng, Rng};
+    use tokio::{join, time::sleep};
+
+    /// Evaluates rounds after a delay, recording the `k` of their queries.
+    struct Mock {
+        delay:  Duration,
+        rounds: Mutex<Vec<Vec<usize>>>,
+    }
+
+    impl Evaluator for Mock {
+        fn query_batch(
+            &self,
+            queries: &[Template],
+            selections: &[Selection],
+        ) -> impl Future<Output = Result<Vec<QueryResult>, QueryError>> + Send {
+            let tags = selections.iter().map(|s| s.k.unwrap()).collect();
+            self.rounds.lock().unwrap().push(tags);
+            let (delay, count) = (self.delay, queries.len());
+            async move {
+                sleep(delay).await;
+                Ok((0..count)
+                    .map(|_| QueryResult::Matches {
+                        matches: Vec::new(),
+                        ids:     Vec::new(),
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    fn coalescer(delay_ms: u64, window_ms: u64, max_batch: usize) -> (Arc<Mock>, Coalescer) {
+        let mock = Arc::new(Mock {
+            delay:  Duration::from_millis(delay_ms),
+            rounds: Mutex::new(Vec::new()),
+        });
+        let window = Duration::from_millis(window_ms);
+        let coalescer = Coalescer::new(mock.clone(), window, max_batch, None);
+        (mock, coalescer)
+    }
+
+    /// Submit a random query tagged as `k`, with a timeout in milliseconds.
+    async fn submit(
+        coalescer: &Coalescer,
+        k: usize,
+        timeout: Option<u64>,
+    ) -> Result<Latency, QueryError> {
+        let selection = Selection {
+            k: Some(k),
+            ..Default::default()
+        };
+        let timeout = timeout.map(Duration::from_millis);
+        let answer = coalescer
+            .query(thread_rng().gen(), selection, timeout)
+            .await?;
+        Ok(answer.latency)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_window() {
+        let (mock, coalescer) = coalescer(100, 10, 8);
+        let latencies = join_all((0..3).map(|k| submit(&coalescer, k, None))).await;
+        for latency in latencies {
+            let latency = latency.unwrap();
+            assert_eq!(latency.batch_size, 3);
+            assert_float_eq!(latency.queued_ms, 10.0, abs <= 1.0);
+            assert_float_eq!(latency.scan_ms, 100.0, abs <= 1.0);
+        }
+        submit(&coalescer, 3, None).await.unwrap();
+        assert_eq!(*mock.rounds.lock().unwrap(), [vec![0, 1, 2], vec![3]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_batch() {
+        let (mock, coalescer) = coalescer(100, 1000, 2);
+        let latencies = join_all((0..3).map(|k| submit(&coalescer, k, None))).await;
+        let latencies = latencies
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        // A full round starts right away, the rest waits for its window.
+        assert_eq!(*mock.rounds.lock().unwrap(), [vec![0, 1], vec![2]]);
+        assert_eq!(latencies[0].batch_size, 2);
+        assert_float_eq!(latencies[0].queued_ms, 0.0, abs <= 1.0);
+        assert_eq!(latencies[2].batch_size, 1);
+        assert_float_eq!(latencies[2].queued_ms, 1000.0, abs <= 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_abandoned() {
+        // A query abandoned while waiting is left out.
+        let (mock, coalescer) = coalescer(100, 10, 8);
+        let (first, second) = join!(submit(&coalescer, 0, Some(5)), submit(&coalescer, 1, None));
+        assert!(matches!(first, Err(QueryError::Timeout)));
+        assert_eq!(second.unwrap().batch_size, 1);
+        assert_eq!(*mock.rounds.lock().unwrap(), [vec![1]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restart() {
+        // Two of three queries are abandoned during the scan, the remaining
+        // one restarts alone before a query submitted in the meantime.
+        let (mock, coalescer) = coalescer(100, 10, 8);
+        let late = async {
+            sleep(Duration::from_millis(20)).await;
+            submit(&coalescer, 3, None).await
+        };
+        let (first, second, third, late) = join!(
+            submit(&coalescer, 0, Some(50)),
+            submit(&coalescer, 1, Some(50)),
+            submit(&coalescer, 2, None),
+            late
+        );
+        assert!(matches!(first, Err(QueryError::Timeout)));
+        assert!(matches!(second, Err(QueryError::Timeout)));
+        let third = third.unwrap();
+        assert_eq!(third.batch_size, 1);
+        assert_float_eq!(third.queued_ms, 50.0, abs <= 1.0);
+        assert_eq!(late.unwrap().batch_size, 1);
+        assert_eq!(*mock.rounds.lock().unwrap(), [
+            vec![0, 1, 2],
+            vec![2],
+            vec![3]
+        ]);
+
+        let stats = coalescer.stats();
+        assert_eq!((stats.queries, stats.failed, stats.rounds), (4, 2, 3));
+    }
+
+    #[test]
+    fn test_stats() {
+        let latency = |queued_ms, scan_ms| Latency {
+            batch_size: 0,
+            queued_ms,
+            scan_ms,
+        };
+        let mut stats = Stats::default();
+        stats.record(&[latency(1.0, 10.0), latency(3.0, 10.0)], true);
+        stats.record(&[latency(8.0, 20.0)], false);
+        assert_eq!((stats.queries, stats.rounds, stats.failed), (3, 2, 1));
+        assert_float_eq!(stats.mean_batch_size, 1.5, ulps <= 1);
+        assert_float_eq!(stats.mean_queued_ms, 4.0, ulps <= 1);
+        assert_float_eq!(stats.max_queued_ms, 8.0, ulps <= 1);
+        assert_float_eq!(stats.mean_scan_ms, 40.0 / 3.0, ulps <= 1);
+        assert_float_eq!(stats.mean_total_ms, 52.0 / 3.0, ulps <= 1);
+        assert_float_eq!(stats.max_total_ms, 28.0, ulps <= 1);
+    }
+}
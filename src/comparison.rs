@@ -0,0 +1,953 @@
+//! Secure comparison of secret-shared distances against a threshold.
+//!
+//! For every entry and rotation the resolver knows the denominator $d$ and
+//! the participants hold additive shares of the numerator $n$. An entry
+//! matches if for some rotation the fractional hamming distance
+//! $(d - n) / (2 d)$ is below the threshold $t$. With $c = \lceil t d \rceil$
+//! this is the case iff $v = d - n - 2 c$ is negative, where $|v| < 2^{15}$
+//! for thresholds $0 \le t \le 1$.
+//!
+//! Using preprocessed material from a dealer the parties open $u = v + r$ for
+//! a random $r$ of which they hold additive shares and XOR shares of its bits.
+//! They then evaluate the sign bit of $u - r$ using a borrow chain and OR the
+//! results over all rotations, using Beaver triples for the AND gates. All
+//! opened values are uniformly random except for the final match bits.
+//!
+//! When the denominators are secret-shared as well nothing per entry is
+//! opened. With the threshold as a [`fraction`] $p / q$ an entry matches iff
+//! $v = q (d - n) - 2 p d$ is negative for some rotation. The shares of
+//! $d - n$ and $d$, both below $2^{15}$, are first extended to
+//! $\mathbb{Z}_{2^{32}}$ by opening them masked with a random $r$, see
+//! [`Extension`]. The same comparison then runs on $v$ in the wider ring.
+//!
+//! Entries are processed in blocks of [`BLOCK`] entries, such that every
+//! `u64` word holds one bit for each entry in a block. The material of a block
+//! is expanded from a per party seed. The dealer derives a correction for the
+//! last party such that the shares are consistent.
+
+use crate::BITS;
+use bytemuck::Pod;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rayon::prelude::*;
+use std::ops::Range;
+
+/// Number of entries in a block.
+pub const BLOCK: usize = 64;
+
+const ROTATIONS: usize = 31;
+
+/// AND gates in the tree that ORs together all rotations.
+const OR_GATES: usize = ROTATIONS - 1;
+
+/// Layers of AND gates in the OR tree.
+const OR_LAYERS: usize = 5;
+
+/// Number of entries per batch of the protocol, a multiple of [`BLOCK`].
+pub const BATCH: usize = 320 * BLOCK;
+
+/// Values extended per entry and rotation with secret-shared denominators,
+/// $d - n$ and $d$.
+const EXTENDED: usize = 2;
+
+/// Stream of the seeds' generators for the extension material, disjoint from
+/// the per block streams of the comparison material.
+const EXTENSION_STREAM: u64 = 1 << 63;
+
+/// Number of `u64` correction words per block for the last party when the
+/// denominators are secret-shared, for a [`Material<u32>`] followed by an
+/// [`Extension`].
+pub const SHARED_CORRECTION_WORDS: usize =
+    Material::<u32>::CORRECTION_WORDS + Extension::CORRECTION_WORDS;
+
+/// Ring $\mathbb{Z}_{2^w}$ of the compared values.
+pub trait Ring: Pod + Default + Send + Sync {
+    /// Bit width $w$ of the ring.
+    const WIDTH: usize;
+
+    fn wrapping_add(self, other: Self) -> Self;
+
+    fn wrapping_sub(self, other: Self) -> Self;
+
+    /// Bit `bit` of the value.
+    fn bit(self, bit: usize) -> u64;
+}
+
+macro_rules! impl_ring {
+    ($($t:ty),*) => {$(
+        impl Ring for $t {
+            const WIDTH: usize = <$t>::BITS as usize;
+
+            fn wrapping_add(self, other: Self) -> Self {
+                <$t>::wrapping_add(self, other)
+            }
+
+            fn wrapping_sub(self, other: Self) -> Self {
+                <$t>::wrapping_sub(self, other)
+            }
+
+            fn bit(self, bit: usize) -> u64 {
+                ((self >> bit) & 1) as u64
+            }
+        }
+    )*};
+}
+
+impl_ring!(u16, u32);
+
+/// Preprocessed material of one party for a block.
+#[derive(Clone)]
+pub struct Material<T: Ring = u16> {
+    /// Additive shares of the random masks.
+    r:      [[T; ROTATIONS]; BLOCK],
+    /// XOR shares of the bits of the random masks, per rotation and bit.
+    r_bits: Vec<u64>,
+    /// XOR shares of Beaver triples $c = a \wedge b$.
+    a:      Vec<u64>,
+    b:      Vec<u64>,
+    c:      Vec<u64>,
+}
+
+impl<T: Ring> Material<T> {
+    /// Total number of AND gates per block, computing borrows 2 to $w - 1$
+    /// and the OR tree.
+    const GATES: usize = (T::WIDTH - 2) * ROTATIONS + OR_GATES;
+
+    /// Number of `u64` correction words per block for the last party.
+    pub const CORRECTION_WORDS: usize = ROTATIONS * T::WIDTH + Self::GATES;
+
+    /// Expand the material for `block` from a party's `seed`.
+    pub fn expand(seed: &[u8; 32], block: usize, correction: Option<&[u64]>) -> Self {
+        let mut rng = ChaCha20Rng::from_seed(*seed);
+        rng.set_stream(block as u64);
+        let mut material = Self {
+            r:      [[T::default(); ROTATIONS]; BLOCK],
+            r_bits: vec![0; ROTATIONS * T::WIDTH],
+            a:      vec![0; Self::GATES],
+            b:      vec![0; Self::GATES],
+            c:      vec![0; Self::GATES],
+        };
+        rng.fill_bytes(bytemuck::cast_slice_mut(material.r.as_mut_slice()));
+        rng.fill_bytes(bytemuck::cast_slice_mut(&mut material.r_bits));
+        rng.fill_bytes(bytemuck::cast_slice_mut(&mut material.a));
+        rng.fill_bytes(bytemuck::cast_slice_mut(&mut material.b));
+        rng.fill_bytes(bytemuck::cast_slice_mut(&mut material.c));
+        if let Some(correction) = correction {
+            assert_eq!(correction.len(), Self::CORRECTION_WORDS);
+            let (r_bits, c) = correction.split_at(ROTATIONS * T::WIDTH);
+            material
+                .r_bits
+                .iter_mut()
+                .zip(r_bits)
+                .for_each(|(w, x)| *w ^= x);
+            material.c.iter_mut().zip(c).for_each(|(w, x)| *w ^= x);
+        }
+        material
+    }
+
+    /// Compute the correction for the last party in `seeds` for `block`.
+    pub fn deal(seeds: &[[u8; 32]], block: usize) -> Vec<u64> {
+        let shares = seeds
+            .iter()
+            .map(|seed| Self::expand(seed, block, None))
+            .collect::<Vec<_>>();
+
+        // Combine the shares of the random masks and their bits.
+        let mut r = [[T::default(); ROTATIONS]; BLOCK];
+        let mut r_bits = vec![0_u64; ROTATIONS * T::WIDTH];
+        let mut a = vec![0_u64; Self::GATES];
+        let mut b = vec![0_u64; Self::GATES];
+        let mut c = vec![0_u64; Self::GATES];
+        for share in &shares {
+            for (r, s) in r.iter_mut().flatten().zip(share.r.iter().flatten()) {
+                *r = r.wrapping_add(*s);
+            }
+            for (r, s) in r_bits.iter_mut().zip(share.r_bits.iter()) {
+                *r ^= s;
+            }
+            for gate in 0..Self::GATES {
+                a[gate] ^= share.a[gate];
+                b[gate] ^= share.b[gate];
+                c[gate] ^= share.c[gate];
+            }
+        }
+
+        // Corrections are the difference with the intended values.
+        let mut correction = Vec::with_capacity(Self::CORRECTION_WORDS);
+        for rotation in 0..ROTATIONS {
+            for bit in 0..T::WIDTH {
+                let mut word = 0;
+                for (entry, r) in r.iter().enumerate() {
+                    word |= r[rotation].bit(bit) << entry;
+                }
+                correction.push(word ^ r_bits[rotation * T::WIDTH + bit]);
+            }
+        }
+        for gate in 0..Self::GATES {
+            correction.push((a[gate] & b[gate]) ^ c[gate]);
+        }
+        correction
+    }
+}
+
+/// Preprocessed material of one party to extend the shares of $d - n$ and $d$
+/// for a block from $\mathbb{Z}_{2^{16}}$ to $\mathbb{Z}_{2^{32}}$.
+///
+/// The parties open $u = x + r \bmod 2^{16}$ for a value $0 \le x < 2^{15}$.
+/// As integers $x = u - r + 2^{16} w$ where the wrap around is
+/// $w = r_{15} \wedge \neg u_{15}$, which is linear in the shares of $r$ and
+/// its top bit $r_{15}$ in the wider ring since $u$ is public.
+#[derive(Clone)]
+pub struct Extension {
+    /// Additive shares of the random masks.
+    r:    [[[u16; ROTATIONS]; BLOCK]; EXTENDED],
+    /// Additive shares of the masks as integers in the wider ring.
+    wide: [[[u32; ROTATIONS]; BLOCK]; EXTENDED],
+    /// Additive shares of the top bits of the masks in the wider ring.
+    top:  [[[u32; ROTATIONS]; BLOCK]; EXTENDED],
+}
+
+impl Extension {
+    /// Number of `u64` correction words per block for the last party, packing
+    /// the `u32` corrections of `wide` and `top`.
+    pub const CORRECTION_WORDS: usize = EXTENDED * BLOCK * ROTATIONS;
+
+    /// Expand the material for `block` from a party's `seed`.
+    pub fn expand(seed: &[u8; 32], block: usize, correction: Option<&[u64]>) -> Self {
+        let mut rng = ChaCha20Rng::from_seed(*seed);
+        rng.set_stream(EXTENSION_STREAM | block as u64);
+        let mut extension = Self {
+            r:    [[[0; ROTATIONS]; BLOCK]; EXTENDED],
+            wide: [[[0; ROTATIONS]; BLOCK]; EXTENDED],
+            top:  [[[0; ROTATIONS]; BLOCK]; EXTENDED],
+        };
+        rng.fill_bytes(bytemuck::bytes_of_mut(&mut extension.r));
+        rng.fill_bytes(bytemuck::bytes_of_mut(&mut extension.wide));
+        rng.fill_bytes(bytemuck::bytes_of_mut(&mut extension.top));
+        if let Some(correction) = correction {
+            assert_eq!(correction.len(), Self::CORRECTION_WORDS);
+            let correction: &[u32] = bytemuck::cast_slice(correction);
+            let (wide, top) = correction.split_at(correction.len() / 2);
+            let values = extension.wide.iter_mut().flatten().flatten();
+            values.zip(wide).for_each(|(v, c)| *v = v.wrapping_add(*c));
+            let values = extension.top.iter_mut().flatten().flatten();
+            values.zip(top).for_each(|(v, c)| *v = v.wrapping_add(*c));
+        }
+        extension
+    }
+
+    /// Compute the correction for the last party in `seeds` for `block`.
+    pub fn deal(seeds: &[[u8; 32]], block: usize) -> Vec<u64> {
+        let shares = seeds
+            .iter()
+            .map(|seed| Self::expand(seed, block, None))
+            .collect::<Vec<_>>();
+
+        // Combine the shares of the random masks and their extensions.
+        let values = EXTENDED * BLOCK * ROTATIONS;
+        let (mut r, mut wide, mut top) = (vec![0_u16; values], vec![0_u32; values], vec![
+            0_u32;
+            values
+        ]);
+        for share in &shares {
+            let shares = share.r.iter().flatten().flatten();
+            r.iter_mut()
+                .zip(shares)
+                .for_each(|(r, s)| *r = r.wrapping_add(*s));
+            let shares = share.wide.iter().flatten().flatten();
+            wide.iter_mut()
+                .zip(shares)
+                .for_each(|(w, s)| *w = w.wrapping_add(*s));
+            let shares = share.top.iter().flatten().flatten();
+            top.iter_mut()
+                .zip(shares)
+                .for_each(|(t, s)| *t = t.wrapping_add(*s));
+        }
+
+        // Corrections are the difference with the intended values.
+        let corrections = r
+            .iter()
+            .zip(wide.iter())
+            .map(|(&r, &w)| (r as u32).wrapping_sub(w))
+            .chain(
+                r.iter()
+                    .zip(top.iter())
+                    .map(|(&r, &t)| ((r >> 15) as u32).wrapping_sub(t)),
+            )
+            .collect::<Vec<_>>();
+        corrections
+            .chunks_exact(2)
+            .map(|c| c[0] as u64 | (c[1] as u64) << 32)
+            .collect()
+    }
+}
+
+/// Expand the material to compare secret-shared distances for a range of
+/// blocks in parallel.
+pub fn expand_shared(
+    seed: &[u8; 32],
+    blocks: Range<usize>,
+    corrections: Option<&[u64]>,
+) -> (Vec<Extension>, Vec<Material<u32>>) {
+    blocks
+        .into_par_iter()
+        .enumerate()
+        .map(|(i, block)| {
+            let correction = corrections
+                .map(|c| &c[i * SHARED_CORRECTION_WORDS..(i + 1) * SHARED_CORRECTION_WORDS]);
+            let (material, extension) = correction
+                .map(|c| c.split_at(Material::<u32>::CORRECTION_WORDS))
+                .unzip();
+            (
+                Extension::expand(seed, block, extension),
+                Material::expand(seed, block, material),
+            )
+        })
+        .unzip()
+}
+
+/// Compute the correction for the last party in `seeds` for `block` to
+/// compare secret-shared distances.
+pub fn deal_shared(seeds: &[[u8; 32]], block: usize) -> Vec<u64> {
+    let mut correction = Material::<u32>::deal(seeds, block);
+    correction.extend(Extension::deal(seeds, block));
+    correction
+}
+
+/// The smallest fraction $p / q$ with $q \le$ [`BITS`] that is not below
+/// `threshold` when computed in floating point.
+///
+/// For all $0 \le h \le d \le$ [`BITS`] with $d > 0$ the distance $h / d$ is
+/// below the threshold iff $h q < p d$, so comparing with the fraction gives
+/// exactly the same matches as the plaintext distances. A threshold above one
+/// gives $2 / 1$, which every distance is below.
+pub fn fraction(threshold: f64) -> (u32, u32) {
+    let mut best: Option<(u32, u32)> = None;
+    for d in 1..=BITS as u32 {
+        // Smallest h with h / d not below the threshold, correcting for rounding.
+        let mut h = (threshold * d as f64).ceil().clamp(0.0, d as f64 + 1.0) as u32;
+        while h > 0 && ((h - 1) as f64 / d as f64) >= threshold {
+            h -= 1;
+        }
+        while h <= d && (h as f64 / d as f64) < threshold {
+            h += 1;
+        }
+        let smaller = |(p, q): (u32, u32)| (h as u64) * (q as u64) < (p as u64) * (d as u64);
+        if h <= d && best.is_none_or(smaller) {
+            best = Some((h, d));
+        }
+    }
+    best.unwrap_or((2, 1))
+}
+
+/// Public offsets $d - 2 \lceil t d \rceil$ for a batch of denominators, for a
+/// threshold $0 \le t \le 1$.
+pub fn offsets(denominators: &[[u16; ROTATIONS]], threshold: f64) -> Vec<[u16; ROTATIONS]> {
+    denominators
+        .iter()
+        .map(|denominators| {
+            denominators.map(|d| {
+                let c = (threshold * d as f64).ceil() as u16;
+                d.wrapping_sub(c.wrapping_mul(2))
+            })
+        })
+        .collect()
+}
+
+/// Combine the masked values of all parties and add the public offsets.
+pub fn open_masked<T: Ring>(offsets: &mut [[T; ROTATIONS]], messages: &[Vec<[T; ROTATIONS]>]) {
+    for message in messages {
+        for (o, m) in offsets.iter_mut().flatten().zip(message.iter().flatten()) {
+            *o = o.wrapping_add(*m);
+        }
+    }
+}
+
+/// Combine XOR shared messages of all parties.
+pub fn open_bits(messages: &[Vec<u64>]) -> Vec<u64> {
+    let mut result = vec![0; messages.first().map_or(0, Vec::len)];
+    for message in messages {
+        for (r, m) in result.iter_mut().zip(message.iter()) {
+            *r ^= m;
+        }
+    }
+    result
+}
+
+/// One party's state while extending the shares of a batch of blocks, see
+/// [`Extension`].
+pub struct Extender {
+    leader:   bool,
+    material: Vec<Extension>,
+}
+
+impl Extender {
+    /// Start a batch. Exactly one party, the leader, adds public constants.
+    pub fn new(leader: bool, material: Vec<Extension>) -> Self {
+        Self { leader, material }
+    }
+
+    /// Mask shares of $d - n$ and $d$. The result holds all masked $d - n$
+    /// followed by all masked $d$ and should be opened with [`open_masked`]
+    /// from zeros.
+    pub fn mask(
+        &self,
+        numerators: &[[u16; ROTATIONS]],
+        denominators: &[[u16; ROTATIONS]],
+    ) -> Vec<[u16; ROTATIONS]> {
+        assert_eq!(numerators.len(), denominators.len());
+        assert!(numerators.len() <= self.material.len() * BLOCK);
+        let len = numerators.len();
+        let differences = numerators
+            .iter()
+            .zip(denominators.iter())
+            .map(|(n, d)| std::array::from_fn(|j| d[j].wrapping_sub(n[j])));
+        differences
+            .chain(denominators.iter().copied())
+            .enumerate()
+            .map(|(i, x): (usize, [u16; ROTATIONS])| {
+                let (value, i) = (i / len, i % len);
+                let r = &self.material[i / BLOCK].r[value][i % BLOCK];
+                std::array::from_fn(|j| x[j].wrapping_add(r[j]))
+            })
+            .collect()
+    }
+
+    /// Shares of $2 p d - q (d - n)$ in the wider ring from the opened masked
+    /// values, for the threshold [`fraction`] $p / q$. They should be compared
+    /// by a [`Comparator<u32>`] with zero offsets, which tests whether
+    /// $q (d - n) - 2 p d$ is negative.
+    pub fn scaled(&self, opened: &[[u16; ROTATIONS]], (p, q): (u32, u32)) -> Vec<[u32; ROTATIONS]> {
+        let len = opened.len() / EXTENDED;
+        assert!(len <= self.material.len() * BLOCK);
+        let extend = |value: usize, i: usize, rotation: usize| {
+            let u = opened[value * len + i][rotation];
+            let material = &self.material[i / BLOCK];
+            let (wide, top) = (
+                material.wide[value][i % BLOCK][rotation],
+                material.top[value][i % BLOCK][rotation],
+            );
+            let mut share = if self.leader { u as u32 } else { 0 }.wrapping_sub(wide);
+            if u >> 15 == 0 {
+                share = share.wrapping_add(top << 16);
+            }
+            share
+        };
+        (0..len)
+            .map(|i| {
+                std::array::from_fn(|rotation| {
+                    let (difference, denominator) =
+                        (extend(0, i, rotation), extend(1, i, rotation));
+                    (2 * p)
+                        .wrapping_mul(denominator)
+                        .wrapping_sub(q.wrapping_mul(difference))
+                })
+            })
+            .collect()
+    }
+}
+
+/// One party's state while comparing a batch of blocks.
+pub struct Comparator<T: Ring = u16> {
+    leader:   bool,
+    material: Vec<Material<T>>,
+    /// Opened masked values, per block, rotation and bit.
+    u_bits:   Vec<Vec<u64>>,
+    /// Running borrows, later the OR tree values, per block.
+    values:   Vec<[u64; ROTATIONS]>,
+    /// Number of completed rounds.
+    round:    usize,
+}
+
+impl<T: Ring> Comparator<T> {
+    /// Layers of AND gates in the borrow chain, computing borrows 2 to $w - 1$.
+    const BORROW_LAYERS: usize = T::WIDTH - 2;
+
+    /// Number of rounds of communication after opening the masked values.
+    pub const ROUNDS: usize = Self::BORROW_LAYERS + OR_LAYERS;
+
+    /// Start a batch. Exactly one party, the leader, adds public constants.
+    pub fn new(leader: bool, material: Vec<Material<T>>) -> Self {
+        Self {
+            leader,
+            material,
+            u_bits: Vec::new(),
+            values: Vec::new(),
+            round: 0,
+        }
+    }
+
+    /// Expand the material for a range of blocks in parallel.
+    pub fn expand(
+        seed: &[u8; 32],
+        blocks: Range<usize>,
+        corrections: Option<&[u64]>,
+    ) -> Vec<Material<T>> {
+        let words = Material::<T>::CORRECTION_WORDS;
+        blocks
+            .into_par_iter()
+            .enumerate()
+            .map(|(i, block)| {
+                let correction = corrections.map(|c| &c[i * words..(i + 1) * words]);
+                Material::expand(seed, block, correction)
+            })
+            .collect()
+    }
+
+    /// Mask shares of numerators. The result should be opened with
+    /// [`open_masked`].
+    pub fn mask(&self, numerators: &[[T; ROTATIONS]]) -> Vec<[T; ROTATIONS]> {
+        assert!(numerators.len() <= self.material.len() * BLOCK);
+        numerators
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                let r = &self.material[i / BLOCK].r[i % BLOCK];
+                std::array::from_fn(|j| r[j].wrapping_sub(n[j]))
+            })
+            .collect()
+    }
+
+    /// Receive the opened masked values and compute the first borrow.
+    pub fn open(&mut self, opened: &[[T; ROTATIONS]]) {
+        self.u_bits = self
+            .material
+            .iter()
+            .enumerate()
+            .map(|(block, _)| {
+                let mut bits = vec![0_u64; ROTATIONS * T::WIDTH];
+                let entries = opened.iter().skip(block * BLOCK).take(BLOCK);
+                for (entry, u) in entries.enumerate() {
+                    for (rotation, bits) in bits.chunks_exact_mut(T::WIDTH).enumerate() {
+                        for (bit, word) in bits.iter_mut().enumerate() {
+                            *word |= u[rotation].bit(bit) << entry;
+                        }
+                    }
+                }
+                bits
+            })
+            .collect();
+
+        // First borrow is !u_0 & r_0, which is linear.
+        self.values = self
+            .material
+            .iter()
+            .zip(self.u_bits.iter())
+            .map(|(material, u_bits)| {
+                std::array::from_fn(|rotation| {
+                    let bit = rotation * T::WIDTH;
+                    !u_bits[bit] & material.r_bits[bit]
+                })
+            })
+            .collect();
+    }
+
+    /// Number of `u64` words in a message of `round` for `blocks` blocks.
+    pub fn message_words(round: usize, blocks: usize) -> usize {
+        2 * blocks * Self::gates(round).0.len()
+    }
+
+    /// Range of gates and their input pairs for `round`.
+    fn gates(round: usize) -> (Range<usize>, Vec<(usize, usize)>) {
+        if round < Self::BORROW_LAYERS {
+            let start = round * ROTATIONS;
+            let inputs = (0..ROTATIONS).map(|r| (r, r)).collect();
+            (start..start + ROTATIONS, inputs)
+        } else {
+            // OR tree over the rotations, halving every layer.
+            let layer = round - Self::BORROW_LAYERS;
+            let width = (ROTATIONS + (1 << layer) - 1) >> layer;
+            let half = width.div_ceil(2);
+            let start = Self::BORROW_LAYERS * ROTATIONS
+                + (0..layer)
+                    .map(|l| ((ROTATIONS + (1 << l) - 1) >> l) / 2)
+                    .sum::<usize>();
+            let inputs = (0..width / 2).map(|i| (i, i + half)).collect();
+            (start..start + width / 2, inputs)
+        }
+    }
+
+    /// The left and right AND inputs of a gate.
+    fn inputs(&self, block: usize, left: usize, right: usize) -> (u64, u64) {
+        if self.round < Self::BORROW_LAYERS {
+            // r_i & b_i for bit i = round + 1
+            let bit = self.round + 1;
+            (
+                self.material[block].r_bits[left * T::WIDTH + bit],
+                self.values[block][right],
+            )
+        } else {
+            (self.values[block][left], self.values[block][right])
+        }
+    }
+
+    /// Masked AND inputs for the current round, to be opened with
+    /// [`open_bits`].
+    pub fn message(&self) -> Vec<u64> {
+        let (gates, inputs) = Self::gates(self.round);
+        let mut d = Vec::with_capacity(self.material.len() * gates.len());
+        let mut e = Vec::with_capacity(self.material.len() * gates.len());
+        for (block, material) in self.material.iter().enumerate() {
+            for (gate, &(left, right)) in gates.clone().zip(inputs.iter()) {
+                let (x, y) = self.inputs(block, left, right);
+                d.push(x ^ material.a[gate]);
+                e.push(y ^ material.b[gate]);
+            }
+        }
+        d.extend(e);
+        d
+    }
+
+    /// Receive the opened AND inputs and complete the round.
+    pub fn receive(&mut self, opened: &[u64]) {
+        let (gates, inputs) = Self::gates(self.round);
+        let (d, e) = opened.split_at(opened.len() / 2);
+        assert_eq!(d.len(), self.material.len() * gates.len());
+        for block in 0..self.material.len() {
+            let material = &self.material[block];
+            let mut values = self.values[block];
+            for (i, (gate, &(left, right))) in gates.clone().zip(inputs.iter()).enumerate() {
+                let (d, e) = (d[block * gates.len() + i], e[block * gates.len() + i]);
+                let mut z = material.c[gate] ^ (d & material.b[gate]) ^ (e & material.a[gate]);
+                if self.leader {
+                    z ^= d & e;
+                }
+                let (x, y) = self.inputs(block, left, right);
+                if self.round < Self::BORROW_LAYERS {
+                    // b_{i+1} = r_i & b_i ^ !u_i & (r_i ^ b_i)
+                    let bit = right * T::WIDTH + self.round + 1;
+                    values[right] = z ^ (!self.u_bits[block][bit] & (x ^ y));
+                } else {
+                    // x | y = x ^ y ^ (x & y), stored in place of the left input.
+                    values[left] = z ^ x ^ y;
+                }
+            }
+            self.values[block] = values;
+        }
+        self.round += 1;
+
+        // After the borrow chain the sign is u_{w-1} ^ r_{w-1} ^ b_{w-1}.
+        if self.round == Self::BORROW_LAYERS {
+            for (block, values) in self.values.iter_mut().enumerate() {
+                for (rotation, value) in values.iter_mut().enumerate() {
+                    let bit = rotation * T::WIDTH + T::WIDTH - 1;
+                    *value ^= self.material[block].r_bits[bit];
+                    if self.leader {
+                        *value ^= self.u_bits[block][bit];
+                    }
+                }
+            }
+        }
+    }
+
+    /// Shares of the match bits, one word per block.
+    pub fn result(&self) -> Vec<u64> {
+        assert_eq!(self.round, Self::ROUNDS);
+        self.values.iter().map(|values| values[0]).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    fn check_gates<T: Ring>() {
+        let mut total = 0;
+        for round in 0..Comparator::<T>::ROUNDS {
+            let (gates, inputs) = Comparator::<T>::gates(round);
+            assert_eq!(gates.start, total);
+            assert_eq!(gates.len(), inputs.len());
+            total += gates.len();
+        }
+        assert_eq!(total, Material::<T>::GATES);
+    }
+
+    #[test]
+    fn test_gates() {
+        check_gates::<u16>();
+        check_gates::<u32>();
+    }
+
+    /// Additive shares of `values` for `parties` parties.
+    fn share<T: Ring>(values: &[[T; ROTATIONS]], parties: usize) -> Vec<Vec<[T; ROTATIONS]>> {
+        let mut shares = vec![values.to_vec()];
+        for _ in 1..parties {
+            let mut share = vec![[T::default(); ROTATIONS]; values.len()];
+            thread_rng().fill_bytes(bytemuck::cast_slice_mut(&mut share));
+            for (s, r) in shares[0].iter_mut().flatten().zip(share.iter().flatten()) {
+                *s = s.wrapping_sub(*r);
+            }
+            shares.push(share);
+        }
+        shares
+    }
+
+    /// Random denominators and numerators close to the threshold, with the
+    /// expected matches.
+    fn distances(
+        entries: usize,
+        threshold: f64,
+    ) -> (Vec<[u16; ROTATIONS]>, Vec<[u16; ROTATIONS]>, Vec<bool>) {
+        let mut rng = thread_rng();
+        let denominators = (0..entries)
+            .map(|_| std::array::from_fn(|_| rng.gen_range(0..=BITS as u16)))
+            .collect::<Vec<[u16; ROTATIONS]>>();
+        let numerators = denominators
+            .iter()
+            .map(|d| {
+                d.map(|d| {
+                    let h = ((threshold * d as f64) as i32 + rng.gen_range(-3..=3))
+                        .clamp(0, d as i32) as u16;
+                    d.wrapping_sub(2 * h)
+                })
+            })
+            .collect::<Vec<[u16; ROTATIONS]>>();
+        let expected = numerators
+            .iter()
+            .zip(denominators.iter())
+            .map(|(n, d)| {
+                n.iter().zip(d.iter()).any(|(&n, &d)| {
+                    let h = d.wrapping_sub(n) / 2;
+                    d > 0 && (h as f64) / (d as f64) < threshold
+                })
+            })
+            .collect::<Vec<_>>();
+        (numerators, denominators, expected)
+    }
+
+    fn check_matches(matches: &[u64], expected: &[bool]) {
+        for (i, &expected) in expected.iter().enumerate() {
+            let actual = (matches[i / BLOCK] >> (i % BLOCK)) & 1 == 1;
+            assert_eq!(actual, expected, "Entry {i} mismatch");
+        }
+    }
+
+    /// Evaluate the rounds of the circuit and open the match bits.
+    fn evaluate<T: Ring>(comparators: &mut [Comparator<T>]) -> Vec<u64> {
+        for _ in 0..Comparator::<T>::ROUNDS {
+            let messages = comparators
+                .iter()
+                .map(Comparator::message)
+                .collect::<Vec<_>>();
+            let opened = open_bits(&messages);
+            comparators.iter_mut().for_each(|c| c.receive(&opened));
+        }
+        let results = comparators
+            .iter()
+            .map(Comparator::result)
+            .collect::<Vec<_>>();
+        open_bits(&results)
+    }
+
+    /// Compare with public denominators, returning the match bits.
+    fn compare(
+        numerators: &[[u16; ROTATIONS]],
+        denominators: &[[u16; ROTATIONS]],
+        threshold: f64,
+        parties: usize,
+    ) -> Vec<u64> {
+        let blocks = numerators.len().div_ceil(BLOCK);
+        let shares = share(numerators, parties);
+
+        // Dealer
+        let seeds = (0..parties)
+            .map(|_| thread_rng().gen())
+            .collect::<Vec<[u8; 32]>>();
+        let corrections = (0..blocks)
+            .flat_map(|block| Material::<u16>::deal(&seeds, block))
+            .collect::<Vec<_>>();
+        let mut comparators = seeds
+            .iter()
+            .enumerate()
+            .map(|(party, seed)| {
+                let last = party == parties - 1;
+                let material = Comparator::<u16>::expand(
+                    seed,
+                    0..blocks,
+                    last.then_some(corrections.as_slice()),
+                );
+                Comparator::new(party == 0, material)
+            })
+            .collect::<Vec<_>>();
+
+        // Protocol
+        let mut opened = offsets(denominators, threshold);
+        let messages = comparators
+            .iter()
+            .zip(shares.iter())
+            .map(|(c, s)| c.mask(s))
+            .collect::<Vec<_>>();
+        open_masked(&mut opened, &messages);
+        comparators.iter_mut().for_each(|c| c.open(&opened));
+        evaluate(&mut comparators)
+    }
+
+    #[test]
+    fn test_comparison() {
+        let mut rng = thread_rng();
+        for parties in 1..4 {
+            let threshold = rng.gen_range(0.2..0.5);
+            let entries = 3 * BLOCK - 5;
+            let (numerators, denominators, expected) = distances(entries, threshold);
+            let matches = compare(&numerators, &denominators, threshold, parties);
+            check_matches(&matches, &expected);
+        }
+    }
+
+    #[test]
+    fn test_threshold_bounds() {
+        // Every distance of the largest denominator, and an empty one.
+        let distances = (0..=BITS as u16)
+            .map(|h| (h, BITS as u16))
+            .chain([(0, 0)])
+            .collect::<Vec<_>>();
+        let denominators = distances
+            .iter()
+            .map(|&(_, d)| [d; ROTATIONS])
+            .collect::<Vec<_>>();
+        let numerators = distances
+            .iter()
+            .map(|&(h, d)| [d.wrapping_sub(2 * h); ROTATIONS])
+            .collect::<Vec<_>>();
+        for threshold in [0.0, 1.0] {
+            let expected = distances
+                .iter()
+                .map(|&(h, d)| d > 0 && (h as f64) / (d as f64) < threshold)
+                .collect::<Vec<_>>();
+            let matches = compare(&numerators, &denominators, threshold, 2);
+            check_matches(&matches, &expected);
+        }
+    }
+
+    /// Values the resolver receives from the parties while comparing with
+    /// secret-shared denominators.
+    struct Received {
+        extension: Vec<Vec<[u16; ROTATIONS]>>,
+        scaled:    Vec<Vec<[u32; ROTATIONS]>>,
+    }
+
+    /// Compare with secret-shared denominators, returning the match bits.
+    fn compare_shared(
+        numerators: &[[u16; ROTATIONS]],
+        denominators: &[[u16; ROTATIONS]],
+        threshold: f64,
+        parties: usize,
+    ) -> (Vec<u64>, Received) {
+        let entries = numerators.len();
+        let blocks = entries.div_ceil(BLOCK);
+        let (numerators, denominators) = (share(numerators, parties), share(denominators, parties));
+
+        // Dealer
+        let seeds = (0..parties)
+            .map(|_| thread_rng().gen())
+            .collect::<Vec<[u8; 32]>>();
+        let corrections = (0..blocks)
+            .flat_map(|block| deal_shared(&seeds, block))
+            .collect::<Vec<_>>();
+        let (extenders, mut comparators): (Vec<_>, Vec<_>) = seeds
+            .iter()
+            .enumerate()
+            .map(|(party, seed)| {
+                let last = party == parties - 1;
+                let (extension, material) =
+                    expand_shared(seed, 0..blocks, last.then_some(corrections.as_slice()));
+                (
+                    Extender::new(party == 0, extension),
+                    Comparator::new(party == 0, material),
+                )
+            })
+            .unzip();
+
+        // Extend the shares of d - n and d.
+        let extension = extenders
+            .iter()
+            .zip(numerators.iter().zip(denominators.iter()))
+            .map(|(e, (n, d))| e.mask(n, d))
+            .collect::<Vec<_>>();
+        let mut opened = vec![[0; ROTATIONS]; EXTENDED * entries];
+        open_masked(&mut opened, &extension);
+
+        // Compare the scaled difference
+        let fraction = fraction(threshold);
+        let scaled = extenders
+            .iter()
+            .zip(comparators.iter())
+            .map(|(e, c)| c.mask(&e.scaled(&opened, fraction)))
+            .collect::<Vec<_>>();
+        let mut opened = vec![[0; ROTATIONS]; entries];
+        open_masked(&mut opened, &scaled);
+        comparators.iter_mut().for_each(|c| c.open(&opened));
+        let matches = evaluate(&mut comparators);
+        (matches, Received { extension, scaled })
+    }
+
+    #[test]
+    fn test_shared_comparison() {
+        let mut rng = thread_rng();
+        let thresholds = [rng.gen_range(0.2..0.5), 0.375, 1.5];
+        for (parties, threshold) in (1..4).zip(thresholds) {
+            let entries = 3 * BLOCK - 5;
+            let (numerators, denominators, expected) = distances(entries, threshold);
+            let (matches, _) = compare_shared(&numerators, &denominators, threshold, parties);
+            check_matches(&matches, &expected);
+        }
+    }
+
+    #[test]
+    fn test_shared_masked() {
+        // Constant distances would show in anything not uniformly masked.
+        let (entries, d, h) = (3 * BLOCK, 1000_u16, 300_u16);
+        let denominators = vec![[d; ROTATIONS]; entries];
+        let numerators = vec![[d - 2 * h; ROTATIONS]; entries];
+        let (matches, received) = compare_shared(&numerators, &denominators, 0.35, 2);
+        check_matches(&matches, &vec![true; entries]);
+
+        fn check<T: Ring + Ord>(messages: &[Vec<[T; ROTATIONS]>], plaintext: &[T]) {
+            let mut opened = vec![[T::default(); ROTATIONS]; messages[0].len()];
+            open_masked(&mut opened, messages);
+            for values in messages.iter().chain([&opened]) {
+                let mut values = values.iter().flatten().copied().collect::<Vec<_>>();
+                let equal = values.iter().filter(|v| plaintext.contains(v)).count();
+                assert!(equal < 16, "{equal} unmasked values");
+                values.sort_unstable();
+                values.dedup();
+                let total = messages[0].len() * ROTATIONS;
+                assert!(
+                    values.len() > total * 3 / 4,
+                    "Only {} distinct values",
+                    values.len()
+                );
+            }
+        }
+        let (p, q) = fraction(0.35);
+        check(&received.extension, &[2 * h, d]);
+        check(&received.scaled, &[
+            (2 * p * d as u32).wrapping_sub(q * 2 * h as u32)
+        ]);
+    }
+
+    #[test]
+    fn test_fraction() {
+        let mut rng = thread_rng();
+        assert_eq!(fraction(0.0), (0, 1));
+        assert_eq!(fraction(-0.5), (0, 1));
+        assert_eq!(fraction(1.5), (2, 1));
+        assert_eq!(fraction(0.375), (3, 8));
+        let thresholds = [1.0 / 3.0, 0.35, 1.0, rng.gen_range(0.0..1.0)];
+        for threshold in thresholds {
+            let (p, q) = fraction(threshold);
+            assert!(q > 0 && q as usize <= BITS && p <= q);
+            let denominators = (1..=64).chain((0..64).map(|_| rng.gen_range(1..=BITS as u64)));
+            for d in denominators.chain([BITS as u64]) {
+                for h in 0..=d {
+                    assert_eq!(
+                        (h as f64) / (d as f64) < threshold,
+                        h * (q as u64) < (p as u64) * d,
+                        "{h} / {d} against {threshold} as {p} / {q}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -2,8 +2,9 @@ use crate::{Bits, BITS, COLS};
 use bytemuck::{cast_slice_mut, Pod, Zeroable};
 use rand::{
     distributions::{Distribution, Standard},
-    thread_rng, Rng,
+    thread_rng, CryptoRng, Rng, SeedableRng,
 };
+use rand_chacha::ChaCha20Rng;
 use std::{
     array,
     iter::{self, Sum},
@@ -14,6 +15,10 @@ use std::{
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct EncodedBits(pub [u16; BITS]);
 
+/// Key from which a party's shares are expanded, see
+/// [`EncodedBits::expand`].
+pub type Seed = [u8; 32];
+
 unsafe impl Zeroable for EncodedBits {}
 
 unsafe impl Pod for EncodedBits {}
@@ -21,10 +26,15 @@ unsafe impl Pod for EncodedBits {}
 impl EncodedBits {
     /// Generate secret shares from this bitvector.
     pub fn share(&self, n: usize) -> Box<[EncodedBits]> {
+        self.share_with_rng(n, &mut thread_rng())
+    }
+
+    /// Like [`Self::share`], drawing the randomness from `rng`, e.g. a seeded
+    /// generator for reproducible shares.
+    pub fn share_with_rng<R: Rng + CryptoRng>(&self, n: usize, rng: &mut R) -> Box<[EncodedBits]> {
         assert!(n > 0);
 
         // Create `n - 1` random shares.
-        let mut rng = thread_rng();
         let mut result: Box<[EncodedBits]> = iter::repeat_with(|| rng.gen::<EncodedBits>())
             .take(n - 1)
             .chain(iter::once(EncodedBits([0_u16; BITS])))
@@ -37,6 +47,29 @@ impl EncodedBits {
         result
     }
 
+    /// The pseudorandom share of entry `index` expanded from `seed`.
+    ///
+    /// Every entry uses its own ChaCha stream, so shares can be expanded in
+    /// any order.
+    pub fn expand(seed: &Seed, index: usize) -> Self {
+        let mut rng = ChaCha20Rng::from_seed(*seed);
+        rng.set_stream(index as u64);
+        rng.gen()
+    }
+
+    /// Generate the last share of entry `index` for parties whose shares are
+    /// expanded from `seeds`.
+    ///
+    /// Together with the expanded shares this forms an additive sharing, so
+    /// only the returned share needs to be stored.
+    pub fn share_seeded(&self, seeds: &[Seed], index: usize) -> Self {
+        let mut last = *self;
+        for seed in seeds {
+            last -= &Self::expand(seed, index);
+        }
+        last
+    }
+
     pub fn rotate(&mut self, amount: i32) {
         if amount < 0 {
             let amount = amount.unsigned_abs() as usize;
@@ -119,6 +152,16 @@ impl<'a> Sum<&'a EncodedBits> for EncodedBits {
     }
 }
 
+impl Sum for EncodedBits {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        let mut result = Self::default();
+        for i in iter {
+            result += &i;
+        }
+        result
+    }
+}
+
 impl ops::Sub<EncodedBits> for &EncodedBits {
     type Output = EncodedBits;
 
@@ -186,6 +229,44 @@ impl ops::MulAssign<&EncodedBits> for EncodedBits {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_share_seeded() {
+        let mut rng = thread_rng();
+        for n in 1..5 {
+            let seeds = (1..n).map(|_| rng.gen()).collect::<Vec<Seed>>();
+            for index in [0, 1, 1000] {
+                let secret: EncodedBits = rng.gen();
+                let last = secret.share_seeded(&seeds, index);
+                let shares = seeds
+                    .iter()
+                    .map(|seed| EncodedBits::expand(seed, index))
+                    .chain(iter::once(last))
+                    .collect::<Vec<_>>();
+                assert_eq!(shares.iter().sum::<EncodedBits>(), secret);
+            }
+        }
+    }
+
+    #[test]
+    fn test_share_with_rng() {
+        let secret: EncodedBits = thread_rng().gen();
+        let shares = |seed| secret.share_with_rng(3, &mut ChaCha20Rng::seed_from_u64(seed));
+        assert_eq!(shares(1), shares(1));
+        assert_ne!(shares(1), shares(2));
+        assert_eq!(shares(1).iter().sum::<EncodedBits>(), secret);
+    }
+
+    #[test]
+    fn test_expand() {
+        let seed: Seed = thread_rng().gen();
+        assert_eq!(EncodedBits::expand(&seed, 7), EncodedBits::expand(&seed, 7));
+        assert_ne!(EncodedBits::expand(&seed, 7), EncodedBits::expand(&seed, 8));
+        assert_ne!(
+            EncodedBits::expand(&seed, 7),
+            EncodedBits::expand(&[0; 32], 7)
+        );
+    }
+
     #[test]
     fn test_rotated_inverse() {
         let mut rng = thread_rng();
@@ -0,0 +1,178 @@
+use crate::{
+    format::{self, DataFile, Kind},
+    merkle::Hash,
+    MAX_ENROLL,
+};
+use anyhow::{bail, Result};
+use mpc_iris_code::EncodedBits;
+use std::{mem::size_of, path::Path, sync::Arc};
+
+/// Append the new records of each part in the `payload` of an enroll frame
+/// to the part's file.
+///
+/// The records of every part follow each other in the payload. Files are
+/// synced before the new number of records is returned, with the new Merkle
+/// roots of the files, for the participant to acknowledge once it serves
+/// them.
+pub async fn append(
+    payload: Vec<u8>,
+    paths: &[&Path],
+    count: usize,
+    legacy: bool,
+) -> Result<(usize, [Hash; 2])> {
+    let records = records(payload.len(), paths.len())?;
+    let paths = paths
+        .iter()
+        .map(|path| path.to_path_buf())
+        .collect::<Vec<_>>();
+    let roots = tokio::task::spawn_blocking(move || {
+        let mut roots = [Hash::default(); 2];
+        for ((path, part), root) in paths
+            .iter()
+            .zip(payload.chunks_exact(records * size_of::<EncodedBits>()))
+            .zip(&mut roots)
+        {
+            if let Some(header) = format::append(path, part, legacy)? {
+                *root = header.root();
+            }
+        }
+        Result::<_>::Ok(roots)
+    })
+    .await??;
+
+    Ok((count + records, roots))
+}
+
+/// Number of records of every part in an enroll payload of `length` bytes.
+fn records(length: usize, parts: usize) -> Result<usize> {
+    let entry_size = parts * size_of::<EncodedBits>();
+    if !length.is_multiple_of(entry_size) {
+        bail!("Enrollment of {length} bytes, which is not whole records of every part.");
+    }
+    let records = length / entry_size;
+    if records == 0 || records > MAX_ENROLL {
+        bail!("Enrollment of {records} records, at most {MAX_ENROLL} allowed.");
+    }
+    Ok(records)
+}
+
+/// Truncate the files of the parts to their first `count` records, rolling
+/// back an enrollment that failed on other participants.
+///
+/// Returns the new Merkle roots of the files, as after enrollment.
+pub async fn truncate(paths: &[(&Path, Kind)], count: usize, legacy: bool) -> Result<[Hash; 2]> {
+    let paths = paths
+        .iter()
+        .map(|&(path, kind)| (path.to_path_buf(), kind))
+        .collect::<Vec<_>>();
+    tokio::task::spawn_blocking(move || {
+        let mut roots = [Hash::default(); 2];
+        for ((path, kind), root) in paths.iter().zip(&mut roots) {
+            if let Some(header) = format::truncate(path, *kind, count, legacy)? {
+                *root = header.root();
+            }
+        }
+        Ok(roots)
+    })
+    .await?
+}
+
+/// Memory map a file of records, as after it has grown or shrunk.
+pub fn remap(path: &Path, kind: Kind, legacy: bool) -> Result<Arc<DataFile>> {
+    Ok(Arc::new(DataFile::map(path, kind, legacy)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Dataset, Header, Writer};
+    use bytemuck::cast_slice;
+    use rand::{thread_rng, Rng};
+
+    fn random(count: usize) -> Vec<EncodedBits> {
+        (0..count).map(|_| thread_rng().gen()).collect()
+    }
+
+    /// Create an empty file of `kind` for party 0 of 2.
+    async fn created(path: &Path, kind: Kind) {
+        let header = Header::new(kind, 0, 2, 2, 1, Dataset::default());
+        Writer::create(path, header)
+            .await
+            .unwrap()
+            .finish()
+            .await
+            .unwrap();
+    }
+
+    /// Records of a file, with its Merkle root.
+    fn read(path: &Path, kind: Kind) -> (Vec<EncodedBits>, Hash) {
+        let data_file = DataFile::open(path, kind, false).unwrap();
+        let root = data_file.header().unwrap().root();
+        (cast_slice(&data_file).to_vec(), root)
+    }
+
+    #[test]
+    fn test_records() {
+        let size = size_of::<EncodedBits>();
+        assert_eq!(records(3 * 2 * size, 2).unwrap(), 3);
+        assert_eq!(records(3 * size, 1).unwrap(), 3);
+        assert_eq!(records(MAX_ENROLL * 2 * size, 2).unwrap(), MAX_ENROLL);
+        for (length, parts) in [(3 * size, 2), (2 * size + 1, 2), (size - 1, 1)] {
+            let err = records(length, parts).err().unwrap();
+            assert!(err.to_string().contains("not whole records"), "{err}");
+        }
+        for (length, parts) in [(0, 2), ((MAX_ENROLL + 1) * 2 * size, 2)] {
+            let err = records(length, parts).err().unwrap();
+            assert!(err.to_string().contains("at most"), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let (share, masks) = (dir.path().join("share"), dir.path().join("masks"));
+        created(&share, Kind::Share).await;
+        created(&masks, Kind::MaskShare).await;
+        let paths = [share.as_path(), masks.as_path()];
+
+        // The payload holds the records of the share, then of the masks.
+        let (patterns, mask_shares) = (random(3), random(3));
+        let payload = [cast_slice(&patterns), cast_slice(&mask_shares)].concat();
+        let (count, roots) = append(payload, &paths, 5, false).await.unwrap();
+        assert_eq!(count, 8);
+        assert_eq!(read(&share, Kind::Share), (patterns.clone(), roots[0]));
+        assert_eq!(
+            read(&masks, Kind::MaskShare),
+            (mask_shares.clone(), roots[1])
+        );
+        assert_ne!(roots[0], roots[1]);
+
+        // Invalid payloads leave the files alone.
+        let payload = cast_slice(&random(3)).to_vec();
+        assert!(append(payload, &paths, 8, false).await.is_err());
+        assert!(append(Vec::new(), &paths, 8, false).await.is_err());
+        assert_eq!(read(&share, Kind::Share).0, patterns);
+
+        // Rolling back to the first record gives the roots of enrolling it
+        // alone.
+        let first = dir.path().join("first");
+        created(&first, Kind::Share).await;
+        let (_, first_roots) = append(cast_slice(&patterns[..1]).to_vec(), &[&first], 0, false)
+            .await
+            .unwrap();
+        let paths = [
+            (share.as_path(), Kind::Share),
+            (masks.as_path(), Kind::MaskShare),
+        ];
+        let roots = truncate(&paths, 1, false).await.unwrap();
+        assert_eq!(roots[0], first_roots[0]);
+        assert_eq!(
+            read(&share, Kind::Share),
+            (patterns[..1].to_vec(), roots[0])
+        );
+        assert_eq!(
+            read(&masks, Kind::MaskShare),
+            (mask_shares[..1].to_vec(), roots[1])
+        );
+    }
+}
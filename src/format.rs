@@ -0,0 +1,833 @@
+use crate::merkle::{self, Builder, Hash, Verifier, BLOCK_SIZE};
+use anyhow::{bail, ensure, format_err, Context, Result};
+use bytemuck::{bytes_of, bytes_of_mut, cast_slice, Pod, Zeroable};
+use crc32fast::Hasher;
+use memmap::{Mmap, MmapOptions};
+use mpc_iris_code::{Bits, DistanceEngine, EncodedBits, Seed, COLS, ROWS};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fmt::{self, Display},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    mem::size_of,
+    ops::{Deref, Range},
+    path::{Path, PathBuf},
+};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+/// Identifies data files written by `prepare`.
+const MAGIC: [u8; 8] = *b"MPCIRIS\0";
+
+/// Current version of the header layout.
+const VERSION: u32 = 1;
+
+/// Written in native byte order, so it reads differently on a machine with
+/// the other byte order.
+const ENDIANNESS: u32 = 0x0102_0304;
+
+/// Size of the header in bytes. Records follow it, which keeps them aligned.
+pub const HEADER_SIZE: usize = size_of::<Header>();
+
+/// Contents of a data file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Plaintext masks, one `Bits` per entry.
+    Masks,
+    /// A party's shares of the encoded patterns.
+    Share,
+    /// A party's shares of the encoded masks.
+    MaskShare,
+    /// Seed from which a party's shares of the encoded patterns are expanded.
+    SeededShare,
+    /// Seed from which a party's shares of the encoded masks are expanded.
+    SeededMaskShare,
+}
+
+impl Kind {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Masks),
+            2 => Some(Self::Share),
+            3 => Some(Self::MaskShare),
+            4 => Some(Self::SeededShare),
+            5 => Some(Self::SeededMaskShare),
+            _ => None,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            Self::Masks => 1,
+            Self::Share => 2,
+            Self::MaskShare => 3,
+            Self::SeededShare => 4,
+            Self::SeededMaskShare => 5,
+        }
+    }
+
+    /// Kind of the seed from which records of this kind can be expanded.
+    pub fn seeded(self) -> Option<Self> {
+        match self {
+            Self::Share => Some(Self::SeededShare),
+            Self::MaskShare => Some(Self::SeededMaskShare),
+            _ => None,
+        }
+    }
+
+    /// Size of a record in bytes.
+    pub fn record_size(self) -> usize {
+        match self {
+            Self::Masks => size_of::<Bits>(),
+            Self::Share | Self::MaskShare => size_of::<EncodedBits>(),
+            Self::SeededShare | Self::SeededMaskShare => size_of::<Seed>(),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Masks => "masks",
+            Self::Share => "share",
+            Self::MaskShare => "mask share",
+            Self::SeededShare => "seeded share",
+            Self::SeededMaskShare => "seeded mask share",
+        })
+    }
+}
+
+/// Random identifier shared by all files prepared from the same input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Pod, Zeroable, Serialize, Deserialize)]
+#[repr(transparent)]
+#[serde(transparent)]
+pub struct Dataset(#[serde(with = "hex")] pub [u8; 16]);
+
+impl Display for Dataset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// Parse a dataset identifier from hex, for use as a command line argument.
+pub fn parse_dataset(s: &str) -> Result<Dataset> {
+    let mut dataset = Dataset::default();
+    hex::decode_to_slice(s, &mut dataset.0)
+        .map_err(|_| format_err!("Dataset identifiers are 32 hexadecimal digits."))?;
+    Ok(dataset)
+}
+
+/// Header at the start of every data file.
+///
+/// Describes the geometry and origin of the records, and holds a checksum of
+/// the records and one of the header itself. The root of the Merkle tree over
+/// the records commits to them, with the leaves kept next to the file. All
+/// fields are in native byte order, like the records.
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct Header {
+    magic:           [u8; 8],
+    version:         u32,
+    endianness:      u32,
+    kind:            u32,
+    rows:            u32,
+    cols:            u32,
+    record_size:     u32,
+    party:           u32,
+    parties:         u32,
+    threshold:       u32,
+    /// Records per entry.
+    records:         u32,
+    /// Records in the file.
+    count:           u64,
+    dataset:         Dataset,
+    data_checksum:   u32,
+    header_checksum: u32,
+    root:            Hash,
+    block_size:      u32,
+    reserved:        [u8; 4],
+    /// Records expanded from the seed, for seeded shares.
+    expanded:        u64,
+}
+
+impl Header {
+    /// Header for an empty file.
+    ///
+    /// Plaintext masks are not shared, but record the parties they were
+    /// prepared for.
+    pub fn new(
+        kind: Kind,
+        party: usize,
+        parties: usize,
+        threshold: usize,
+        records: usize,
+        dataset: Dataset,
+    ) -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            endianness: ENDIANNESS,
+            kind: kind.to_u32(),
+            rows: ROWS as u32,
+            cols: COLS as u32,
+            record_size: kind.record_size() as u32,
+            party: party as u32,
+            parties: parties as u32,
+            threshold: threshold as u32,
+            records: records as u32,
+            dataset,
+            root: merkle::root(&[]),
+            block_size: BLOCK_SIZE as u32,
+            ..Self::zeroed()
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        Kind::from_u32(self.kind).expect("validated on read")
+    }
+
+    pub fn party(&self) -> usize {
+        self.party as usize
+    }
+
+    pub fn parties(&self) -> usize {
+        self.parties as usize
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold as usize
+    }
+
+    /// Records per entry.
+    pub fn records(&self) -> usize {
+        self.records as usize
+    }
+
+    /// Records in the file.
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn dataset(&self) -> Dataset {
+        self.dataset
+    }
+
+    /// Root of the Merkle tree over the records.
+    pub fn root(&self) -> Hash {
+        self.root
+    }
+
+    pub fn set_root(&mut self, leaves: &[Hash]) {
+        self.root = merkle::root(leaves);
+    }
+
+    /// Records expanded from the seed, for seeded shares.
+    pub fn expanded(&self) -> usize {
+        self.expanded as usize
+    }
+
+    pub fn set_expanded(&mut self, records: usize) {
+        self.expanded = records as u64;
+    }
+
+    /// Size of the records in bytes.
+    pub fn data_len(&self) -> usize {
+        self.count() * self.record_size as usize
+    }
+
+    /// Account for records appended to the file.
+    pub fn append(&mut self, data: &[u8]) {
+        debug_assert!(data.len().is_multiple_of(self.record_size as usize));
+        let mut hasher = Hasher::new_with_initial(self.data_checksum);
+        hasher.update(data);
+        self.data_checksum = hasher.finalize();
+        self.count += (data.len() / self.record_size as usize) as u64;
+    }
+
+    /// Account for records removed from the end of the file, of which `data`
+    /// are the ones kept.
+    pub fn truncate(&mut self, data: &[u8]) {
+        debug_assert!(data.len().is_multiple_of(self.record_size as usize));
+        self.data_checksum = crc32fast::hash(data);
+        self.count = (data.len() / self.record_size as usize) as u64;
+    }
+
+    /// Remove all records, e.g. before appending those kept by compaction.
+    pub fn clear(&mut self) {
+        self.count = 0;
+        self.data_checksum = 0;
+        self.root = merkle::root(&[]);
+    }
+
+    /// Header as written to disk, with its checksum.
+    fn sealed(&self) -> Self {
+        Self {
+            header_checksum: self.compute_checksum(),
+            ..*self
+        }
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        let header = Self {
+            header_checksum: 0,
+            ..*self
+        };
+        crc32fast::hash(bytes_of(&header))
+    }
+
+    /// Check a header read from `path`, but not yet the records.
+    fn validate(&self, path: &Path) -> Result<()> {
+        if self.magic != MAGIC {
+            bail!(
+                "File {path:?} has no header. Files written before headers were introduced can be \
+                 read with --legacy."
+            );
+        }
+        ensure!(
+            self.version == VERSION,
+            "File {path:?} has format version {}, expected {VERSION}.",
+            self.version
+        );
+        ensure!(
+            self.endianness == ENDIANNESS,
+            "File {path:?} was written on a machine with a different byte order."
+        );
+        ensure!(
+            self.header_checksum == self.compute_checksum(),
+            "File {path:?} has a corrupted header."
+        );
+        let kind = Kind::from_u32(self.kind)
+            .with_context(|| format!("File {path:?} has unknown kind {}.", self.kind))?;
+        ensure!(
+            self.rows as usize == ROWS && self.cols as usize == COLS,
+            "File {path:?} has templates of {}x{}, expected {ROWS}x{COLS}.",
+            self.rows,
+            self.cols
+        );
+        ensure!(
+            self.record_size as usize == kind.record_size(),
+            "File {path:?} has records of {} bytes, expected {}.",
+            self.record_size,
+            kind.record_size()
+        );
+        ensure!(
+            self.block_size as usize == BLOCK_SIZE,
+            "File {path:?} has Merkle tree blocks of {} bytes, expected {BLOCK_SIZE}.",
+            self.block_size
+        );
+        ensure!(
+            self.party < self.parties && self.threshold <= self.parties,
+            "File {path:?} is for party {} with threshold {} of {}.",
+            self.party,
+            self.threshold,
+            self.parties
+        );
+        ensure!(
+            self.records > 0 && self.count.is_multiple_of(self.records as u64),
+            "File {path:?} has {} records, not a multiple of {} per entry.",
+            self.count,
+            self.records
+        );
+        Ok(())
+    }
+}
+
+/// Read and check the header of an open data file, and that the file holds
+/// the records it accounts for.
+///
+/// The header is written last, which commits an append. Bytes beyond the
+/// records it accounts for are left by an interrupted append and are ignored.
+pub fn read_header(file: &mut File, path: &Path) -> Result<Header> {
+    let mut header = Header::zeroed();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(bytes_of_mut(&mut header))
+        .with_context(|| format!("File {path:?} is too short for a header."))?;
+    header.validate(path)?;
+    let size = file.metadata()?.len() as usize;
+    ensure!(
+        size >= HEADER_SIZE + header.data_len(),
+        "File {path:?} has {} bytes of records, header says {}. It may be truncated.",
+        size.saturating_sub(HEADER_SIZE),
+        header.data_len()
+    );
+    Ok(header)
+}
+
+/// Discard the bytes of an open data file beyond the records its `header`
+/// accounts for, left by an interrupted append.
+pub fn discard_uncommitted(file: &mut File, path: &Path, header: &Header) -> Result<()> {
+    let end = (HEADER_SIZE + header.data_len()) as u64;
+    let size = file.metadata()?.len();
+    if size > end {
+        eprintln!(
+            "Discarding {} bytes of {path:?} left by an interrupted append.",
+            size - end
+        );
+        file.set_len(end)?;
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Replace the header of an open data file.
+pub fn write_header(file: &mut File, header: &Header) -> Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(bytes_of(&header.sealed()))?;
+    Ok(())
+}
+
+/// A memory mapped data file, dereferencing to its records.
+pub struct DataFile {
+    mmap:   Mmap,
+    header: Option<Header>,
+    /// End of the records in the file.
+    end:    usize,
+    tree:   Option<Verifier>,
+}
+
+impl DataFile {
+    /// Open a data file of `kind`, checking its header and records.
+    ///
+    /// With `legacy` the file is read without a header, so nothing but the
+    /// record size can be checked.
+    pub fn open(path: &Path, kind: Kind, legacy: bool) -> Result<Self> {
+        let data_file = Self::map(path, kind, legacy)?;
+        if let Some(header) = &data_file.header {
+            ensure!(
+                crc32fast::hash(&data_file) == header.data_checksum,
+                "File {path:?} has corrupted records."
+            );
+        }
+        Ok(data_file)
+    }
+
+    /// Open a data file of `kind` checking only its header and Merkle tree.
+    /// Records are verified against the tree with `verify` as they are used.
+    pub fn map(path: &Path, kind: Kind, legacy: bool) -> Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        let header = if legacy {
+            None
+        } else {
+            let header = read_header(&mut file, path)?;
+            ensure!(
+                header.kind() == kind,
+                "File {path:?} contains a {}, expected a {kind}.",
+                header.kind()
+            );
+            Some(header)
+        };
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+        let end = header.map_or(mmap.len(), |header| HEADER_SIZE + header.data_len());
+        if mmap.len() > end {
+            eprintln!(
+                "Ignoring {} bytes of {path:?} left by an interrupted append.",
+                mmap.len() - end
+            );
+        }
+        let mut data_file = Self {
+            mmap,
+            header,
+            end,
+            tree: None,
+        };
+        ensure!(
+            data_file.len().is_multiple_of(kind.record_size()),
+            "File {path:?} is not a multiple of {} byte records.",
+            kind.record_size()
+        );
+        if let Some(header) = &data_file.header {
+            let leaves = load_leaves(path, header, &data_file)?;
+            data_file.tree = Some(Verifier::new(path, leaves));
+        }
+        Ok(data_file)
+    }
+
+    /// The header, unless the file was opened as a legacy file.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// Check the records in the byte `range` against the Merkle tree.
+    pub fn verify(&self, range: Range<usize>) -> Result<()> {
+        match &self.tree {
+            Some(tree) => tree.verify(self, range),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Deref for DataFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let offset = if self.header.is_some() {
+            HEADER_SIZE
+        } else {
+            0
+        };
+        &self.mmap[offset..self.end]
+    }
+}
+
+/// Records of one part of a participant's entries, either stored or expanded
+/// from a seed.
+pub enum Records {
+    Stored(DataFile),
+    Seeded { header: Header, seed: Seed },
+}
+
+impl Records {
+    /// Open a file of records of `kind`, or the seed they are expanded from,
+    /// checking the header and records.
+    pub fn open(path: &Path, kind: Kind, legacy: bool) -> Result<Self> {
+        Self::open_with(path, kind, legacy, DataFile::open)
+    }
+
+    /// Like [`Self::open`], but verifying stored records only as they are
+    /// processed.
+    pub fn map(path: &Path, kind: Kind, legacy: bool) -> Result<Self> {
+        Self::open_with(path, kind, legacy, DataFile::map)
+    }
+
+    fn open_with(
+        path: &Path,
+        kind: Kind,
+        legacy: bool,
+        open: impl FnOnce(&Path, Kind, bool) -> Result<DataFile>,
+    ) -> Result<Self> {
+        if let Some(seeded) = kind.seeded().filter(|_| !legacy) {
+            let mut file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+            if read_header(&mut file, path)?.kind() == seeded {
+                let data_file = DataFile::open(path, seeded, false)?;
+                let header = *data_file.header().unwrap();
+                ensure!(
+                    header.count() == 1,
+                    "File {path:?} has {} seeds, expected one.",
+                    header.count()
+                );
+                let seed = data_file[..].try_into().unwrap();
+                return Ok(Self::Seeded { header, seed });
+            }
+        }
+        Ok(Self::Stored(open(path, kind, legacy)?))
+    }
+
+    /// The header, unless the file was opened as a legacy file.
+    pub fn header(&self) -> Option<&Header> {
+        match self {
+            Self::Stored(data_file) => data_file.header(),
+            Self::Seeded { header, .. } => Some(header),
+        }
+    }
+
+    /// Root of the Merkle tree, unless the file was opened as a legacy file.
+    pub fn root(&self) -> Option<Hash> {
+        self.header().map(Header::root)
+    }
+
+    pub fn is_seeded(&self) -> bool {
+        matches!(self, Self::Seeded { .. })
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Stored(data_file) => data_file.len() / size_of::<EncodedBits>(),
+            Self::Seeded { header, .. } => header.expanded(),
+        }
+    }
+
+    /// The records in `range`, expanded if necessary.
+    pub fn get(&self, range: Range<usize>) -> Cow<'_, [EncodedBits]> {
+        match self {
+            Self::Stored(data_file) => Cow::Borrowed(&cast_slice(data_file)[range]),
+            Self::Seeded { seed, .. } => {
+                Cow::Owned(range.map(|i| EncodedBits::expand(seed, i)).collect())
+            }
+        }
+    }
+
+    /// The record at `index`, expanded if necessary.
+    pub fn record(&self, index: usize) -> EncodedBits {
+        match self {
+            Self::Stored(data_file) => cast_slice(data_file)[index],
+            Self::Seeded { seed, .. } => EncodedBits::expand(seed, index),
+        }
+    }
+
+    /// Evaluate `engine` on the records in `range`, verifying stored records
+    /// against the Merkle tree on first use.
+    pub fn process(
+        &self,
+        engine: &DistanceEngine,
+        out: &mut [[u16; 31]],
+        range: Range<usize>,
+    ) -> Result<()> {
+        match self {
+            Self::Stored(data_file) => {
+                let size = size_of::<EncodedBits>();
+                data_file.verify(range.start * size..range.end * size)?;
+                engine.batch_process(out, &cast_slice(data_file)[range]);
+            }
+            Self::Seeded { seed, .. } => engine.batch_process_seeded(out, seed, range),
+        }
+        Ok(())
+    }
+}
+
+/// Leaves of the Merkle tree of a data file, recomputed from the records if
+/// they are missing or outdated.
+fn load_leaves(path: &Path, header: &Header, data: &[u8]) -> Result<Vec<Hash>> {
+    if let Some(leaves) = merkle::read_leaves(path, &header.root) {
+        return Ok(leaves);
+    }
+    eprintln!("Recomputing Merkle tree of {path:?}.");
+    let leaves = merkle::leaves(data);
+    ensure!(
+        merkle::root(&leaves) == header.root,
+        "File {path:?} does not match its Merkle root {}.",
+        header.root
+    );
+    merkle::write_leaves(path, &leaves)?;
+    Ok(leaves)
+}
+
+/// Durably append records to a data file, updating its header and tree.
+///
+/// Returns the updated header, or `None` for legacy files.
+pub fn append(path: &Path, data: &[u8], legacy: bool) -> Result<Option<Header>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {path:?} for appending"))?;
+    if legacy {
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        return Ok(None);
+    }
+
+    // Only the last block of the tree changes, besides the new ones.
+    let mut header = read_header(&mut file, path)?;
+    discard_uncommitted(&mut file, path, &header)?;
+    let tail_len = header.data_len() % BLOCK_SIZE;
+    let mut tail = vec![0_u8; tail_len];
+    file.seek(SeekFrom::End(-(tail_len as i64)))?;
+    file.read_exact(&mut tail)?;
+    let leaves = match merkle::read_leaves(path, &header.root) {
+        Some(leaves) => leaves,
+        None => {
+            let mmap = unsafe { MmapOptions::new().map(&file)? };
+            load_leaves(path, &header, &mmap[HEADER_SIZE..])?
+        }
+    };
+    let mut tree = Builder::resume(leaves, &tail);
+    tree.update(data);
+    let leaves = tree.finish();
+
+    // Records are written before the header that accounts for them, so an
+    // interrupted append leaves bytes that are ignored until the next one.
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    merkle::write_leaves(path, &leaves)?;
+    header.append(data);
+    header.set_root(&leaves);
+    write_header(&mut file, &header)?;
+    file.sync_data()?;
+    Ok(Some(header))
+}
+
+/// Durably truncate a data file of `kind` to its first `count` records, e.g.
+/// to roll back an append.
+///
+/// Returns the updated header, or `None` for legacy files. The records must
+/// not be in use beyond `count`, as they are discarded.
+pub fn truncate(path: &Path, kind: Kind, count: usize, legacy: bool) -> Result<Option<Header>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {path:?} for truncation"))?;
+    let len = count * kind.record_size();
+    if legacy {
+        ensure!(
+            file.metadata()?.len() as usize >= len,
+            "File {path:?} has fewer than {count} records."
+        );
+        file.set_len(len as u64)?;
+        file.sync_data()?;
+        return Ok(None);
+    }
+
+    let mut header = read_header(&mut file, path)?;
+    ensure!(
+        header.kind() == kind,
+        "File {path:?} contains a {}, expected a {kind}.",
+        header.kind()
+    );
+    ensure!(
+        count <= header.count(),
+        "File {path:?} has {} records, can not truncate to {count}.",
+        header.count()
+    );
+    if count == header.count() {
+        return Ok(Some(header));
+    }
+
+    // The header commits the truncation, the records beyond it are then
+    // ignored even if discarding them is interrupted.
+    let mmap = unsafe { MmapOptions::new().map(&file)? };
+    let data = &mmap[HEADER_SIZE..HEADER_SIZE + len];
+    let leaves = merkle::leaves(data);
+    header.truncate(data);
+    drop(mmap);
+    merkle::write_leaves(path, &leaves)?;
+    header.set_root(&leaves);
+    write_header(&mut file, &header)?;
+    file.sync_data()?;
+    file.set_len((HEADER_SIZE + len) as u64)?;
+    file.sync_data()?;
+    Ok(Some(header))
+}
+
+/// Writes a new data file, followed by its header once all records are known.
+pub struct Writer {
+    path:   PathBuf,
+    file:   BufWriter<tokio::fs::File>,
+    header: Header,
+    tree:   Builder,
+}
+
+impl Writer {
+    pub async fn create(path: &Path, header: Header) -> Result<Self> {
+        let file = tokio::fs::File::create(path)
+            .await
+            .with_context(|| format!("Failed to create file at {path:?}"))?;
+        // Placeholder until the records are written, an incomplete file has no
+        // valid header.
+        let mut file = BufWriter::new(file);
+        file.write_all(&[0; HEADER_SIZE]).await?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            header,
+            tree: Builder::new(),
+        })
+    }
+
+    /// Record the number of records expanded from the seed written to a
+    /// seeded share.
+    pub fn set_expanded(&mut self, records: usize) {
+        self.header.set_expanded(records);
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await?;
+        self.header.append(data);
+        self.tree.update(data);
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<Header> {
+        let leaves = self.tree.finish();
+        merkle::write_leaves(&self.path, &leaves)?;
+        self.header.set_root(&leaves);
+        self.file.flush().await?;
+        let mut file = self.file.into_inner();
+        file.seek(SeekFrom::Start(0)).await?;
+        file.write_all(bytes_of(&self.header.sealed()))
+            .await
+            .with_context(|| format!("Failed to write header of {:?}", self.path))?;
+        file.sync_all().await?;
+        Ok(self.header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+    use std::fs;
+
+    fn random(count: usize) -> Vec<EncodedBits> {
+        (0..count).map(|_| thread_rng().gen()).collect()
+    }
+
+    /// Write a share file of `count` random records.
+    async fn written(path: &Path, count: usize) -> Vec<EncodedBits> {
+        let records = random(count);
+        let header = Header::new(Kind::Share, 0, 2, 2, 1, Dataset::default());
+        let mut writer = Writer::create(path, header).await.unwrap();
+        writer.write(cast_slice(&records)).await.unwrap();
+        writer.finish().await.unwrap();
+        records
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("share");
+        let mut records = written(&path, 10).await;
+
+        // An append interrupted before its header leaves records behind.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(cast_slice(&random(3))).unwrap();
+        drop(file);
+
+        // They are ignored, and discarded by the next append.
+        let data_file = DataFile::open(&path, Kind::Share, false).unwrap();
+        assert_eq!(cast_slice::<_, EncodedBits>(&data_file), &records[..]);
+        let added = random(2);
+        let header = append(&path, cast_slice(&added), false).unwrap().unwrap();
+        records.extend(added);
+        assert_eq!(header.count(), records.len());
+        let size = fs::metadata(&path).unwrap().len() as usize;
+        assert_eq!(size, HEADER_SIZE + header.data_len());
+        let data_file = DataFile::open(&path, Kind::Share, false).unwrap();
+        assert_eq!(cast_slice::<_, EncodedBits>(&data_file), &records[..]);
+        assert!(merkle::check(&path, &header).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_truncate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("share");
+        let mut records = written(&path, 10).await;
+        let before = append(&path, &[], false).unwrap().unwrap();
+        let added = random(3);
+        append(&path, cast_slice(&added), false).unwrap();
+
+        // Rolling back the append restores the previous header and tree.
+        let header = truncate(&path, Kind::Share, 10, false).unwrap().unwrap();
+        assert_eq!(bytes_of(&header.sealed()), bytes_of(&before.sealed()));
+        let data_file = DataFile::open(&path, Kind::Share, false).unwrap();
+        assert_eq!(cast_slice::<_, EncodedBits>(&data_file), &records[..]);
+        assert!(merkle::check(&path, &header).unwrap().is_empty());
+        assert!(truncate(&path, Kind::Share, 11, false).is_err());
+        assert!(truncate(&path, Kind::Masks, 10, false).is_err());
+
+        // Appends continue from the truncated records.
+        records.truncate(4);
+        truncate(&path, Kind::Share, 4, false).unwrap();
+        let added = random(2);
+        let header = append(&path, cast_slice(&added), false).unwrap().unwrap();
+        records.extend(added);
+        assert_eq!(header.count(), records.len());
+        let data_file = DataFile::open(&path, Kind::Share, false).unwrap();
+        assert_eq!(cast_slice::<_, EncodedBits>(&data_file), &records[..]);
+    }
+
+    #[tokio::test]
+    async fn test_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("share");
+        written(&path, 4).await;
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let size = file.metadata().unwrap().len();
+        file.set_len(size - 1).unwrap();
+        let err = DataFile::open(&path, Kind::Share, false).err().unwrap();
+        assert!(err.to_string().contains("truncated"), "{err}");
+    }
+}
@@ -0,0 +1,257 @@
+use crate::{
+    format::{Dataset, Records},
+    merkle::{Hash, Manifest},
+    protocol::{Frame, Message, VERSION},
+};
+use anyhow::{bail, ensure, Context, Result};
+use bytemuck::{bytes_of, bytes_of_mut, Pod, Zeroable};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Identifies participants at the start of the announcement.
+const MAGIC: [u8; 8] = *b"MPCPART\0";
+
+/// Size of the magic and version at the start of the announcement.
+const PREFIX_SIZE: usize = 16;
+
+/// Party of a participant serving legacy files without a header.
+const UNKNOWN_PARTY: u64 = u64::MAX;
+
+/// Sent by a participant when it accepts a connection, describing the protocol
+/// it speaks and the data it serves.
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct Announcement {
+    magic:     [u8; 8],
+    version:   u32,
+    reserved:  u32,
+    /// Number of records.
+    pub count: u64,
+    /// Parts per record, two when mask shares are served.
+    pub parts: u64,
+    party:     u64,
+    dataset:   Dataset,
+    /// Merkle roots of the share and the mask share.
+    roots:     [Hash; 2],
+}
+
+impl Announcement {
+    /// Describe the `count` records of a participant's share and optional
+    /// mask share.
+    pub fn new(count: usize, share: &Records, masks: Option<&Records>) -> Self {
+        let (party, dataset) = match share.header() {
+            Some(header) => (header.party() as u64, header.dataset()),
+            None => (UNKNOWN_PARTY, Dataset::default()),
+        };
+        let mut roots = [Hash::default(); 2];
+        for (root, file) in roots.iter_mut().zip(Some(share).into_iter().chain(masks)) {
+            *root = file.root().unwrap_or_default();
+        }
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            reserved: 0,
+            count: count as u64,
+            parts: if masks.is_some() { 2 } else { 1 },
+            party,
+            dataset,
+            roots,
+        }
+    }
+
+    /// Read the announcement, failing unless the participant speaks this
+    /// version of the protocol.
+    pub async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Self> {
+        // The version is checked first, as other versions may announce less.
+        let mut announcement = Self::zeroed();
+        stream
+            .read_exact(&mut bytes_of_mut(&mut announcement)[..PREFIX_SIZE])
+            .await?;
+        ensure!(
+            announcement.magic == MAGIC,
+            "Peer is not a participant of a compatible version."
+        );
+        ensure!(
+            announcement.version == VERSION,
+            "Participant speaks protocol version {}, expected {VERSION}.",
+            announcement.version
+        );
+        stream
+            .read_exact(&mut bytes_of_mut(&mut announcement)[PREFIX_SIZE..])
+            .await?;
+        Ok(announcement)
+    }
+
+    /// Parse the payload of an announce frame.
+    pub fn parse(frame: &Frame) -> Result<Self> {
+        ensure!(
+            frame.message == Message::Announce,
+            "Expected an announce frame, received a {} frame.",
+            frame.message
+        );
+        let mut announcement = Self::zeroed();
+        frame.read_into(bytes_of_mut(&mut announcement))?;
+        Ok(announcement)
+    }
+
+    pub async fn write<S: AsyncWrite + Unpin>(&self, stream: &mut S) -> Result<()> {
+        stream.write_all(bytes_of(self)).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Roots of the parts, unless the participant serves legacy files.
+    pub fn roots(&self) -> Option<&[Hash]> {
+        (self.party != UNKNOWN_PARTY).then(|| &self.roots[..self.parts as usize])
+    }
+
+    /// Check the participant at `address` serves the share of `party` with
+    /// `parts` parts per record, from `dataset` and matching `manifest` if
+    /// given.
+    pub fn check(
+        &self,
+        address: SocketAddr,
+        party: usize,
+        parts: usize,
+        dataset: Option<Dataset>,
+        manifest: Option<&Manifest>,
+    ) -> Result<()> {
+        if self.parts as usize != parts {
+            bail!(
+                "Participant {address} has {} parts per record, expected {parts}.",
+                self.parts
+            );
+        }
+        if self.party == UNKNOWN_PARTY {
+            if dataset.is_some() || manifest.is_some() {
+                bail!("Participant {address} serves legacy files, which can not be verified.");
+            }
+            return Ok(());
+        }
+        if self.party as usize != party {
+            bail!(
+                "Participant {address} serves the share of party {}, expected {party}.",
+                self.party
+            );
+        }
+        if let Some(dataset) = dataset.filter(|&dataset| dataset != self.dataset) {
+            bail!(
+                "Participant {address} serves dataset {}, expected {dataset}.",
+                self.dataset
+            );
+        }
+        if let Some(manifest) = manifest {
+            let expected = iter_roots(manifest, party).collect::<Vec<_>>();
+            if self.roots().unwrap() != expected {
+                bail!(
+                    "Participant {address} serves data with Merkle roots {:?}, expected {:?}.",
+                    self.roots().unwrap(),
+                    expected
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Connect to the participant at `address` and read its announcement.
+pub async fn connect(address: SocketAddr) -> Result<(TcpStream, Announcement)> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .with_context(|| format!("Could not connect to {address}"))?;
+    // Frames are flushed whole, so they are sent without waiting for more.
+    stream.set_nodelay(true)?;
+    let announcement = Announcement::read(&mut stream)
+        .await
+        .with_context(|| format!("Handshake with participant {address} failed"))?;
+    Ok((stream, announcement))
+}
+
+/// Roots of the share and mask share of `party` in `manifest`.
+fn iter_roots(manifest: &Manifest, party: usize) -> impl Iterator<Item = Hash> + '_ {
+    manifest
+        .shares
+        .get(party)
+        .into_iter()
+        .chain(manifest.mask_shares.get(party))
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(party: u64, dataset: Dataset, root: Hash) -> Announcement {
+        Announcement {
+            magic: MAGIC,
+            version: VERSION,
+            reserved: 0,
+            count: 4,
+            parts: 1,
+            party,
+            dataset,
+            roots: [root, Hash::default()],
+        }
+    }
+
+    fn manifest(dataset: Dataset, shares: Vec<Hash>) -> Manifest {
+        Manifest {
+            dataset,
+            masks: None,
+            shares,
+            mask_shares: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let announcement = announcement(0, Dataset::default(), Hash::default());
+        let mut bytes = bytes_of(&announcement).to_vec();
+        let read = Announcement::read(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(read.count, 4);
+
+        bytes[8..12].copy_from_slice(&(VERSION + 1).to_ne_bytes());
+        let err = Announcement::read(&mut bytes.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("protocol version"), "{err}");
+        bytes[0] = b'X';
+        let err = Announcement::read(&mut bytes.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("not a participant"), "{err}");
+    }
+
+    #[test]
+    fn test_check() {
+        let address = "127.0.0.1:1234".parse().unwrap();
+        let dataset = Dataset([1; 16]);
+        let roots = [Hash([2; 32]), Hash([3; 32])];
+        let manifest = manifest(dataset, roots.to_vec());
+        let announcement = announcement(1, dataset, roots[1]);
+        announcement
+            .check(address, 1, 1, Some(dataset), Some(&manifest))
+            .unwrap();
+
+        let rejected = |party, parts, dataset, manifest: Option<&Manifest>| {
+            announcement
+                .check(address, party, parts, dataset, manifest)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(rejected(0, 1, None, None).contains("share of party 1"));
+        assert!(rejected(1, 2, None, None).contains("parts per record"));
+        assert!(rejected(1, 1, Some(Dataset([4; 16])), None).contains("dataset"));
+        let other = self::manifest(dataset, vec![roots[0], Hash([5; 32])]);
+        assert!(rejected(1, 1, None, Some(&other)).contains("Merkle roots"));
+
+        // Legacy files can not be checked against the dataset or manifest.
+        let legacy = self::announcement(UNKNOWN_PARTY, Dataset::default(), Hash::default());
+        legacy.check(address, 1, 1, None, None).unwrap();
+        assert!(legacy
+            .check(address, 1, 1, Some(dataset), None)
+            .unwrap_err()
+            .to_string()
+            .contains("legacy"));
+        assert!(legacy.check(address, 1, 1, None, Some(&manifest)).is_err());
+    }
+}
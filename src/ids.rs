@@ -0,0 +1,283 @@
+use anyhow::{bail, ensure, Context, Result};
+use mpc_iris_code::{Bits, Template};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// A template with an optional external identifier, as found in input files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub pattern: Bits,
+    pub mask:    Bits,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id:      Option<String>,
+}
+
+impl Entry {
+    pub fn template(&self) -> Template {
+        Template {
+            pattern: self.pattern,
+            mask:    self.mask,
+        }
+    }
+}
+
+/// Identifiers are stored one per line, so must be non-empty single lines.
+pub fn validate(id: &str) -> Result<()> {
+    ensure!(!id.is_empty(), "Identifiers must not be empty.");
+    ensure!(
+        !id.contains(['\n', '\r']),
+        "Identifier {id:?} must not contain line breaks."
+    );
+    Ok(())
+}
+
+/// External identifiers of entries, kept in a sidecar file with one line per
+/// entry.
+///
+/// Entries without an identifier have an empty line. The file may end before
+/// the last entry if later entries have no identifiers.
+pub struct Ids {
+    path:  PathBuf,
+    ids:   Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl Ids {
+    /// Read identifiers from `path`, or start empty if it does not exist.
+    pub fn open(path: &Path) -> Result<Self> {
+        let ids = match fs::read_to_string(path) {
+            Ok(contents) => contents.lines().map(str::to_owned).collect::<Vec<_>>(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read identifiers {path:?}"))
+            }
+        };
+        // Later entries take precedence, as earlier ones may be deleted.
+        let index = ids
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| !id.is_empty())
+            .map(|(entry, id)| (id.clone(), entry))
+            .collect::<HashMap<_, _>>();
+        Ok(Self {
+            path: path.to_path_buf(),
+            ids,
+            index,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Identifier of an entry, if it has one.
+    pub fn get(&self, entry: usize) -> Option<&str> {
+        self.ids
+            .get(entry)
+            .map(String::as_str)
+            .filter(|id| !id.is_empty())
+    }
+
+    /// Latest entry with an identifier.
+    pub fn find(&self, id: &str) -> Option<usize> {
+        self.index.get(id).copied()
+    }
+
+    /// Durably record the identifiers of new entries starting at `first`.
+    pub fn append(&mut self, first: usize, ids: &[Option<String>]) -> Result<()> {
+        ensure!(
+            first >= self.ids.len(),
+            "Identifiers for entry {first} already recorded."
+        );
+        let Some(last) = ids.iter().rposition(Option::is_some) else {
+            return Ok(());
+        };
+        let lines = (self.ids.len()..first)
+            .map(|_| String::new())
+            .chain(ids[..=last].iter().map(|id| id.clone().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open identifiers {:?}", self.path))?;
+        let mut buffer = String::new();
+        for line in &lines {
+            buffer.push_str(line);
+            buffer.push('\n');
+        }
+        file.write_all(buffer.as_bytes())?;
+        file.sync_data()?;
+        for line in lines {
+            if !line.is_empty() {
+                self.index.insert(line.clone(), self.ids.len());
+            }
+            self.ids.push(line);
+        }
+        Ok(())
+    }
+}
+
+/// Writes the identifiers of a new database, rejecting duplicates.
+pub struct IdWriter {
+    path:  PathBuf,
+    file:  BufWriter<File>,
+    seen:  HashSet<String>,
+    count: usize,
+}
+
+impl IdWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create identifiers {path:?}"))?;
+        Ok(Self {
+            path:  path.to_path_buf(),
+            file:  BufWriter::new(file),
+            seen:  HashSet::new(),
+            count: 0,
+        })
+    }
+
+    pub fn push(&mut self, id: Option<&str>) -> Result<()> {
+        if let Some(id) = id {
+            validate(id)?;
+            if !self.seen.insert(id.to_owned()) {
+                bail!("Duplicate identifier {id:?} for entry {}.", self.count);
+            }
+            self.file.write_all(id.as_bytes())?;
+        }
+        self.file.write_all(b"\n")?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Finish the file, or remove it if no entry had an identifier.
+    pub fn finish(mut self) -> Result<usize> {
+        self.file.flush()?;
+        if self.seen.is_empty() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(self.seen.len())
+    }
+}
+
+/// Remove the lines of deleted entries, overwriting the original file before
+/// it is replaced.
+pub fn compact(path: &Path, deleted: impl Fn(usize) -> bool) -> Result<()> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read identifiers {path:?}"))?;
+    let mut output = String::with_capacity(contents.len());
+    for (entry, line) in contents.lines().enumerate() {
+        if !deleted(entry) {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0_u8; contents.len()])?;
+    file.sync_data()?;
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".compacting");
+    let temp = PathBuf::from(temp);
+    let mut file = File::create(&temp)?;
+    file.write_all(output.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    eprintln!(
+        "Compacted identifiers {path:?} from {} to {} lines.",
+        contents.lines().count(),
+        output.lines().count()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<Option<String>> {
+        ids.iter()
+            .map(|id| Some(id.to_string()).filter(|id| !id.is_empty()))
+            .collect()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ids");
+        let mut index = Ids::open(&path).unwrap();
+        assert_eq!(index.len(), 0);
+        index.append(0, &ids(&["a", "", "c"])).unwrap();
+        // Entries enrolled without identifiers leave no lines until a later
+        // entry has one.
+        index.append(3, &ids(&["", ""])).unwrap();
+        index.append(5, &ids(&["f", ""])).unwrap();
+        assert!(index.append(4, &ids(&["x"])).is_err());
+
+        let index = Ids::open(&path).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.get(0), Some("a"));
+        assert_eq!(index.get(1), None);
+        assert_eq!(index.get(5), Some("f"));
+        assert_eq!(index.get(6), None);
+        assert_eq!(index.find("c"), Some(2));
+        assert_eq!(index.find("f"), Some(5));
+        assert_eq!(index.find("b"), None);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ids");
+        let mut writer = IdWriter::create(&path).unwrap();
+        writer.push(Some("a")).unwrap();
+        writer.push(None).unwrap();
+        let err = writer.push(Some("a")).unwrap_err();
+        assert!(err.to_string().contains("Duplicate"), "{err}");
+        assert!(writer.push(Some("")).is_err());
+        assert!(writer.push(Some("b\nc")).is_err());
+        assert_eq!(writer.finish().unwrap(), 1);
+
+        // An identifier enrolled again after its entry was deleted refers to
+        // the new entry.
+        let mut index = Ids::open(&path).unwrap();
+        index.append(2, &ids(&["a"])).unwrap();
+        assert_eq!(index.find("a"), Some(2));
+        assert_eq!(Ids::open(&path).unwrap().find("a"), Some(2));
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ids");
+        let mut writer = IdWriter::create(&path).unwrap();
+        for id in ["a", "b", "", "d"] {
+            writer.push(Some(id).filter(|id| !id.is_empty())).unwrap();
+        }
+        writer.finish().unwrap();
+        let mut index = Ids::open(&path).unwrap();
+        index.append(6, &ids(&["g"])).unwrap();
+
+        // Deleting entries 1 and 4 shifts the later ones down, as in the data
+        // files.
+        compact(&path, |entry| entry == 1 || entry == 4).unwrap();
+        let index = Ids::open(&path).unwrap();
+        assert_eq!(index.find("a"), Some(0));
+        assert_eq!(index.find("b"), None);
+        assert_eq!(index.get(1), None);
+        assert_eq!(index.find("d"), Some(2));
+        assert_eq!(index.find("g"), Some(4));
+
+        // Enrollment continues after the remaining entries.
+        let mut index = index;
+        index.append(5, &ids(&["h"])).unwrap();
+        assert_eq!(Ids::open(&path).unwrap().find("h"), Some(5));
+    }
+}
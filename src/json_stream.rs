@@ -1,6 +1,11 @@
+use itertools::Either;
+use rayon::prelude::*;
 use serde::de::DeserializeOwned;
-use serde_json::{self, Deserializer};
-use std::io::{self, Read};
+use serde_json::{self, value::RawValue, Deserializer};
+use std::{
+    io::{self, BufRead, Read},
+    iter,
+};
 
 fn read_skipping_ws(mut reader: impl Read) -> io::Result<u8> {
     loop {
@@ -56,3 +61,146 @@ pub fn iter_json_array<T: DeserializeOwned, R: Read>(
     let mut at_start = false;
     std::iter::from_fn(move || yield_next_obj(&mut reader, &mut at_start).transpose())
 }
+
+/// Like [`iter_json_array`], but also accepts objects that are not wrapped in
+/// an array, such as a single object or newline-delimited JSON. Empty input
+/// has no objects.
+pub fn iter_json_objects<T: DeserializeOwned, R: BufRead>(
+    mut reader: R,
+) -> impl Iterator<Item = Result<T, io::Error>> {
+    // Peek at the first non-whitespace byte
+    let first = loop {
+        match reader.fill_buf() {
+            Ok([]) => break Ok(None),
+            Ok(buf) => {
+                let whitespace = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+                if whitespace < buf.len() {
+                    break Ok(Some(buf[whitespace]));
+                }
+                reader.consume(whitespace);
+            }
+            Err(err) => break Err(err),
+        }
+    };
+    match first {
+        Ok(Some(b'{')) => Either::Left(
+            Deserializer::from_reader(reader)
+                .into_iter()
+                .map(|result| result.map_err(Into::into)),
+        ),
+        Ok(Some(_)) => Either::Right(Either::Left(iter_json_array(reader))),
+        Ok(None) => Either::Right(Either::Right(None.into_iter())),
+        Err(err) => Either::Right(Either::Right(Some(Err(err)).into_iter())),
+    }
+}
+
+/// Like [`iter_json_objects`], but yields batches of up to `batch` objects,
+/// which are deserialized in parallel. Ends after the first error.
+///
+/// Only the extent of every object is found sequentially, which is much
+/// cheaper than deserializing them, e.g. decoding the hex strings of
+/// templates.
+pub fn par_json_objects<T: DeserializeOwned + Send, R: BufRead>(
+    reader: R,
+    batch: usize,
+) -> impl Iterator<Item = Result<Vec<T>, io::Error>> {
+    let mut objects = iter_json_objects::<Box<RawValue>, _>(reader).fuse();
+    let mut failed = false;
+    iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let values = match objects.by_ref().take(batch).collect::<Result<Vec<_>, _>>() {
+            Ok(values) if values.is_empty() => return None,
+            Ok(values) => values,
+            Err(err) => {
+                failed = true;
+                return Some(Err(err));
+            }
+        };
+        let result: Result<Vec<T>, io::Error> = values
+            .par_iter()
+            .map(|value| serde_json::from_str(value.get()).map_err(Into::into))
+            .collect();
+        failed = result.is_err();
+        Some(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::io::BufReader;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Object {
+        a: u32,
+    }
+
+    /// Objects read from `input` through a buffer of `capacity` bytes.
+    fn objects(input: &str, capacity: usize) -> io::Result<Vec<u32>> {
+        let reader = BufReader::with_capacity(capacity, input.as_bytes());
+        iter_json_objects::<Object, _>(reader)
+            .map(|object| object.map(|object| object.a))
+            .collect()
+    }
+
+    #[test]
+    fn test_formats() {
+        let inputs = [
+            ("[{\"a\": 1}, {\"a\": 2},{\"a\":3}]", vec![1, 2, 3]),
+            ("[]", vec![]),
+            ("{\n  \"a\": 1\n}\n", vec![1]),
+            ("{\"a\": 1}\n{\"a\": 2}\n{\"a\": 3}", vec![1, 2, 3]),
+            ("", vec![]),
+            (" \n\t ", vec![]),
+        ];
+        for (input, expected) in inputs {
+            // Leading whitespace may take more than one buffer.
+            let input = format!("{}{input}", " \n".repeat(10));
+            for capacity in [1, 3, 8192] {
+                assert_eq!(objects(&input, capacity).unwrap(), expected, "{input:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        for input in [
+            "x",
+            "[{\"a\": 1}",
+            "[{\"a\": 1} {\"a\": 2}]",
+            "{\"a\": 1} x",
+        ] {
+            assert!(objects(input, 8192).is_err(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn test_batches() {
+        let input = (0..10)
+            .map(|a| format!("{{\"a\": {a}}}\n"))
+            .collect::<String>();
+        let batches = par_json_objects::<Object, _>(input.as_bytes(), 4)
+            .map(|batch| batch.unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(batches, [4, 4, 2]);
+        assert_eq!(par_json_objects::<Object, _>("[]".as_bytes(), 4).count(), 0);
+
+        // An object that does not deserialize fails its batch, and malformed
+        // JSON the batch it is read in. Either ends the iteration.
+        for invalid in ["{\"b\": 5}", "{\"a\": "] {
+            let input = (0..10)
+                .map(|a| match a {
+                    5 => format!("{invalid}\n"),
+                    a => format!("{{\"a\": {a}}}\n"),
+                })
+                .collect::<String>();
+            let batches = par_json_objects::<Object, _>(input.as_bytes(), 4)
+                .map(|batch| batch.map(|batch| batch.len()).ok())
+                .collect::<Vec<_>>();
+            assert_eq!(batches, [Some(4), None], "{invalid}");
+        }
+    }
+}
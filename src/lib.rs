@@ -1,10 +1,22 @@
 pub mod arch;
 mod bits;
+pub mod comparison;
 mod encoded_bits;
+mod neighbors;
+mod replicated;
+mod synthetic;
 mod template;
 
-pub use crate::{bits::Bits, encoded_bits::EncodedBits, template::Template};
-use core::slice;
+pub use crate::{
+    bits::Bits,
+    encoded_bits::{EncodedBits, Seed},
+    neighbors::{Neighbor, Neighbors},
+    replicated::Replicated,
+    synthetic::Synthetic,
+    template::Template,
+};
+use core::{iter, ops::Range, slice};
+use rand::{thread_rng, Rng};
 use rayon::prelude::*;
 
 pub const COLS: usize = 200;
@@ -25,55 +37,131 @@ pub fn encode(template: &Template) -> EncodedBits {
     mask - &pattern - &pattern
 }
 
+/// Inverse of [`encode`]. Returns `None` if any value is not in
+/// $\{-1,0,1\}$.
+///
+/// Note that pattern bits outside the mask are not encoded and decode as zero.
+pub fn decode(encoded: &EncodedBits) -> Option<Template> {
+    let mut template = Template::default();
+    for (i, &v) in encoded.0.iter().enumerate() {
+        match v {
+            0 => {}
+            1 => template.mask.set(i, true),
+            u16::MAX => {
+                template.mask.set(i, true);
+                template.pattern.set(i, true);
+            }
+            _ => return None,
+        }
+    }
+    Some(template)
+}
+
+/// Inverse of [`EncodedBits::from`] for masks. Returns `None` if any value is
+/// not in $\{0,1\}$.
+pub fn decode_mask(encoded: &EncodedBits) -> Option<Bits> {
+    let mut mask = Bits::default();
+    for (i, &v) in encoded.0.iter().enumerate() {
+        match v {
+            0 => {}
+            1 => mask.set(i, true),
+            _ => return None,
+        }
+    }
+    Some(mask)
+}
+
+/// Computes the dot products of all rotations of one or more queries with
+/// database entries.
+///
+/// With multiple queries all of them are evaluated on an entry while it is in
+/// cache, so the database is only read once.
 pub struct DistanceEngine {
-    rotations: Box<[EncodedBits; 31]>,
+    rotations: Box<[EncodedBits]>,
 }
 
 impl DistanceEngine {
     pub fn new(query: &EncodedBits) -> Self {
-        let rotations = (-15..=15)
-            .map(|r| query.rotated(r))
-            .collect::<Box<[EncodedBits]>>()
-            .try_into()
-            .unwrap();
+        Self::new_batch(slice::from_ref(query))
+    }
+
+    pub fn new_batch(queries: &[EncodedBits]) -> Self {
+        let rotations = queries
+            .iter()
+            .flat_map(|query| (-15..=15).map(|r| query.rotated(r)))
+            .collect();
         Self { rotations }
     }
 
+    /// Number of queries.
+    pub fn queries(&self) -> usize {
+        self.rotations.len() / 31
+    }
+
+    /// Results for entry `i` and query `q` are stored at `i * queries + q`.
     pub fn batch_process(&self, out: &mut [[u16; 31]], db: &[EncodedBits]) {
-        assert_eq!(out.len(), db.len());
-        out.par_iter_mut()
+        assert_eq!(out.len(), db.len() * self.queries());
+        out.par_chunks_exact_mut(self.queries())
             .zip(db.par_iter())
-            .for_each(|(result, entry)| {
-                // Compute dot product for each rotation
-                for (d, rotation) in result.iter_mut().zip(self.rotations.iter()) {
-                    *d = rotation.dot(entry);
-                }
-            });
+            .for_each(|(results, entry)| self.process(results, entry));
+    }
+
+    /// Like [`Self::batch_process`], for the entries in `range` of a share
+    /// expanded from `seed` with [`EncodedBits::expand`].
+    ///
+    /// Entries are expanded as they are processed, so the share is never
+    /// stored.
+    pub fn batch_process_seeded(&self, out: &mut [[u16; 31]], seed: &Seed, range: Range<usize>) {
+        assert_eq!(out.len(), range.len() * self.queries());
+        out.par_chunks_exact_mut(self.queries())
+            .zip(range.into_par_iter())
+            .for_each(|(results, index)| self.process(results, &EncodedBits::expand(seed, index)));
+    }
+
+    fn process(&self, results: &mut [[u16; 31]], entry: &EncodedBits) {
+        // Compute dot product for each query and rotation
+        for (result, rotations) in results.iter_mut().zip(self.rotations.chunks_exact(31)) {
+            for (d, rotation) in result.iter_mut().zip(rotations.iter()) {
+                *d = rotation.dot(entry);
+            }
+        }
     }
 }
 
+/// Like [`DistanceEngine`], but for masks.
 pub struct MasksEngine {
-    rotations: Box<[Bits; 31]>,
+    rotations: Box<[Bits]>,
 }
 
 impl MasksEngine {
     pub fn new(query: &Bits) -> Self {
-        let rotations = (-15..=15)
-            .map(|r| query.rotated(r))
-            .collect::<Box<[Bits]>>()
-            .try_into()
-            .unwrap();
+        Self::new_batch(slice::from_ref(query))
+    }
+
+    pub fn new_batch(queries: &[Bits]) -> Self {
+        let rotations = queries
+            .iter()
+            .flat_map(|query| (-15..=15).map(|r| query.rotated(r)))
+            .collect();
         Self { rotations }
     }
 
+    /// Number of queries.
+    pub fn queries(&self) -> usize {
+        self.rotations.len() / 31
+    }
+
+    /// Results for entry `i` and query `q` are stored at `i * queries + q`.
     pub fn batch_process(&self, out: &mut [[u16; 31]], db: &[Bits]) {
-        assert_eq!(out.len(), db.len());
-        out.par_iter_mut()
+        assert_eq!(out.len(), db.len() * self.queries());
+        out.par_chunks_exact_mut(self.queries())
             .zip(db.par_iter())
-            .for_each(|(result, entry)| {
-                // Compute dot product for each rotation
-                for (d, rotation) in result.iter_mut().zip(self.rotations.iter()) {
-                    *d = rotation.dot(entry);
+            .for_each(|(results, entry)| {
+                // Compute dot product for each query and rotation
+                for (result, rotations) in results.iter_mut().zip(self.rotations.chunks_exact(31)) {
+                    for (d, rotation) in result.iter_mut().zip(rotations.iter()) {
+                        *d = rotation.dot(entry);
+                    }
                 }
             });
     }
@@ -93,8 +181,36 @@ pub fn denominators(query: &Bits, entry: &Bits) -> [u16; 31] {
     result
 }
 
+/// Split dot products into `n` additive secret shares.
+pub fn share_distances(distances: &[u16; 31], n: usize) -> Box<[[u16; 31]]> {
+    assert!(n > 0);
+
+    // Create `n - 1` random shares.
+    let mut rng = thread_rng();
+    let mut result: Box<[[u16; 31]]> = iter::repeat_with(|| rng.gen::<[u16; 31]>())
+        .take(n - 1)
+        .chain(iter::once(*distances))
+        .collect();
+    let (last, rest) = result.split_last_mut().unwrap();
+
+    // Subtract the random shares from last
+    for share in rest.iter() {
+        for (l, &s) in last.iter_mut().zip(share.iter()) {
+            *l = l.wrapping_sub(s);
+        }
+    }
+
+    result
+}
+
 /// Decode a distances. Takes the minimum over the rotations
 pub fn decode_distance(distances: &[u16; 31], denominators: &[u16; 31]) -> f64 {
+    decode_rotation(distances, denominators).0
+}
+
+/// Like [`decode_distance`], but also returns the rotation in `-15..=15`
+/// achieving the minimum.
+pub fn decode_rotation(distances: &[u16; 31], denominators: &[u16; 31]) -> (f64, i32) {
     // TODO: Detect errors.
     // (d - n) must be an even number in range
 
@@ -103,7 +219,14 @@ pub fn decode_distance(distances: &[u16; 31], denominators: &[u16; 31]) -> f64 {
         .zip(denominators.iter())
         .map(|(&n, &d)| (d.wrapping_sub(n) / 2, d))
         .map(|(n, d)| (n as f64) / (d as f64))
-        .fold(f64::INFINITY, f64::min)
+        .zip(-15..=15)
+        .fold((f64::INFINITY, 0), |best, (distance, rotation)| {
+            if distance < best.0 {
+                (distance, rotation)
+            } else {
+                best
+            }
+        })
 }
 
 #[cfg(test)]
@@ -112,7 +235,6 @@ mod tests {
     use crate::{encode, template::tests::test_data};
     use float_eq::assert_float_eq;
     use proptest::bits::u16;
-    use rand::{thread_rng, Rng};
 
     #[test]
     fn test_preprocess() {
@@ -131,6 +253,98 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode() {
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let entry: Template = rng.gen();
+            let decoded = decode(&encode(&entry)).unwrap();
+            assert_eq!(decoded.mask, entry.mask);
+            assert_eq!(decoded.pattern, &entry.pattern & &entry.mask);
+        }
+        let mut invalid = encode(&rng.gen());
+        invalid.0[rng.gen_range(0..BITS)] = 2;
+        assert!(decode(&invalid).is_none());
+    }
+
+    #[test]
+    fn test_shared_masks() {
+        let mut rng = thread_rng();
+        for n in 1..5 {
+            let query: Template = rng.gen();
+            let entry: Template = rng.gen();
+            let mask_shares = EncodedBits::from(&entry.mask).share(n);
+
+            // Denominators are the sum of the dot products with the mask shares
+            let query_mask = EncodedBits::from(&query.mask);
+            let mut result = [0_u16; 31];
+            for share in mask_shares.iter() {
+                let partial = distances(&query_mask, share);
+                for (r, p) in result.iter_mut().zip(partial.iter()) {
+                    *r = r.wrapping_add(*p);
+                }
+            }
+            assert_eq!(result, denominators(&query.mask, &entry.mask));
+            assert_eq!(decode_mask(&mask_shares.iter().sum()), Some(entry.mask));
+        }
+        assert!(decode_mask(&encode(&rng.gen())).is_none());
+    }
+
+    #[test]
+    fn test_batch_engine() {
+        let mut rng = thread_rng();
+        let queries = (0..5).map(|_| rng.gen()).collect::<Vec<Template>>();
+        let db = (0..10).map(|_| rng.gen()).collect::<Vec<Template>>();
+        let encoded = queries.iter().map(encode).collect::<Vec<_>>();
+        let masks = queries.iter().map(|q| q.mask).collect::<Vec<_>>();
+        let engine = DistanceEngine::new_batch(&encoded);
+        let masks_engine = MasksEngine::new_batch(&masks);
+        let mut out = vec![[0_u16; 31]; db.len() * queries.len()];
+        let mut masks_out = vec![[0_u16; 31]; db.len() * queries.len()];
+        engine.batch_process(&mut out, &db.iter().map(encode).collect::<Vec<_>>());
+        masks_engine.batch_process(
+            &mut masks_out,
+            &db.iter().map(|e| e.mask).collect::<Vec<_>>(),
+        );
+        for (i, entry) in db.iter().enumerate() {
+            for (q, query) in queries.iter().enumerate() {
+                let index = i * queries.len() + q;
+                assert_eq!(out[index], distances(&encode(query), &encode(entry)));
+                assert_eq!(masks_out[index], denominators(&query.mask, &entry.mask));
+            }
+        }
+    }
+
+    #[test]
+    fn test_seeded_engine() {
+        let mut rng = thread_rng();
+        let queries = (0..3).map(|_| encode(&rng.gen())).collect::<Vec<_>>();
+        let seed: Seed = rng.gen();
+        let range = 5..12;
+        let db = range
+            .clone()
+            .map(|index| EncodedBits::expand(&seed, index))
+            .collect::<Vec<_>>();
+        let engine = DistanceEngine::new_batch(&queries);
+        let mut expected = vec![[0_u16; 31]; db.len() * queries.len()];
+        let mut out = vec![[0_u16; 31]; db.len() * queries.len()];
+        engine.batch_process(&mut expected, &db);
+        engine.batch_process_seeded(&mut out, &seed, range);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_decode_rotation() {
+        let mut rng = thread_rng();
+        let entry: Template = rng.gen();
+        for rotation in -15..=15 {
+            let query = entry.rotated(-rotation);
+            let distances = distances(&encode(&query), &encode(&entry));
+            let denominators = denominators(&query.mask, &entry.mask);
+            assert_eq!(decode_rotation(&distances, &denominators), (0.0, rotation));
+        }
+    }
+
     #[test]
     fn test_dotproduct() {
         let mut rng = thread_rng();
@@ -162,6 +376,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_shared_query() {
+        let mut rng = thread_rng();
+        for n in 1..5 {
+            let query = encode(&rng.gen());
+            let entry = encode(&rng.gen());
+            let entry_shares = entry.share(n);
+
+            // Dealer preprocessing
+            let mask: EncodedBits = rng.gen();
+            let mask_shares = mask.share(n);
+            let product_shares = share_distances(&distances(&mask, &entry), n);
+
+            // Open the masked query
+            let masked = query
+                .share(n)
+                .iter()
+                .zip(mask_shares.iter())
+                .map(|(q, m)| *q - m)
+                .collect::<Vec<_>>();
+            let masked = masked.iter().sum::<EncodedBits>();
+
+            // Each party computes a share of the result
+            let mut result = [0_u16; 31];
+            for (share, product) in entry_shares.iter().zip(product_shares.iter()) {
+                let partial = distances(&masked, share);
+                for ((r, p), c) in result.iter_mut().zip(partial.iter()).zip(product.iter()) {
+                    *r = r.wrapping_add(*p).wrapping_add(*c);
+                }
+            }
+            assert_eq!(result, distances(&query, &entry));
+        }
+    }
+
     #[test]
     fn test_encrypted_distances() {
         let (data, dist) = test_data();
@@ -170,7 +418,7 @@ mod tests {
             let query = &data[d.left];
             let entry = &data[d.right];
 
-            let encrypted = encode(&entry);
+            let encrypted = encode(entry);
             for (i, v) in encrypted.0.iter().enumerate() {
                 match *v {
                     u16::MAX => assert!(entry.mask[i] && entry.pattern[i]),
@@ -181,7 +429,7 @@ mod tests {
             }
 
             // Encode entry
-            let preprocessed = encode(&query);
+            let preprocessed = encode(query);
             let distances = distances(&preprocessed, &encrypted);
             let denominators = denominators(&query.mask, &entry.mask);
 
@@ -196,9 +444,28 @@ mod tests {
 #[cfg(feature = "bench")]
 pub mod benches {
     use super::*;
-    use criterion::Criterion;
+    use criterion::{BenchmarkId, Criterion, Throughput};
 
     pub fn group(c: &mut Criterion) {
         arch::benches::group(c);
+        bench_distance_engine(c);
+    }
+
+    fn bench_distance_engine(criterion: &mut Criterion) {
+        let mut rng = thread_rng();
+        let mut group = criterion.benchmark_group("distance_engine");
+        group.sample_size(10);
+        let db = (0..1000).map(|_| rng.gen()).collect::<Vec<EncodedBits>>();
+        for queries in [1, 4, 16] {
+            let query = (0..queries)
+                .map(|_| rng.gen())
+                .collect::<Vec<EncodedBits>>();
+            let engine = DistanceEngine::new_batch(&query);
+            let mut out = vec![[0_u16; 31]; db.len() * queries];
+            group.throughput(Throughput::Elements((queries * db.len()) as u64));
+            group.bench_function(BenchmarkId::from_parameter(queries), |bencher| {
+                bencher.iter(|| engine.batch_process(&mut out, &db));
+            });
+        }
     }
 }
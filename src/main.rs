@@ -1,43 +1,80 @@
+mod api;
+mod coalesce;
+mod enroll;
+mod format;
+mod handshake;
+mod ids;
 mod json_stream;
-
-use crate::json_stream::iter_json_array;
-use anyhow::{format_err, Context, Ok, Result};
-use bytemuck::{bytes_of, bytes_of_mut, cast_slice, cast_slice_mut, try_cast_slice};
-use clap::{Args, Parser, Subcommand};
+mod merkle;
+mod participant;
+mod protocol;
+mod resolver;
+mod secure_threshold;
+mod shutdown;
+mod slot;
+mod templates;
+mod tombstones;
+mod triples;
+
+use crate::{
+    coalesce::Coalescer,
+    format::{DataFile, Dataset, Header, Kind, Records, Writer},
+    handshake::Announcement,
+    ids::{Entry, IdWriter},
+    json_stream::{iter_json_objects, par_json_objects},
+    merkle::Manifest,
+    participant::Participant,
+    protocol::{Message, Mux, ResultStream},
+    resolver::Resolver,
+    secure_threshold::Comparisons,
+    shutdown::Signals,
+    templates::TemplateFile,
+    tombstones::Tombstones,
+    triples::Triples,
+};
+use anyhow::{bail, format_err, Context, Ok, Result};
+use bytemuck::{bytes_of, cast_slice};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use clap_num::si_number;
 use futures::future::try_join_all;
 use indicatif::{HumanBytes, HumanCount, ProgressBar, ProgressStyle};
 use itertools::Itertools;
-use memmap::MmapOptions;
 use mpc_iris_code::{
-    decode_distance, encode, Bits, DistanceEngine, EncodedBits, MasksEngine, Template,
+    comparison, decode, decode_mask, encode, Bits, EncodedBits, Replicated, Seed, Synthetic,
+    Template,
 };
-use rand::{thread_rng, Rng};
+use rand::{thread_rng, Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use rayon::{
     current_num_threads,
     iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator as _},
     prelude::*,
     ThreadPoolBuilder,
 };
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::json;
 use shadow_rs::shadow;
 use std::{
     cmp::min,
-    mem::{size_of, swap},
+    collections::BTreeMap,
+    io::{BufRead, Write as _},
+    mem::size_of,
     net::SocketAddr,
     os::unix::fs::MetadataExt,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
     thread::available_parallelism,
+    time::Duration,
 };
 use target_features::CURRENT_TARGET;
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    join,
-    net::{TcpListener, TcpStream},
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    net::TcpListener,
     sync::mpsc,
 };
 
@@ -59,16 +96,29 @@ struct Cli {
 
 #[derive(Debug, Subcommand)]
 enum Commands {
-    /// Generate random test data in json
+    /// Generate random test data in json or binary
     #[command(arg_required_else_help = true)]
     Generate(GenerateArgs),
 
-    /// Prepare secret shares from json input
+    /// Convert json templates to the binary format
+    #[command(arg_required_else_help = true)]
+    Convert(ConvertArgs),
+
+    /// Prepare secret shares from json or binary input
     #[command(arg_required_else_help = true)]
     Prepare(PrepareArgs),
 
     /// Combine secret shares back to json
-    Decrypt,
+    #[command(arg_required_else_help = true)]
+    Decrypt(DecryptArgs),
+
+    /// Generate preprocessed material for secret-shared queries
+    ///
+    /// The dealer combines the shares of all parties, so it sees the
+    /// plaintext templates. Run it only where they may be seen, and hand
+    /// each party only its own output.
+    #[command(arg_required_else_help = true)]
+    Preprocess(PreprocessArgs),
 
     /// Start participant
     #[command(arg_required_else_help = true)]
@@ -82,6 +132,26 @@ enum Commands {
     #[command(arg_required_else_help = true)]
     Coordinator(ResolverArgs),
 
+    /// Submit queries from json input to a running resolver
+    #[command(arg_required_else_help = true)]
+    Query(QueryArgs),
+
+    /// Enroll templates from json input through a running resolver
+    #[command(arg_required_else_help = true)]
+    Enroll(EnrollArgs),
+
+    /// Delete entries through a running resolver
+    #[command(arg_required_else_help = true)]
+    Delete(DeleteArgs),
+
+    /// Remove deleted entries from data files while services are stopped
+    #[command(arg_required_else_help = true)]
+    Compact(CompactArgs),
+
+    /// Check data files against their Merkle trees, listing corrupted blocks
+    #[command(arg_required_else_help = true)]
+    Verify(VerifyArgs),
+
     /// Benchmark a participant
     #[command(arg_required_else_help = true)]
     Benchmark(BenchmarkArgs),
@@ -89,13 +159,109 @@ enum Commands {
 
 #[derive(Debug, Args)]
 struct GenerateArgs {
-    /// Output JSON file
+    /// Output file, or `-` for standard output
     path: PathBuf,
 
-    /// Number of entries to generate.
+    /// Number of entries to generate, or of identities with --captures.
     #[arg(default_value = "1M", value_parser=si_number::<usize>)]
     count: usize,
 
+    /// Format of the output file.
+    #[arg(long, value_enum, default_value_t = TemplateFormat::Json)]
+    format: TemplateFormat,
+
+    #[command(flatten)]
+    synthetic: SyntheticArgs,
+
+    /// Seed for reproducible output, which is the same for any number of
+    /// threads. Defaults to a random seed, which is printed.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Allow overwriting existing file
+    #[arg(long, default_value_t = false)]
+    replace: bool,
+}
+
+#[derive(Debug, Args)]
+struct SyntheticArgs {
+    /// Generate this many realistic captures of every identity instead of
+    /// uniformly random templates, consecutively.
+    #[arg(long)]
+    captures: Option<usize>,
+
+    /// Fraction of bits flipped in a capture.
+    #[arg(long, default_value_t = Synthetic::default().flip_rate)]
+    flip_rate: f64,
+
+    /// Correlation of flips with the previous bit in the row.
+    #[arg(long, default_value_t = Synthetic::default().flip_correlation)]
+    flip_correlation: f64,
+
+    /// Largest rotation between two captures in columns.
+    #[arg(long, default_value_t = Synthetic::default().max_rotation)]
+    max_rotation: i32,
+
+    /// Average fraction of bits masked by eyelids.
+    #[arg(long, default_value_t = Synthetic::default().occlusion)]
+    occlusion: f64,
+
+    /// File to write the genuine pairs of captures to as JSON. Defaults to the
+    /// output file with `.truth.json` appended.
+    #[arg(long)]
+    truth: Option<PathBuf>,
+}
+
+impl SyntheticArgs {
+    fn synthetic(&self) -> Result<Synthetic> {
+        if !(0.0..=1.0).contains(&self.flip_rate) {
+            bail!("Flip rate must be between 0 and 1.");
+        }
+        if !(0.0..1.0).contains(&self.flip_correlation) {
+            bail!("Flip correlation must be at least 0 and below 1.");
+        }
+        if !(0..=30).contains(&self.max_rotation) {
+            bail!("Rotation must be between 0 and 30 columns.");
+        }
+        if !(0.0..=0.2).contains(&self.occlusion) {
+            bail!("Occlusion must be between 0 and 0.2.");
+        }
+        Ok(Synthetic {
+            flip_rate:        self.flip_rate,
+            flip_correlation: self.flip_correlation,
+            max_rotation:     self.max_rotation,
+            occlusion:        self.occlusion,
+        })
+    }
+}
+
+/// A pair of captures of the same identity, by index in the generated file.
+#[derive(Debug, Serialize)]
+struct GenuinePair {
+    left:     usize,
+    right:    usize,
+    identity: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum TemplateFormat {
+    /// A JSON array of templates.
+    Json,
+    /// Newline-delimited JSON, one template per line.
+    Ndjson,
+    /// Packed templates after a header, which `prepare` reads without parsing.
+    Binary,
+}
+
+#[derive(Debug, Args)]
+struct ConvertArgs {
+    /// Input file of templates as a JSON array or newline-delimited JSON
+    input: PathBuf,
+
+    /// Output binary file. Identifiers are written next to it with an `.ids`
+    /// extension appended.
+    output: PathBuf,
+
     /// Allow overwriting existing file
     #[arg(long, default_value_t = false)]
     replace: bool,
@@ -103,7 +269,8 @@ struct GenerateArgs {
 
 #[derive(Debug, Args)]
 struct PrepareArgs {
-    /// Input JSON file
+    /// Input file of templates as a JSON array, newline-delimited JSON or
+    /// binary, or `-` for JSON from standard input
     input: PathBuf,
 
     /// Number of shares to generate.
@@ -113,6 +280,92 @@ struct PrepareArgs {
     /// Base file name for output.
     #[arg(default_value = "mpc")]
     output: PathBuf,
+
+    /// Number of shares required to reconstruct, at least 2. Defaults to all
+    /// shares.
+    #[arg(long)]
+    threshold: Option<usize>,
+
+    /// Secret-share the masks instead of writing them in plaintext.
+    #[arg(long, default_value_t = false)]
+    share_masks: bool,
+
+    /// Store only a seed for all shares but the last, from which participants
+    /// expand them on the fly. Requires sharing without threshold, and the
+    /// entries can then not be enrolled into or compacted.
+    #[arg(long, default_value_t = false)]
+    seeded: bool,
+}
+
+#[derive(Debug, Args)]
+struct DecryptArgs {
+    /// Output JSON file
+    output: PathBuf,
+
+    /// Base file name for input.
+    #[arg(default_value = "mpc")]
+    input: PathBuf,
+
+    /// Number of shares to combine.
+    #[arg(default_value = "3")]
+    count: usize,
+
+    /// Number of shares required to reconstruct. Defaults to all shares.
+    #[arg(long)]
+    threshold: Option<usize>,
+
+    /// Reconstruct the masks from mask shares.
+    #[arg(long, default_value_t = false)]
+    shared_masks: bool,
+
+    /// The resolver's file recording deleted entries, which are left out.
+    /// Defaults to the input file name with extension `.tombstones`.
+    #[arg(long)]
+    tombstones: Option<PathBuf>,
+
+    /// Allow overwriting existing file
+    #[arg(long, default_value_t = false)]
+    replace: bool,
+
+    /// Read files written without a header by earlier versions.
+    #[arg(long, default_value_t = false)]
+    legacy: bool,
+}
+
+#[derive(Debug, Args)]
+struct PreprocessArgs {
+    /// Base file name for input and output.
+    #[arg(default_value = "mpc")]
+    input: PathBuf,
+
+    /// Number of shares.
+    #[arg(default_value = "3")]
+    count: usize,
+
+    /// Number of queries to generate material for.
+    #[arg(long, default_value = "10")]
+    slots: usize,
+
+    /// Kind of material to generate.
+    #[arg(long, value_enum, default_value_t = MaterialKind::Triples)]
+    material: MaterialKind,
+
+    /// Read mask shares instead of plaintext masks. Comparisons are then
+    /// for participants holding mask shares.
+    #[arg(long, default_value_t = false)]
+    shared_masks: bool,
+
+    /// Read files written without a header by earlier versions.
+    #[arg(long, default_value_t = false)]
+    legacy: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum MaterialKind {
+    /// Multiplication triples for secret-shared queries.
+    Triples,
+    /// Material for secure comparison against a threshold.
+    Comparisons,
 }
 
 #[derive(Debug, Args)]
@@ -123,6 +376,50 @@ struct ParticipantArgs {
     /// Socket to listen on
     #[arg(default_value = "127.0.0.1:1234")]
     bind: SocketAddr,
+
+    /// Triples file. When set only secret-shared queries are accepted.
+    #[arg(long)]
+    triples: Option<PathBuf>,
+
+    /// Comparisons file. When set only match bits are revealed.
+    #[arg(long)]
+    comparisons: Option<PathBuf>,
+
+    /// Mask share file. When set denominators are computed in MPC.
+    #[arg(long)]
+    masks: Option<PathBuf>,
+
+    /// File recording deleted entries. Defaults to the input file name with
+    /// `.tombstones` appended.
+    #[arg(long)]
+    tombstones: Option<PathBuf>,
+
+    /// Refuse to start unless the share is for this party.
+    #[arg(long)]
+    party: Option<usize>,
+
+    /// Refuse to start unless the share is from this dataset.
+    #[arg(long, value_parser = format::parse_dataset)]
+    dataset: Option<Dataset>,
+
+    /// Read files written without a header by earlier versions.
+    #[arg(long, default_value_t = false)]
+    legacy: bool,
+
+    /// Maximum number of scans evaluated at once, which share the compute
+    /// thread pool.
+    #[arg(long, default_value = "4")]
+    max_scans: usize,
+
+    /// Maximum number of scans waiting for others to finish. Further scans
+    /// are rejected as long as the queue is full.
+    #[arg(long, default_value = "16")]
+    max_queued: usize,
+
+    /// Milliseconds requests in flight are given to finish on SIGINT or
+    /// SIGTERM, before they are cancelled.
+    #[arg(long, default_value = "30000")]
+    shutdown_grace: u64,
 }
 
 #[derive(Debug, Args)]
@@ -131,6 +428,11 @@ struct ResolverArgs {
     #[arg(long, default_value = "mpc.masks")]
     masks: PathBuf,
 
+    /// Compute denominators from the participants' mask shares instead of
+    /// the masks file.
+    #[arg(long, default_value_t = false)]
+    shared_masks: bool,
+
     /// Optional share file if the resolver is also a participant
     #[arg(long)]
     share: Option<PathBuf>,
@@ -139,16 +441,163 @@ struct ResolverArgs {
     #[arg(long, default_value = "127.0.0.1:8080")]
     bind: SocketAddr,
 
+    /// Secret-share queries using preprocessed triples. Participants must
+    /// be given in party order.
+    #[arg(long, default_value_t = false)]
+    shared_query: bool,
+
+    /// First slot of preprocessed material to use for secret-shared queries
+    /// and secure comparison. Slots used by earlier runs, as recorded in
+    /// --next-slot, are skipped anyway.
+    #[arg(long, default_value = "0")]
+    first_slot: usize,
+
+    /// File recording the next unused slot of preprocessed material, so
+    /// slots are not reused after a restart.
+    #[arg(long, default_value = "mpc.next-slot")]
+    next_slot: PathBuf,
+
+    /// Number of participants required to answer a query. Must match the
+    /// threshold used to prepare the shares. Defaults to all participants.
+    #[arg(long)]
+    threshold: Option<usize>,
+
+    /// Only learn which entries are within this distance, using secure
+    /// comparison with preprocessed material. Must be between 0 and 1.
+    #[arg(long)]
+    match_threshold: Option<f64>,
+
+    /// File recording deleted entries, which are excluded from results.
+    #[arg(long, default_value = "mpc.tombstones")]
+    tombstones: PathBuf,
+
+    /// Identifiers of entries, reported in results.
+    #[arg(long, default_value = "mpc.ids")]
+    ids: PathBuf,
+
+    /// Milliseconds to wait for further queries to evaluate in the same scan.
+    #[arg(long, default_value = "0")]
+    coalesce_window: u64,
+
+    /// Maximum number of queries to evaluate in the same scan.
+    #[arg(long, default_value = "64")]
+    max_batch: usize,
+
+    /// Milliseconds from submission after which a query is abandoned, unless
+    /// it sets `timeout_ms`. 0 for no deadline.
+    #[arg(long, default_value = "0")]
+    query_timeout: u64,
+
+    /// Milliseconds requests in flight are given to finish on SIGINT or
+    /// SIGTERM, before queries are cancelled.
+    #[arg(long, default_value = "30000")]
+    shutdown_grace: u64,
+
+    /// Merkle roots of the data the participants must serve, as published by
+    /// `prepare`. Updated on enrollment, but must be republished after
+    /// compaction.
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Read a masks file written without a header by earlier versions.
+    #[arg(long, default_value_t = false)]
+    legacy: bool,
+
     /// Participant addresses
     participants: Vec<SocketAddr>,
 }
 
+#[derive(Debug, Args)]
+struct QueryArgs {
+    /// Input JSON file with a template or an array of templates
+    input: PathBuf,
+
+    /// Resolver API address
+    #[arg(long, default_value = "http://127.0.0.1:8080")]
+    resolver: Url,
+
+    /// Number of queries to submit per request, evaluated in a single scan.
+    #[arg(long, default_value = "1")]
+    batch: usize,
+
+    /// Return this many closest entries instead of only the closest.
+    #[arg(long)]
+    top_k: Option<usize>,
+
+    /// Return all entries within this distance, up to the top-k if given.
+    #[arg(long)]
+    threshold: Option<f64>,
+}
+
+#[derive(Debug, Args)]
+struct EnrollArgs {
+    /// Input JSON file with a template or an array of templates
+    input: PathBuf,
+
+    /// Resolver API address
+    #[arg(long, default_value = "http://127.0.0.1:8080")]
+    resolver: Url,
+
+    /// Number of templates to submit per request.
+    #[arg(long, default_value = "1000")]
+    batch: usize,
+}
+
+#[derive(Debug, Args)]
+struct DeleteArgs {
+    /// Indices of the entries to delete
+    #[arg(required = true)]
+    entries: Vec<String>,
+
+    /// Address entries by identifier instead of index.
+    #[arg(long, default_value_t = false)]
+    id: bool,
+
+    /// Resolver API address
+    #[arg(long, default_value = "http://127.0.0.1:8080")]
+    resolver: Url,
+}
+
+#[derive(Debug, Args)]
+struct CompactArgs {
+    /// Tombstone file listing the deleted entries
+    tombstones: PathBuf,
+
+    /// Data files the tombstones apply to, e.g. a share and its mask share.
+    files: Vec<PathBuf>,
+
+    /// Identifier index to remove the deleted entries from.
+    #[arg(long)]
+    ids: Option<PathBuf>,
+
+    /// Read files written without a header by earlier versions.
+    #[arg(long, default_value_t = false)]
+    legacy: bool,
+}
+
+#[derive(Debug, Args)]
+struct VerifyArgs {
+    /// Masks, share or mask share files
+    files: Vec<PathBuf>,
+}
+
 #[derive(Debug, Args)]
 struct BenchmarkArgs {
     /// Participant address
     participant: SocketAddr,
+
+    /// Number of queries in flight on the connection.
+    #[arg(long, default_value = "1")]
+    concurrent: usize,
 }
 
+/// Maximum number of queries a participant evaluates in a single scan.
+const MAX_QUERIES: usize = 256;
+
+/// Maximum number of records a participant appends in a single enrollment,
+/// and of entries deleted in a single deletion.
+const MAX_ENROLL: usize = 1 << 16;
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let args = Cli::parse();
@@ -181,67 +630,135 @@ async fn main() -> Result<(), anyhow::Error> {
     let count_style = ProgressStyle::with_template(
         "{wide_bar} {human_pos}/{human_len} {per_sec} {elapsed}/{duration} ",
     )?;
+    let stream_style =
+        ProgressStyle::with_template("{spinner} {bytes} {bytes_per_sec} {elapsed} ")?;
 
     match args.command {
         Commands::Generate(args) => {
-            let file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .create_new(!args.replace)
-                .open(args.path.clone())
-                .await
-                .with_context(|| format!("Failed to create file at {:?}", args.path))?;
+            // The binary header is written last, which needs a file to seek in.
+            let format = args.format;
+            let stdout = args.path == Path::new("-");
+            if stdout && format == TemplateFormat::Binary {
+                bail!("Binary templates can not be written to standard output.");
+            }
 
-            let size = HumanBytes(4 + args.count as u64 * 6434);
-            eprintln!(
-                "Writing test templates to {:?} (estimated size {size})",
-                args.path
-            );
+            // Synthetic identities are written as consecutive captures.
+            let synthetic = args.synthetic.synthetic()?;
+            let captures = args.synthetic.captures.unwrap_or(1);
+            let truth = match (args.synthetic.captures, &args.synthetic.truth) {
+                (None, _) => None,
+                (Some(_), Some(path)) => Some(path.clone()),
+                (Some(_), None) if stdout => {
+                    bail!("Synthetic captures written to standard output require --truth.")
+                }
+                (Some(_), None) => {
+                    let mut path = args.path.clone().into_os_string();
+                    path.push(".truth.json");
+                    Some(PathBuf::from(path))
+                }
+            };
+            let total = args.count * captures;
+
+            let file: Box<dyn AsyncWrite + Unpin + Send> = if stdout {
+                Box::new(tokio::io::stdout())
+            } else {
+                Box::new(
+                    OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .create_new(!args.replace)
+                        .open(args.path.clone())
+                        .await
+                        .with_context(|| format!("Failed to create file at {:?}", args.path))?,
+                )
+            };
+
+            let size = HumanBytes(match format {
+                TemplateFormat::Json => 4 + total as u64 * 6434,
+                TemplateFormat::Ndjson => total as u64 * 6424,
+                TemplateFormat::Binary => {
+                    (templates::HEADER_SIZE + total * size_of::<Template>()) as u64
+                }
+            });
+            if stdout {
+                eprintln!("Writing test templates to standard output (estimated size {size})");
+            } else {
+                eprintln!(
+                    "Writing test templates to {:?} (estimated size {size})",
+                    args.path
+                );
+            }
             let progress = ProgressBar::new(size.0).with_style(byte_style);
 
             // Initialize the file
             let mut buffer = BufWriter::new(file);
-            buffer.write_all(b"[").await?;
-            if args.count > 0 {
-                // First one without leading comma.
-                let mut rng = thread_rng();
-                let mut buf = Vec::with_capacity(6500);
-                serde_json::to_writer_pretty(&mut buf, &rng.gen::<Template>())?;
-                buffer.write_all(&buf).await?;
-                progress.inc(buf.len() as u64 + 1);
+            let mut header = None;
+            if format == TemplateFormat::Binary {
+                // Placeholder until all templates are written.
+                buffer.write_all(&[0; templates::HEADER_SIZE]).await?;
+                progress.inc(templates::HEADER_SIZE as u64);
+                header = Some(templates::Header::new());
+            } else if format == TemplateFormat::Json {
+                buffer.write_all(b"[").await?;
+                progress.inc(1);
             }
 
+            // Every batch draws from its own stream of the seeded generator, so
+            // the output does not depend on which thread computes it.
+            let seed = args.seed.unwrap_or_else(|| thread_rng().gen());
+            eprintln!("Generating with seed {seed}");
+
             // Create a channel and feed it with parallel producers.
             const BATCH_SIZE: usize = 100;
             let channel_capacity = 2 * available_parallelism()?.get();
             let (sender, mut receiver) = mpsc::channel(channel_capacity);
             let producer_task = tokio::task::spawn_blocking(move || {
-                let remaining = AtomicUsize::new(args.count.saturating_sub(1));
-                let remaining_ref = &remaining;
+                let next_batch = AtomicUsize::new(0);
+                let next_batch_ref = &next_batch;
                 rayon::scope(|scope| {
                     scope.spawn_broadcast(|_scope, _context| {
-                        let mut rng = thread_rng();
                         loop {
-                            // Atomically compute batch size based on remaining elements
-                            let remaining = remaining_ref
-                                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| {
-                                    Some(remaining.saturating_sub(BATCH_SIZE))
-                                })
-                                .unwrap_or(0);
-                            let batch_size = min(remaining, BATCH_SIZE);
-                            if batch_size == 0 {
+                            // Atomically claim the next batch
+                            let batch = next_batch_ref.fetch_add(1, Ordering::SeqCst);
+                            let start = batch * BATCH_SIZE;
+                            if start >= args.count {
                                 break;
                             }
-
-                            // Compute a batch of random templates in JSON
-                            let mut buf = Vec::with_capacity(6434 * batch_size);
-                            for _ in 0..batch_size {
-                                buf.push(b',');
-                                serde_json::to_writer_pretty(&mut buf, &rng.gen::<Template>())
-                                    .expect("Should serialize.");
+                            let end = min(start + BATCH_SIZE, args.count);
+                            let mut rng = ChaCha20Rng::seed_from_u64(seed);
+                            rng.set_stream(batch as u64);
+
+                            // Compute a batch of random templates in the output format
+                            let mut buf = Vec::with_capacity(6434 * (end - start) * captures);
+                            let templates = (start..end).flat_map(|_| {
+                                if args.synthetic.captures.is_some() {
+                                    synthetic.identity(captures, &mut rng)
+                                } else {
+                                    vec![rng.gen::<Template>()]
+                                }
+                            });
+                            for template in templates.collect::<Vec<_>>() {
+                                match format {
+                                    TemplateFormat::Json => {
+                                        // All but the first with leading comma.
+                                        if start > 0 || !buf.is_empty() {
+                                            buf.push(b',');
+                                        }
+                                        serde_json::to_writer_pretty(&mut buf, &template)
+                                            .expect("Should serialize.");
+                                    }
+                                    TemplateFormat::Ndjson => {
+                                        serde_json::to_writer(&mut buf, &template)
+                                            .expect("Should serialize.");
+                                        buf.push(b'\n');
+                                    }
+                                    TemplateFormat::Binary => {
+                                        buf.extend_from_slice(bytes_of(&template))
+                                    }
+                                }
                             }
-                            sender.blocking_send(buf).expect("Channel failed.");
+                            sender.blocking_send((batch, buf)).expect("Channel failed.");
                         }
                     });
                 });
@@ -249,10 +766,19 @@ async fn main() -> Result<(), anyhow::Error> {
                 Ok(())
             });
 
-            // Sink the channel to file.
-            while let Some(buf) = receiver.recv().await {
-                buffer.write_all(&buf).await?;
-                progress.inc(buf.len() as u64);
+            // Sink the channel to file, restoring the order of the batches.
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            while let Some((batch, buf)) = receiver.recv().await {
+                pending.insert(batch, buf);
+                while let Some(buf) = pending.remove(&next) {
+                    buffer.write_all(&buf).await?;
+                    if let Some(header) = &mut header {
+                        header.append(&buf);
+                    }
+                    progress.inc(buf.len() as u64);
+                    next += 1;
+                }
             }
             progress.finish(); // TODO: Abandon on error
 
@@ -260,27 +786,153 @@ async fn main() -> Result<(), anyhow::Error> {
             producer_task.await??;
 
             // Finalize the file
-            buffer.write_all(b"]\n").await?;
+            if format == TemplateFormat::Json {
+                buffer.write_all(b"]\n").await?;
+            }
             buffer.flush().await?;
+            drop(buffer);
+            if let Some(header) = header {
+                let mut file = OpenOptions::new().write(true).open(&args.path).await?;
+                templates::write_header(&mut file, &header).await?;
+            }
+
+            // Every pair of captures of an identity is genuine, all others are
+            // impostors.
+            if let Some(path) = truth {
+                let pairs = (0..args.count)
+                    .flat_map(|identity| {
+                        let first = identity * captures;
+                        (first..first + captures)
+                            .tuple_combinations()
+                            .map(move |(left, right)| GenuinePair {
+                                left,
+                                right,
+                                identity,
+                            })
+                    })
+                    .collect::<Vec<_>>();
+                let file = std::fs::File::create(&path)
+                    .with_context(|| format!("Failed to create file at {path:?}"))?;
+                let mut output = std::io::BufWriter::new(file);
+                serde_json::to_writer(&mut output, &pairs)?;
+                output.write_all(b"\n")?;
+                output.flush()?;
+                eprintln!(
+                    "Wrote {} genuine pairs to {path:?}",
+                    HumanCount(pairs.len() as u64)
+                );
+            }
 
             Ok(())
         }
-        Commands::Prepare(args) => {
-            // Open input file (synchronous IO for Serde)
-            let file = std::fs::File::open(&args.input)
+        Commands::Convert(args) => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .create_new(!args.replace)
+                .open(&args.output)
+                .await
+                .with_context(|| format!("Failed to create file at {:?}", args.output))?;
+            let input = std::fs::File::open(&args.input)
                 .with_context(|| format!("Failed to open file at {:?}", args.input))?;
-            let size = HumanBytes(file.metadata()?.size());
-            let count = HumanCount(size.0 / 6434);
+            let size = input.metadata()?.size();
             eprintln!(
-                "Input file {:?} ({size}, estimated {count} templates)",
-                args.input
+                "Converting {:?} ({}) to {:?}",
+                args.input,
+                HumanBytes(size),
+                args.output
             );
-            let total_size = HumanBytes(
-                count.0 * (size_of::<Bits>() as u64)
-                    + count.0 * (args.count as u64) * (size_of::<EncodedBits>() as u64),
+            let progress = ProgressBar::new(size / 6434).with_style(count_style);
+
+            // Parse on a blocking thread, writing identifiers to a sidecar.
+            let mut ids = IdWriter::create(&templates::ids_path(&args.output))?;
+            let (sender, mut receiver) = mpsc::channel(4);
+            let reader_task = tokio::task::spawn_blocking(move || {
+                let input = std::io::BufReader::new(input);
+                for batch in par_json_objects::<Entry, _>(input, 1000) {
+                    let batch = batch?;
+                    for entry in &batch {
+                        ids.push(entry.id.as_deref())?;
+                    }
+                    sender.blocking_send(batch.iter().map(Entry::template).collect::<Vec<_>>())?;
+                }
+                ids.finish()
+            });
+
+            let mut buffer = BufWriter::new(file);
+            buffer.write_all(&[0; templates::HEADER_SIZE]).await?;
+            let mut header = templates::Header::new();
+            while let Some(batch) = receiver.recv().await {
+                buffer.write_all(cast_slice(&batch)).await?;
+                header.append(cast_slice(&batch));
+                progress.inc(batch.len() as u64);
+            }
+            // Only write the header once the input was read completely.
+            let ids = reader_task.await??;
+            buffer.flush().await?;
+            templates::write_header(buffer.get_mut(), &header).await?;
+            progress.finish();
+            eprintln!(
+                "Converted {} templates, {} with identifiers.",
+                HumanCount(header.count() as u64),
+                HumanCount(ids as u64)
             );
-            eprintln!("Estimated total size of shares: {total_size}",);
-            let input = std::io::BufReader::new(file);
+            Ok(())
+        }
+        Commands::Prepare(args) => {
+            let scheme = Replicated::new(args.count, args.threshold.unwrap_or(args.count))?;
+            eprintln!(
+                "Sharing with threshold {} out of {} ({} records per share)",
+                scheme.threshold(),
+                scheme.parties(),
+                scheme.records()
+            );
+            if args.seeded && scheme.threshold() != scheme.parties() {
+                bail!("Seeded shares require sharing without threshold.");
+            }
+
+            // Open input file, memory mapping binary templates and otherwise
+            // parsing a JSON array or newline-delimited JSON (synchronous IO for
+            // Serde). Standard input is read with `-`, but can not be binary.
+            let (input, binary, count): (Box<dyn BufRead + Send>, _, _) =
+                if args.input == Path::new("-") {
+                    eprintln!("Reading JSON templates from standard input");
+                    (
+                        Box::new(std::io::BufReader::new(std::io::stdin())),
+                        None,
+                        None,
+                    )
+                } else {
+                    let file = std::fs::File::open(&args.input)
+                        .with_context(|| format!("Failed to open file at {:?}", args.input))?;
+                    let size = HumanBytes(file.metadata()?.size());
+                    if templates::is_binary(&args.input)? {
+                        let binary = TemplateFile::open(&args.input)?;
+                        let count = HumanCount(binary.templates().len() as u64);
+                        eprintln!("Input file {:?} ({size}, {count} templates)", args.input);
+                        (Box::new(std::io::empty()), Some(binary), Some(count))
+                    } else {
+                        let count = HumanCount(size.0 / 6434);
+                        eprintln!(
+                            "Input file {:?} ({size}, estimated {count} templates)",
+                            args.input
+                        );
+                        (Box::new(std::io::BufReader::new(file)), None, Some(count))
+                    }
+                };
+            let stored = if args.seeded { 1 } else { args.count };
+            let share_size = (stored * scheme.records() * size_of::<EncodedBits>()) as u64;
+            let total_size = count.map(|count| {
+                HumanBytes(if args.share_masks {
+                    count.0 * 2 * share_size
+                } else {
+                    count.0 * (size_of::<Bits>() as u64 + share_size)
+                })
+            });
+            if let Some(total_size) = &total_size {
+                eprintln!("Estimated total size of shares: {total_size}",);
+            }
 
             // Pipeline
             // A reader thread parsing the JSON into Vec<Template>'s
@@ -293,37 +945,111 @@ async fn main() -> Result<(), anyhow::Error> {
             // Generate output files
             eprintln!(
                 "Output {:?} {:?}",
-                args.output.with_extension("masks"),
+                args.output.with_extension(if args.share_masks {
+                    "mask-share-n"
+                } else {
+                    "masks"
+                }),
                 args.output.with_extension("share-n")
             );
-            let mut masks = File::create(args.output.with_extension("masks"))
-                .await
-                .map(BufWriter::new)?;
+            // All files carry the same random dataset identifier, so files from
+            // different runs can not be mixed up.
+            let dataset = Dataset(thread_rng().gen());
+            let header = |kind, party| {
+                Header::new(
+                    kind,
+                    party,
+                    args.count,
+                    scheme.threshold(),
+                    scheme.records(),
+                    dataset,
+                )
+            };
+            eprintln!("Dataset {dataset}");
+            let mut masks = if args.share_masks {
+                None
+            } else {
+                let path = args.output.with_extension("masks");
+                let header =
+                    Header::new(Kind::Masks, 0, args.count, scheme.threshold(), 1, dataset);
+                Some(Writer::create(&path, header).await?)
+            };
+            // With seeded shares all parties but the last store the seed their
+            // share is expanded from instead of the share itself.
+            let seeded = if args.seeded { args.count - 1 } else { 0 };
+            let seeds = (0..seeded)
+                .map(|_| thread_rng().gen())
+                .collect::<Vec<Seed>>();
+            let mask_seeds = (0..seeded)
+                .map(|_| thread_rng().gen())
+                .collect::<Vec<Seed>>();
+            let kind = |kind: Kind, party| {
+                if party < seeded {
+                    kind.seeded().unwrap()
+                } else {
+                    kind
+                }
+            };
             let mut shares = Vec::new();
+            let mut mask_shares = Vec::new();
             for i in 0..args.count {
-                shares.push(
-                    File::create(args.output.with_extension(format!("share-{i}")))
-                        .await
-                        .map(BufWriter::new)?,
+                let path = args.output.with_extension(format!("share-{i}"));
+                let mut output = Writer::create(&path, header(kind(Kind::Share, i), i)).await?;
+                if let Some(seed) = seeds.get(i) {
+                    output.write(seed).await?;
+                }
+                shares.push(output);
+                if args.share_masks {
+                    let path = args.output.with_extension(format!("mask-share-{i}"));
+                    let mut output =
+                        Writer::create(&path, header(kind(Kind::MaskShare, i), i)).await?;
+                    if let Some(seed) = mask_seeds.get(i) {
+                        output.write(seed).await?;
+                    }
+                    mask_shares.push(output);
+                }
+            }
+            if args.seeded {
+                eprintln!(
+                    "Parties 0 to {} expand their shares from seeds.",
+                    seeded - 1
                 );
             }
 
-            // Read elements sequentially to the channel
-            // Runs at around 20k/s bottle-necked by deserializing hex strings.
+            // Read elements sequentially to the channel, writing any identifiers
+            // to the index. JSON objects are only delimited sequentially and
+            // deserialized in parallel, as decoding the hex strings dominates.
+            // Binary templates are copied from the map with their identifiers
+            // read from the sidecar.
+            let mut ids = IdWriter::create(&args.output.with_extension("ids"))?;
+            let input_ids = match &binary {
+                Some(_) => templates::read_ids(&args.input)?,
+                None => None,
+            };
             let (sender, mut templates) = mpsc::channel(4);
             let reader_task = tokio::task::spawn_blocking(move || {
-                let iter = iter_json_array::<Template, _>(input);
-                let mut buffer = Vec::with_capacity(1000);
-                for template in iter {
-                    buffer.push(template?);
-                    if buffer.len() == buffer.capacity() {
-                        let mut other = Vec::with_capacity(buffer.capacity());
-                        swap(&mut buffer, &mut other);
-                        sender.blocking_send(other)?;
+                if let Some(binary) = binary {
+                    let mut input_ids = input_ids.into_iter().flatten();
+                    for batch in binary.templates().chunks(1000) {
+                        for _ in batch {
+                            ids.push(input_ids.next().transpose()?.flatten().as_deref())?;
+                        }
+                        sender.blocking_send(batch.to_vec())?;
+                    }
+                    if ids.finish()? > 0 {
+                        eprintln!("Wrote identifiers to the index.");
                     }
+                    return Ok(());
                 }
-                if !buffer.is_empty() {
-                    sender.blocking_send(buffer)?;
+                for batch in par_json_objects::<Entry, _>(input, 1000) {
+                    let batch = batch?;
+                    for entry in &batch {
+                        ids.push(entry.id.as_deref())?;
+                    }
+                    sender.blocking_send(batch.iter().map(Entry::template).collect::<Vec<_>>())?;
+                }
+                if ids.finish()? > 0 {
+                    eprintln!("Wrote identifiers to the index.");
                 }
                 Ok(())
             });
@@ -331,359 +1057,678 @@ async fn main() -> Result<(), anyhow::Error> {
             // Process batches in parallel
             let (sender, mut buffers) = mpsc::channel(4);
             let process_task = tokio::task::spawn_blocking(move || {
+                // Shares expanded from seeds are keyed by the entry index.
+                let mut entries = 0;
+                let share = |secret: &EncodedBits, seeds: &[Seed], index| {
+                    if args.seeded {
+                        let mut records = vec![Vec::new(); seeded];
+                        records.push(vec![secret.share_seeded(seeds, index)]);
+                        records
+                    } else {
+                        scheme.share(secret)
+                    }
+                };
                 while let Some(templates) = templates.blocking_recv() {
                     // Compute main buffer and shares in parallel
                     let mut main = vec![0_u8; templates.len() * size_of::<Bits>()];
                     let shares = templates
                         .par_iter()
                         .zip(main.par_chunks_exact_mut(size_of::<Bits>()))
-                        .map(|(&template, main)| {
-                            main.copy_from_slice(bytes_of(&template.mask));
-                            encode(&template).share(args.count)
+                        .enumerate()
+                        .map(|(i, (&template, main))| {
+                            let index = entries + i;
+                            let mask_shares = if args.share_masks {
+                                share(&EncodedBits::from(&template.mask), &mask_seeds, index)
+                            } else {
+                                main.copy_from_slice(bytes_of(&template.mask));
+                                Vec::new()
+                            };
+                            (share(&encode(&template), &seeds, index), mask_shares)
                         })
                         .collect::<Vec<_>>();
+                    entries += templates.len();
+                    if args.share_masks {
+                        main.clear();
+                    }
 
                     // Sequentially merge share outputs
                     // It would be nice if we could write these in place likewith the main share.
+                    let output_size = templates.len() * scheme.records() * size_of::<EncodedBits>();
                     let mut outputs: Vec<Vec<u8>> =
-                        vec![
-                            Vec::with_capacity(templates.len() * size_of::<EncodedBits>());
-                            args.count
-                        ];
-                    for shares in shares.iter() {
-                        for (output, share) in outputs.iter_mut().zip(shares.iter()) {
-                            output.extend_from_slice(bytes_of(share));
+                        vec![Vec::with_capacity(output_size); args.count];
+                    let mut mask_outputs: Vec<Vec<u8>> = if args.share_masks {
+                        vec![Vec::with_capacity(output_size); args.count]
+                    } else {
+                        Vec::new()
+                    };
+                    for (shares, mask_shares) in shares.iter() {
+                        for (output, records) in outputs.iter_mut().zip(shares.iter()) {
+                            output.extend_from_slice(cast_slice(records));
+                        }
+                        for (output, records) in mask_outputs.iter_mut().zip(mask_shares.iter()) {
+                            output.extend_from_slice(cast_slice(records));
                         }
                     }
-                    sender.blocking_send((main, outputs))?;
+                    sender.blocking_send((main, outputs, mask_outputs))?;
                 }
-                Ok(())
+                Ok(entries)
             });
 
             // Write
-            let progress = ProgressBar::new(total_size.0).with_style(byte_style);
-            while let Some((buf_main, buf_outputs)) = buffers.recv().await {
-                masks.write_all(&buf_main).await?;
-                progress.inc(buf_main.len() as u64);
+            let progress = match total_size {
+                Some(total_size) => ProgressBar::new(total_size.0).with_style(byte_style),
+                None => ProgressBar::new_spinner().with_style(stream_style),
+            };
+            while let Some((buf_main, buf_outputs, buf_masks)) = buffers.recv().await {
+                if let Some(masks) = &mut masks {
+                    masks.write(&buf_main).await?;
+                    progress.inc(buf_main.len() as u64);
+                }
                 for (output, buffer) in shares.iter_mut().zip(buf_outputs) {
-                    output.write_all(&buffer).await?;
+                    output.write(&buffer).await?;
+                    progress.inc(buffer.len() as u64);
+                }
+                for (output, buffer) in mask_shares.iter_mut().zip(buf_masks) {
+                    output.write(&buffer).await?;
                     progress.inc(buffer.len() as u64);
                 }
-            }
-            masks.flush().await?;
-            for output in &mut shares {
-                output.flush().await?;
             }
 
+            // Only write the headers once the input was read completely.
             reader_task.await??;
-            process_task.await??;
+            let entries = process_task.await??;
+            let expanded = shares.iter_mut().take(seeded);
+            for output in expanded.chain(mask_shares.iter_mut().take(seeded)) {
+                output.set_expanded(entries);
+            }
+            let masks = match masks {
+                Some(masks) => Some(masks.finish().await?.root()),
+                None => None,
+            };
+            let mut roots = Vec::with_capacity(shares.len());
+            for output in shares {
+                roots.push(output.finish().await?.root());
+            }
+            let mut mask_roots = Vec::with_capacity(mask_shares.len());
+            for output in mask_shares {
+                mask_roots.push(output.finish().await?.root());
+            }
             progress.finish();
 
+            // Publish the roots for the resolver to check the participants against.
+            let path = args.output.with_extension("roots");
+            Manifest {
+                dataset,
+                masks,
+                shares: roots,
+                mask_shares: mask_roots,
+            }
+            .write(&path)?;
+            eprintln!("Wrote Merkle roots to {path:?}");
+
             Ok(())
         }
-        Commands::Participant(args) => {
-            // Read share as memory mapped file.
-            let file = std::fs::File::open(&args.input)
-                .with_context(|| format!("Failed to open share at {:?}", args.input))?;
-            let size = HumanBytes(file.metadata()?.size());
-            let mmap = Arc::new(unsafe { MmapOptions::new().map(&file)? });
-            let count = {
-                let patterns: &[EncodedBits] = try_cast_slice(&mmap)
-                    .map_err(|_| format_err!("Share file {:?} invalid.", args.input))?;
+        Commands::Decrypt(args) => {
+            // Open output file (synchronous IO for Serde)
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .create_new(!args.replace)
+                .open(&args.output)
+                .with_context(|| format!("Failed to create file at {:?}", args.output))?;
+
+            // Read masks and shares as memory mapped files.
+            let scheme = Replicated::new(args.count, args.threshold.unwrap_or(args.count))?;
+            let records = scheme.records();
+            let (mask_shares, mmap_masks, count) = if args.shared_masks {
+                let (mask_shares, mask_count) =
+                    open_shares(&args.input, Kind::MaskShare, args.count, args.legacy)?;
+                if mask_count % records != 0 {
+                    bail!("Mask shares have {mask_count} records, expected multiple of {records}.");
+                }
+                eprintln!(
+                    "Opened mask shares with {} entries",
+                    HumanCount((mask_count / records) as u64)
+                );
+                (mask_shares, None, mask_count / records)
+            } else {
+                let path = args.input.with_extension("masks");
+                let mmap_masks = DataFile::open(&path, Kind::Masks, args.legacy)?;
+                let count = cast_slice::<_, Bits>(&mmap_masks).len();
                 eprintln!(
-                    "Opened share {:?} with {} encrypted patterns ({})",
-                    args.input,
-                    HumanCount(patterns.len() as u64),
-                    size
+                    "Opened masks {path:?} with {} entries",
+                    HumanCount(count as u64)
                 );
-                patterns.len()
+                (Vec::new(), Some(mmap_masks), count)
             };
+            let (mmap_shares, share_count) =
+                open_shares(&args.input, Kind::Share, args.count, args.legacy)?;
+            if let Some(header) = mmap_shares[0].header() {
+                if header.threshold() != scheme.threshold() {
+                    bail!(
+                        "Shares were prepared with threshold {}, not {}.",
+                        header.threshold(),
+                        scheme.threshold()
+                    );
+                }
+            }
+            let masks_header = match &mmap_masks {
+                Some(mmap_masks) => mmap_masks.header(),
+                None => mask_shares[0].header(),
+            };
+            if let (Some(masks), Some(shares)) = (masks_header, mmap_shares[0].header()) {
+                if masks.dataset() != shares.dataset() {
+                    bail!(
+                        "Shares are from dataset {}, masks from {}.",
+                        shares.dataset(),
+                        masks.dataset()
+                    );
+                }
+            }
+            if share_count != count * records {
+                bail!(
+                    "Shares have {share_count} records, expected {} entries of {records}.",
+                    count
+                );
+            }
 
-            // TODO: Sync from database and add to memmapped file.
+            // Deleted entries can no longer be returned by queries.
+            let path = args
+                .tombstones
+                .unwrap_or_else(|| args.input.with_extension("tombstones"));
+            let tombstones = Tombstones::open(&path, size_of::<Bits>())?;
+            if tombstones.len() > 0 {
+                eprintln!(
+                    "Leaving out {} deleted entries listed in {path:?}.",
+                    tombstones.len()
+                );
+            }
+
+            eprintln!("Writing templates to {:?}", args.output);
+            let progress = ProgressBar::new(count as u64).with_style(count_style);
+            let worker = tokio::task::spawn_blocking(move || {
+                const BATCH_SIZE: usize = 1000;
+                let mut output = std::io::BufWriter::new(file);
+                output.write_all(b"[")?;
+                let mut first = true;
+                for start in (0..count).step_by(BATCH_SIZE) {
+                    // Combine and serialize a batch of templates in parallel
+                    let batch = (start..min(start + BATCH_SIZE, count))
+                        .into_par_iter()
+                        .filter(|&i| !tombstones.contains(i))
+                        .map(|i| {
+                            // Seeded shares are expanded for the entry.
+                            let range = i * records..(i + 1) * records;
+                            let shares = mmap_shares
+                                .iter()
+                                .map(|share| share.get(range.clone()))
+                                .collect::<Vec<_>>();
+                            let shares = shares.iter().map(|s| &s[..]).collect::<Vec<_>>();
+                            let encoded = scheme
+                                .reconstruct(&shares)
+                                .ok_or_else(|| format_err!("Entry {i} has inconsistent shares."))?;
+                            let template = decode(&encoded)
+                                .ok_or_else(|| format_err!("Entry {i} has values out of range."))?;
+                            let mask = if let Some(mmap_masks) = &mmap_masks {
+                                cast_slice::<_, Bits>(mmap_masks)[i]
+                            } else {
+                                let shares = mask_shares
+                                    .iter()
+                                    .map(|share| share.get(range.clone()))
+                                    .collect::<Vec<_>>();
+                                let shares = shares.iter().map(|s| &s[..]).collect::<Vec<_>>();
+                                let encoded = scheme.reconstruct(&shares).ok_or_else(|| {
+                                    format_err!("Entry {i} has inconsistent mask shares.")
+                                })?;
+                                decode_mask(&encoded).ok_or_else(|| {
+                                    format_err!("Entry {i} has mask values out of range.")
+                                })?
+                            };
+                            if template.mask != mask {
+                                bail!("Entry {i} does not match the stored mask.");
+                            }
+                            let mut buf = Vec::with_capacity(6500);
+                            serde_json::to_writer_pretty(&mut buf, &template)?;
+                            Ok(buf)
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    for buf in &batch {
+                        if !first {
+                            output.write_all(b",")?;
+                        }
+                        output.write_all(buf)?;
+                        first = false;
+                    }
+                    progress.inc((min(start + BATCH_SIZE, count) - start) as u64);
+                }
+                output.write_all(b"]\n")?;
+                output.flush()?;
+                progress.finish();
+                Ok(())
+            });
+            worker.await?
+        }
+        Commands::Preprocess(args) if args.material == MaterialKind::Comparisons => {
+            // Comparison material does not depend on the database contents.
+            let count = if args.shared_masks {
+                open_shares(&args.input, Kind::MaskShare, args.count, args.legacy)?.1
+            } else {
+                let path = args.input.with_extension("masks");
+                DataFile::open(&path, Kind::Masks, args.legacy)?.len() / size_of::<Bits>()
+            };
+            let total_size = HumanBytes(
+                (args.count * Comparisons::header_size()
+                    + (0..args.count)
+                        .map(|party| {
+                            Comparisons::slot_size(party, args.count, count, args.shared_masks)
+                        })
+                        .sum::<usize>()
+                        * args.slots) as u64,
+            );
+            eprintln!(
+                "Output {:?} ({} slots, total {total_size})",
+                args.input.with_extension("compare-n"),
+                args.slots
+            );
+            tokio::task::spawn_blocking(move || {
+                secure_threshold::deal(
+                    &args.input,
+                    args.count,
+                    count,
+                    args.slots,
+                    args.shared_masks,
+                )
+            })
+            .await?
+        }
+        Commands::Preprocess(args) => {
+            // The dealer sees the combined shares, like `Prepare` sees the templates.
+            // With shared masks there is a second query part for the mask.
+            let (mut mmap_shares, count) =
+                open_shares(&args.input, Kind::Share, args.count, args.legacy)?;
+            let parts = if args.shared_masks {
+                let (mmap_masks, masks) =
+                    open_shares(&args.input, Kind::MaskShare, args.count, args.legacy)?;
+                if masks != count {
+                    bail!("Shares have {count} records for {masks} mask records.");
+                }
+                mmap_shares.extend(mmap_masks);
+                2
+            } else {
+                let path = args.input.with_extension("masks");
+                let masks =
+                    DataFile::open(&path, Kind::Masks, args.legacy)?.len() / size_of::<Bits>();
+                if masks != count {
+                    bail!(
+                        "Shares have {count} records for {masks} masks, triples require shares \
+                         without threshold."
+                    );
+                }
+                1
+            };
+            let slot_size = Triples::slot_size(count, parts);
+            let total_size = HumanBytes((args.slots * slot_size * args.count) as u64);
+            eprintln!(
+                "Output {:?} ({} slots, total {total_size})",
+                args.input.with_extension("triples-n"),
+                args.slots
+            );
+            let mut outputs = Vec::with_capacity(args.count);
+            for i in 0..args.count {
+                let path = args.input.with_extension(format!("triples-{i}"));
+                let file = std::fs::File::create(&path)
+                    .with_context(|| format!("Failed to create file at {path:?}"))?;
+                outputs.push(std::io::BufWriter::new(file));
+            }
+
+            let progress = ProgressBar::new(total_size.0).with_style(byte_style);
+            let worker = tokio::task::spawn_blocking(move || {
+                // The dealer combines the shares of every record.
+                let record = |part: usize, i: usize| {
+                    mmap_shares[part * args.count..(part + 1) * args.count]
+                        .iter()
+                        .map(|share| share.record(i))
+                        .sum()
+                };
+                triples::deal(&mut outputs, parts, count, args.slots, record, |bytes| {
+                    progress.inc(bytes)
+                })?;
+                for output in &mut outputs {
+                    output.flush()?;
+                }
+                progress.finish();
+                Ok(())
+            });
+            worker.await?
+        }
+        Commands::Participant(args) => {
+            let signals = Signals::new()?;
+            let participant = Arc::new(Participant::new(&args, count_style, byte_style)?);
 
             // Open socket
             let listener = TcpListener::bind(args.bind)
                 .await
                 .with_context(|| format!("Could not bind to socket {}", args.bind))?;
             eprintln!("Listening on {}", listener.local_addr()?);
+            participant.serve(listener, signals).await
+        }
+        Commands::Coordinator(args) | Commands::Resolver(args) => {
+            if args.max_batch == 0 || args.max_batch > MAX_QUERIES {
+                bail!("Maximum batch size must be between 1 and {MAX_QUERIES}.");
+            }
+            let signals = Signals::new()?;
+            let resolver = Arc::new(Resolver::new(&args, count_style)?);
+            let coalescer = Arc::new(Coalescer::new(
+                resolver.clone(),
+                Duration::from_millis(args.coalesce_window),
+                args.max_batch,
+                (args.query_timeout > 0).then(|| Duration::from_millis(args.query_timeout)),
+            ));
+
+            // Serve API requests
+            let listener = TcpListener::bind(args.bind)
+                .await
+                .with_context(|| format!("Could not bind to socket {}", args.bind))?;
+            eprintln!("Listening for API requests on {}", listener.local_addr()?);
+            api::serve(
+                listener,
+                resolver,
+                coalescer,
+                signals,
+                Duration::from_millis(args.shutdown_grace),
+            )
+            .await
+        }
+        Commands::Query(args) => {
+            if args.batch == 0 {
+                bail!("Batch size must be positive.");
+            }
+            let mut url = args.resolver.join(if args.batch > 1 {
+                "query/batch"
+            } else {
+                "query"
+            })?;
+            if let Some(k) = args.top_k {
+                url.query_pairs_mut().append_pair("k", &k.to_string());
+            }
+            if let Some(threshold) = args.threshold {
+                url.query_pairs_mut()
+                    .append_pair("threshold", &threshold.to_string());
+            }
+            let file = std::fs::File::open(&args.input)
+                .with_context(|| format!("Failed to open file at {:?}", args.input))?;
+            let input = std::io::BufReader::new(file);
 
-            // Listen for requests
-            loop {
-                let (mut stream, peer) = listener.accept().await?;
-                eprintln!("Inbound from {peer:?}");
-
-                // TODO: Sync from database and add to memmapped file.
-
-                // Read request
-                let mut template = Template::default();
-                stream.read_exact(bytes_of_mut(&mut template)).await?;
-                eprintln!("Request received.");
-
-                // Process in worker thread
-                let (sender, mut receiver) = mpsc::channel(4);
-                let mmap_ref = mmap.clone();
-                let worker = tokio::task::spawn_blocking(move || {
-                    let patterns: &[EncodedBits] = cast_slice(&mmap_ref);
-                    let engine = DistanceEngine::new(&encode(&template));
-                    for chunk in patterns.chunks(20_000) {
-                        let mut result = vec![0_u8; chunk.len() * size_of::<[u16; 31]>()];
-                        engine.batch_process(cast_slice_mut(&mut result), chunk);
-                        sender.blocking_send(result)?;
-                    }
-                    Ok(())
-                });
+            // Read batches of templates sequentially to the channel
+            let (sender, mut batches) = mpsc::channel(4);
+            let reader_task = tokio::task::spawn_blocking(move || {
+                let iter = iter_json_objects::<Template, _>(input);
+                for batch in &iter.chunks(args.batch) {
+                    sender.blocking_send(batch.collect::<Result<Vec<_>, _>>()?)?;
+                }
+                Ok(())
+            });
 
-                // Stream output
-                let progress_bar = ProgressBar::new((count * size_of::<[u16; 31]>()) as u64)
-                    .with_style(byte_style.clone());
-                let mut buf = BufWriter::new(stream);
-                while let Some(buffer) = receiver.recv().await {
-                    buf.write_all(&buffer).await?;
-                    progress_bar.inc(buffer.len() as u64);
+            // Submit batches one at a time and print the results as JSON lines
+            eprintln!("Submitting queries to {url}");
+            let client = reqwest::Client::new();
+            let mut count = 0;
+            let mut failed = 0;
+            while let Some(batch) = batches.recv().await {
+                let request = if args.batch > 1 {
+                    client.post(url.clone()).json(&batch)
+                } else {
+                    client.post(url.clone()).json(&batch[0])
+                };
+                let response = request
+                    .send()
+                    .await
+                    .with_context(|| format!("Could not reach resolver at {url}"))?;
+                let status = response.status();
+                let body = response.text().await?;
+                let body = serde_json::from_str::<serde_json::Value>(&body)
+                    .unwrap_or_else(|_| json!({ "error": body }));
+
+                // Successful batches return an array, errors apply to the whole batch.
+                let results = match body {
+                    serde_json::Value::Array(results) if args.batch > 1 => results,
+                    body => vec![body; batch.len()],
+                };
+                for result in results {
+                    println!(
+                        "{}",
+                        json!({ "query": count, "status": status.as_u16(), "result": result })
+                    );
+                    if !status.is_success() {
+                        failed += 1;
+                    }
+                    count += 1;
                 }
-                progress_bar.finish();
-                worker.await??;
-                eprintln!("Reply sent.");
             }
+            reader_task.await??;
 
-            // TODO: A clean way to exit
-            #[allow(unreachable_code)]
+            eprintln!("Submitted {count} queries.");
+            if failed > 0 {
+                bail!("{failed} out of {count} queries failed.");
+            }
             Ok(())
         }
-        Commands::Coordinator(args) | Commands::Resolver(args) => {
-            // Read main file with masks
-            let file = std::fs::File::open(&args.masks)
-                .with_context(|| format!("Failed to open main at {:?}", args.masks))?;
-            let size = HumanBytes(file.metadata()?.size());
-            let mmap = Arc::new(unsafe { MmapOptions::new().map(&file)? });
-            let count = {
-                let masks: &[Bits] = try_cast_slice(&mmap)
-                    .map_err(|_| format_err!("Main file {:?} invalid.", args.masks))?;
-                eprintln!(
-                    "Opened main {:?} with {} masks ({})",
-                    args.masks,
-                    HumanCount(masks.len() as u64),
-                    size
-                );
-                masks.len()
-            };
-
-            eprintln!("Participants: {:?}", &args.participants);
-
-            const BATCH_SIZE: usize = 20_000;
-
-            eprintln!("Starting main loop.");
-            loop {
-                // Generate random request.
-                eprintln!("Generating random request.");
-                let query: Template = thread_rng().gen();
-
-                // TODO: Local share.
-                assert!(args.share.is_none());
-
-                // Contact participants
-                eprintln!("Calling participants {:?}", args.participants);
-                let mut streams = try_join_all(args.participants.iter().map(|address| async {
-                    let address = address.clone();
-
-                    // Connect to participant
-                    let mut stream = TcpStream::connect(address)
-                        .await
-                        .with_context(|| format!("Could not connect to {address}"))?;
-                    eprintln!("Connected to {address}");
-
-                    // Send query
-                    stream.write_all(bytes_of(&query)).await?;
-                    eprintln!("Request send.");
-
-                    // Read buffered
-                    let stream = BufReader::new(stream);
-
-                    Ok(stream)
-                }))
-                .await?;
-
-                // Prepare local computation of denominators
-                eprintln!("Locally computing denominators.");
-                let mmap_ref = mmap.clone();
-                let (sender, mut denom_receiver) = mpsc::channel(4);
-                let denomoninator_worker = tokio::task::spawn_blocking(move || {
-                    let masks: &[Bits] = cast_slice(&mmap_ref);
-                    let engine = MasksEngine::new(&query.mask);
-                    for chunk in masks.chunks(BATCH_SIZE) {
-                        let mut result = vec![[0_u16; 31]; chunk.len()];
-                        engine.batch_process(&mut result, chunk);
-                        sender.blocking_send(result)?;
-                    }
-                    Ok(())
-                });
-
-                // Collect batches of shares
-                let (sender, mut receiver) = mpsc::channel(4);
-                let batch_worker = tokio::task::spawn(async move {
-                    loop {
-                        // Collect futures of denominator and share batches
-                        let streams_future = try_join_all(streams.iter_mut().enumerate().map(
-                            |(i, stream)| async move {
-                                // Allocate a buffer and cast to bytes
-                                // OPT: Could use MaybeUninit here.
-                                let mut batch = vec![[0_u16; 31]; BATCH_SIZE];
-                                let mut buffer: &mut [u8] = cast_slice_mut(batch.as_mut_slice());
-
-                                // We can not use read_exact here as we might get EOF before the
-                                // buffer is full But we should
-                                // still try to fill the entire buffer.
-                                // If nothing else, this guarantees that we read batches at a
-                                // [u16;31] boundary.
-                                while !buffer.is_empty() {
-                                    let bytes_read = stream.read_buf(&mut buffer).await?;
-                                    if bytes_read == 0 {
-                                        // End of stream
-                                        eprintln!("Participant {i} finished.");
-                                        if buffer.len() % size_of::<[u16; 31]>() != 0 {
-                                            eprintln!(
-                                                "Warning: received partial results from {i}."
-                                            );
-                                        }
-                                        let n_incomplete = (buffer.len() + size_of::<[u16; 31]>()
-                                            - 1)
-                                            / size_of::<[u16; 31]>();
-                                        batch.truncate(batch.len() - n_incomplete);
-                                        break;
-                                    }
-                                }
-                                Ok(batch)
-                            },
-                        ));
-
-                        // Wait on all parts concurrently
-                        let (denom, shares) = join!(denom_receiver.recv(), streams_future);
-                        let mut denom = denom.unwrap_or_default();
-                        let mut shares = shares?;
-
-                        // Find the shortest prefix
-                        let batch_size = shares.iter().map(Vec::len).fold(denom.len(), min);
-                        denom.truncate(batch_size);
-                        shares
-                            .iter_mut()
-                            .for_each(|batch| batch.truncate(batch_size));
-
-                        // Send batches
-                        sender.send((denom, shares)).await?;
-                        if batch_size == 0 {
-                            break;
-                        }
-                    }
-                    Ok(())
-                });
+        Commands::Enroll(args) => {
+            if args.batch == 0 {
+                bail!("Batch size must be positive.");
+            }
+            let url = args.resolver.join("enroll")?;
+            let file = std::fs::File::open(&args.input)
+                .with_context(|| format!("Failed to open file at {:?}", args.input))?;
+            let input = std::io::BufReader::new(file);
 
-                // Keep track of min distance entry.
-                let mut min_distance = f64::INFINITY;
-                let mut min_index = usize::MAX;
-
-                // Process results
-                eprintln!("Processing results.");
-                let progress_bar = ProgressBar::new(count as u64).with_style(count_style.clone());
-                let mut i = 0;
-                loop {
-                    // Fetch batches of denominators and shares
-                    let (denom_batch, shares) = receiver.recv().await.unwrap();
-                    let batch_size = denom_batch.len();
-                    if batch_size == 0 {
-                        break;
-                    }
+            // Read batches of templates sequentially to the channel
+            let (sender, mut batches) = mpsc::channel(4);
+            let reader_task = tokio::task::spawn_blocking(move || {
+                let iter = iter_json_objects::<Entry, _>(input);
+                for batch in &iter.chunks(args.batch) {
+                    sender.blocking_send(batch.collect::<Result<Vec<_>, _>>()?)?;
+                }
+                Ok(())
+            });
 
-                    // Compute batch of distances in Rayon
-                    let worker = tokio::task::spawn_blocking(move || {
-                        (0..batch_size)
-                            .into_par_iter()
-                            .map(|i| {
-                                let denominator = denom_batch[i];
-                                let mut numerator = [0_u16; 31];
-                                for share in shares.iter() {
-                                    let share = share[i];
-                                    for (n, &s) in numerator.iter_mut().zip(share.iter()) {
-                                        *n = n.wrapping_add(s);
-                                    }
-                                }
-                                decode_distance(&numerator, &denominator)
-                            })
-                            .collect::<Vec<_>>()
-                    });
-                    let distances = worker.await?;
+            // Submit batches one at a time, stopping at the first failure as
+            // later entries would otherwise be enrolled out of order.
+            eprintln!("Enrolling templates through {url}");
+            let client = reqwest::Client::new();
+            let mut count = 0;
+            while let Some(batch) = batches.recv().await {
+                let response = client
+                    .post(url.clone())
+                    .json(&batch)
+                    .send()
+                    .await
+                    .with_context(|| format!("Could not reach resolver at {url}"))?;
+                let status = response.status();
+                let body = response.text().await?;
+                if !status.is_success() {
+                    bail!("Enrollment failed after {count} templates ({status}): {body}");
+                }
+                println!("{body}");
+                count += batch.len();
+            }
+            reader_task.await??;
 
-                    // Aggregate distances
-                    for (j, distance) in distances.into_iter().enumerate() {
-                        if distance < min_distance {
-                            min_index = i + j;
-                            min_distance = distance;
-                        }
+            eprintln!("Enrolled {count} templates.");
+            Ok(())
+        }
+        Commands::Delete(args) => {
+            let url = args.resolver.join("delete")?;
+            let entries = args
+                .entries
+                .iter()
+                .map(|entry| {
+                    if args.id {
+                        Ok(json!(entry))
+                    } else {
+                        let index = entry
+                            .parse::<usize>()
+                            .with_context(|| format!("Invalid entry index {entry:?}"))?;
+                        Ok(json!(index))
                     }
-
-                    // Update counter
-                    i += batch_size;
-                    progress_bar.inc(batch_size as u64);
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let response = reqwest::Client::new()
+                .post(url.clone())
+                .json(&entries)
+                .send()
+                .await
+                .with_context(|| format!("Could not reach resolver at {url}"))?;
+            let status = response.status();
+            let body = response.text().await?;
+            if !status.is_success() {
+                bail!("Deletion failed ({status}): {body}");
+            }
+            println!("{body}");
+            Ok(())
+        }
+        Commands::Compact(args) => {
+            let remaining = tombstones::compact(
+                &args.tombstones,
+                &args.files,
+                args.ids.as_deref(),
+                args.legacy,
+            )?;
+            eprintln!(
+                "Compacted {} files to {remaining} entries, removed {:?}.",
+                args.files.len(),
+                args.tombstones
+            );
+            Ok(())
+        }
+        Commands::Verify(args) => {
+            let mut corrupted = 0;
+            for path in &args.files {
+                let mut file = std::fs::File::open(path)
+                    .with_context(|| format!("Failed to open {path:?}"))?;
+                let header = format::read_header(&mut file, path)?;
+                let blocks = merkle::check(path, &header)?;
+                for block in &blocks {
+                    eprintln!(
+                        "{path:?}: block {block} (bytes {}..{} of records) is corrupted.",
+                        block * merkle::BLOCK_SIZE,
+                        min((block + 1) * merkle::BLOCK_SIZE, header.data_len())
+                    );
                 }
-                progress_bar.finish();
-
-                // Await processes.
-                // Note that some can be stopped early due to receiver being closed.
-                // TODO: Make sure that all workers receive signal to stop.
-                drop(receiver);
-                denomoninator_worker.await??;
-                batch_worker.await??;
-
-                eprintln!(
-                    "Found closest entry at {min_index} out of {i} at distance {min_distance}."
+                corrupted += blocks.len();
+                println!(
+                    "{path:?}: {} of party {} from dataset {}, {} records, Merkle root {}",
+                    header.kind(),
+                    header.party(),
+                    header.dataset(),
+                    header.count(),
+                    header.root()
                 );
             }
-
-            // TODO: A clean way to exit
-            #[allow(unreachable_code)]
+            if corrupted > 0 {
+                bail!("Found {corrupted} corrupted blocks.");
+            }
             Ok(())
         }
         Commands::Benchmark(args) => {
             eprintln!("Participant: {:?}", &args.participant);
+            if args.concurrent == 0 {
+                bail!("Concurrent queries must be positive.");
+            }
+
+            // Connect to participant once, queries share the connection.
+            eprintln!("Calling participant.");
+            let (stream, _) = handshake::connect(args.participant).await?;
+            eprintln!("Connected to {}", args.participant);
+            let (mux, reader) = Mux::new(stream);
+            tokio::spawn({
+                let mux = mux.clone();
+                async move { mux.receive(reader).await }
+            });
 
             eprintln!("Starting main loop.");
             let mut max_size = 0;
             loop {
-                eprintln!("Generating random request.");
-                // Generate random request.
-                let query: Template = thread_rng().gen();
-
-                // Connect to participant
-                eprintln!("Calling participant.");
-                let mut stream = TcpStream::connect(args.participant)
-                    .await
-                    .with_context(|| format!("Could not connect to {}", args.participant))?;
-                eprintln!("Connected to {}", args.participant);
-
-                // Send query
-                stream.write_all(bytes_of(&query)).await?;
-                eprintln!("Request send.");
-
-                // Read buffered
-                let mut stream = BufReader::new(stream);
-
-                // Process results
-                eprintln!("Reading share.");
                 let progress_bar = ProgressBar::new(max_size as u64).with_style(byte_style.clone());
-                let mut i = 0;
-                let mut buffer = Vec::with_capacity(10_000_000);
-                loop {
-                    buffer.clear();
-                    stream.read_buf(&mut buffer).await?;
-                    if buffer.is_empty() {
-                        break;
+                let sizes = try_join_all((0..args.concurrent).map(|_| async {
+                    // Generate random request.
+                    let query: Template = thread_rng().gen();
+
+                    // Send query
+                    let mut channel = mux.open()?;
+                    channel.send(Message::Query, &[bytes_of(&query)]).await?;
+                    let announcement =
+                        Announcement::parse(&channel.expect(Message::Announce).await?)?;
+
+                    // Process results
+                    let count = announcement.count as usize;
+                    let parts = announcement.parts as usize;
+                    let mut results = ResultStream::new(channel, parts);
+                    let mut size = 0;
+                    for start in (0..count).step_by(comparison::BATCH) {
+                        let batch = results.take(min(comparison::BATCH, count - start)).await?;
+                        let bytes = batch.len() * size_of::<[u16; 31]>();
+                        size += bytes;
+                        progress_bar.inc(bytes as u64);
                     }
-                    i += buffer.len();
-                    progress_bar.inc(buffer.len() as u64);
-                }
+                    results.finish(count).await?;
+                    Ok(size)
+                }))
+                .await?;
                 progress_bar.finish();
-                max_size = max_size.max(i);
+                max_size = sizes.iter().sum();
             }
         }
-        _ => todo!(),
     }
 }
+
+/// Memory map `count` share files of `kind` (`Share` or `MaskShare`) with
+/// base name `base`, or read the seeds of seeded shares.
+///
+/// Returns the records and their number, which must agree. Unless the
+/// files are `legacy`, they must also be the parties' shares of one dataset.
+fn open_shares(
+    base: &Path,
+    kind: Kind,
+    count: usize,
+    legacy: bool,
+) -> Result<(Vec<Records>, usize)> {
+    let extension = match kind {
+        Kind::MaskShare => "mask-share",
+        _ => "share",
+    };
+    let mut mmaps = Vec::<Records>::with_capacity(count);
+    let mut entries = None;
+    for i in 0..count {
+        let path = base.with_extension(format!("{extension}-{i}"));
+        let mmap = Records::open(&path, kind, legacy)?;
+        if let Some(header) = mmap.header() {
+            if header.party() != i || header.parties() != count {
+                bail!(
+                    "Share file {path:?} is for party {} of {}, expected {i} of {count}.",
+                    header.party(),
+                    header.parties()
+                );
+            }
+            if let Some(first) = mmaps.first().and_then(Records::header) {
+                if header.dataset() != first.dataset() {
+                    bail!(
+                        "Share file {path:?} is from dataset {}, expected {}.",
+                        header.dataset(),
+                        first.dataset()
+                    );
+                }
+            }
+        }
+        if *entries.get_or_insert(mmap.len()) != mmap.len() {
+            bail!(
+                "Share file {path:?} has {} entries, expected {}.",
+                mmap.len(),
+                entries.unwrap()
+            );
+        }
+        mmaps.push(mmap);
+    }
+    Ok((mmaps, entries.unwrap_or_default()))
+}
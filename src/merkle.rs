@@ -0,0 +1,362 @@
+use crate::format::{Dataset, Header, HEADER_SIZE};
+use anyhow::{ensure, Context, Result};
+use bytemuck::{cast_slice, Pod, Zeroable};
+use memmap::MmapOptions;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::{self, Display},
+    fs,
+    mem::size_of,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Size of the blocks of records the tree is computed over.
+pub const BLOCK_SIZE: usize = 1 << 20;
+
+/// SHA-256 hash of a block or of a node of the tree.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Pod, Zeroable, Serialize, Deserialize)]
+#[repr(transparent)]
+#[serde(transparent)]
+pub struct Hash(#[serde(with = "hex")] pub [u8; 32]);
+
+impl Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Leaves and inner nodes are hashed with distinct prefixes, so that one can
+/// not be passed off as the other.
+fn leaf(block: &[u8]) -> Hash {
+    Hash(
+        Sha256::new()
+            .chain_update([0])
+            .chain_update(block)
+            .finalize()
+            .into(),
+    )
+}
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    Hash(
+        Sha256::new()
+            .chain_update([1])
+            .chain_update(left.0)
+            .chain_update(right.0)
+            .finalize()
+            .into(),
+    )
+}
+
+/// Root of the tree over `leaves`. An odd node at the end of a level is
+/// promoted to the next level unchanged.
+pub fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return leaf(&[]);
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Hashes of the blocks of `data`, computed in parallel.
+pub fn leaves(data: &[u8]) -> Vec<Hash> {
+    data.par_chunks(BLOCK_SIZE).map(leaf).collect()
+}
+
+/// Incrementally computes the leaves of data written in arbitrary pieces.
+pub struct Builder {
+    leaves:  Vec<Hash>,
+    partial: Vec<u8>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            leaves:  Vec::new(),
+            partial: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+
+    /// Continue the leaves of existing data, of which `tail` are the bytes
+    /// of the last block if it is incomplete.
+    pub fn resume(mut leaves: Vec<Hash>, tail: &[u8]) -> Self {
+        if !tail.is_empty() {
+            leaves.pop();
+        }
+        let mut builder = Self {
+            leaves,
+            partial: Vec::with_capacity(BLOCK_SIZE),
+        };
+        builder.update(tail);
+        builder
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = (BLOCK_SIZE - self.partial.len()).min(data.len());
+            self.partial.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.partial.len() == BLOCK_SIZE {
+                self.leaves.push(leaf(&self.partial));
+                self.partial.clear();
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<Hash> {
+        if !self.partial.is_empty() {
+            self.leaves.push(leaf(&self.partial));
+        }
+        self.leaves
+    }
+}
+
+/// Path of the file holding the leaves of a data file's tree.
+pub fn tree_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".merkle");
+    path.into()
+}
+
+/// Durably write the leaves of the tree of the data file at `path`.
+pub fn write_leaves(path: &Path, leaves: &[Hash]) -> Result<()> {
+    let path = tree_path(path);
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    fs::write(&temp, cast_slice::<_, u8>(leaves))
+        .with_context(|| format!("Failed to write Merkle tree {path:?}"))?;
+    fs::File::open(&temp)?.sync_all()?;
+    fs::rename(&temp, &path)?;
+    Ok(())
+}
+
+/// Read the leaves of the data file at `path` if they match `root`.
+///
+/// Returns `None` if they are missing or do not match, e.g. after an
+/// interrupted update.
+pub fn read_leaves(path: &Path, root: &Hash) -> Option<Vec<Hash>> {
+    let bytes = fs::read(tree_path(path)).ok()?;
+    if !bytes.len().is_multiple_of(size_of::<Hash>()) {
+        return None;
+    }
+    let leaves = bytes
+        .chunks_exact(size_of::<Hash>())
+        .map(|chunk| Hash(chunk.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    (self::root(&leaves) == *root).then_some(leaves)
+}
+
+/// Checks blocks of a data file against the leaves of its tree the first time
+/// they are accessed.
+pub struct Verifier {
+    path:     PathBuf,
+    leaves:   Vec<Hash>,
+    verified: Vec<AtomicBool>,
+}
+
+impl Verifier {
+    pub fn new(path: &Path, leaves: Vec<Hash>) -> Self {
+        let verified = leaves.iter().map(|_| AtomicBool::new(false)).collect();
+        Self {
+            path: path.to_path_buf(),
+            leaves,
+            verified,
+        }
+    }
+
+    /// Verify the blocks overlapping `range` of `data`, unless they were
+    /// verified before.
+    pub fn verify(&self, data: &[u8], range: Range<usize>) -> Result<()> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let blocks = range.start / BLOCK_SIZE..range.end.div_ceil(BLOCK_SIZE);
+        blocks
+            .into_par_iter()
+            .filter(|&block| !self.verified[block].load(Ordering::Relaxed))
+            .try_for_each(|block| {
+                let start = block * BLOCK_SIZE;
+                let end = (start + BLOCK_SIZE).min(data.len());
+                ensure!(
+                    leaf(&data[start..end]) == self.leaves[block],
+                    "Block {block} (bytes {start}..{end} of records) of {:?} does not match its \
+                     Merkle tree.",
+                    self.path
+                );
+                self.verified[block].store(true, Ordering::Relaxed);
+                Ok(())
+            })
+    }
+}
+
+/// Check all blocks of the data file at `path` with `header`, returning the
+/// corrupted ones.
+///
+/// Blocks can only be told apart with the stored leaves, without them only
+/// the file as a whole is checked against the root.
+pub fn check(path: &Path, header: &Header) -> Result<Vec<usize>> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    let mmap = unsafe { MmapOptions::new().map(&file)? };
+    let data = &mmap[HEADER_SIZE..HEADER_SIZE + header.data_len()];
+    let Some(expected) = read_leaves(path, &header.root()) else {
+        ensure!(
+            root(&leaves(data)) == header.root(),
+            "File {path:?} does not match its Merkle root, and its tree {:?} is missing or \
+             outdated, so corrupted blocks can not be located.",
+            tree_path(path)
+        );
+        return Ok(Vec::new());
+    };
+    Ok(leaves(data)
+        .into_iter()
+        .zip(expected)
+        .enumerate()
+        .filter(|(_, (actual, expected))| actual != expected)
+        .map(|(block, _)| block)
+        .collect())
+}
+
+/// Roots of all files of a dataset, published by `prepare` so the resolver can
+/// check that participants serve the expected data.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub dataset:     Dataset,
+    /// Root of the plaintext masks, unless they are secret-shared.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub masks:       Option<Hash>,
+    /// Roots of the shares, in party order.
+    pub shares:      Vec<Hash>,
+    /// Roots of the mask shares, in party order, if masks are secret-shared.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mask_shares: Vec<Hash>,
+}
+
+impl Manifest {
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read(path).with_context(|| format!("Failed to read manifest {path:?}"))?;
+        serde_json::from_slice(&contents).with_context(|| format!("Manifest {path:?} invalid."))
+    }
+
+    /// Durably replace the manifest at `path`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        fs::write(&temp, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write manifest {path:?}"))?;
+        fs::File::open(&temp)?.sync_all()?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{DataFile, Kind, Writer};
+    use mpc_iris_code::EncodedBits;
+    use rand::{thread_rng, Rng};
+    use std::{
+        fs::OpenOptions,
+        io::{Seek, SeekFrom, Write},
+    };
+
+    /// Write a share file spanning a few blocks.
+    async fn written(path: &Path) -> Header {
+        let records = (0..100)
+            .map(|_| thread_rng().gen())
+            .collect::<Vec<EncodedBits>>();
+        let header = Header::new(Kind::Share, 0, 2, 2, 1, Dataset::default());
+        let mut writer = Writer::create(path, header).await.unwrap();
+        writer.write(cast_slice(&records)).await.unwrap();
+        writer.finish().await.unwrap()
+    }
+
+    fn tamper(path: &Path, offset: usize) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start((HEADER_SIZE + offset) as u64))
+            .unwrap();
+        file.write_all(&[0xa5]).unwrap();
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut data = vec![0_u8; 2 * BLOCK_SIZE + 123];
+        thread_rng().fill(data.as_mut_slice());
+        let mut builder = Builder::new();
+        for piece in data[..BLOCK_SIZE + 7].chunks(100_003) {
+            builder.update(piece);
+        }
+        let resumed = builder.finish();
+        let mut builder = Builder::resume(resumed, &data[BLOCK_SIZE..BLOCK_SIZE + 7]);
+        builder.update(&data[BLOCK_SIZE + 7..]);
+        assert_eq!(builder.finish(), leaves(&data));
+        assert_ne!(leaf(&[]), node(&leaf(&[]), &leaf(&[])));
+    }
+
+    #[tokio::test]
+    async fn test_tampered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("share");
+        let header = written(&path).await;
+        assert!(check(&path, &header).unwrap().is_empty());
+
+        // The stored leaves locate a tampered block, which fails verification
+        // once it is used.
+        tamper(&path, BLOCK_SIZE + 5);
+        assert_eq!(check(&path, &header).unwrap(), [1]);
+        let data_file = DataFile::map(&path, Kind::Share, false).unwrap();
+        data_file.verify(0..BLOCK_SIZE).unwrap();
+        let err = data_file.verify(BLOCK_SIZE..BLOCK_SIZE + 1).unwrap_err();
+        assert!(err.to_string().contains("Block 1"), "{err}");
+
+        // Without them, only the file as a whole is found not to match.
+        fs::remove_file(tree_path(&path)).unwrap();
+        let err = check(&path, &header).unwrap_err();
+        assert!(err.to_string().contains("can not be located"), "{err}");
+        let err = DataFile::map(&path, Kind::Share, false).err().unwrap();
+        assert!(err.to_string().contains("Merkle root"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_wrong_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("share");
+        let header = written(&path).await;
+        let leaves = read_leaves(&path, &header.root()).unwrap();
+        assert_eq!(root(&leaves), header.root());
+        assert!(read_leaves(&path, &Hash([7; 32])).is_none());
+
+        let manifest = Manifest {
+            dataset:     header.dataset(),
+            masks:       None,
+            shares:      vec![header.root(), Hash([7; 32])],
+            mask_shares: Vec::new(),
+        };
+        let manifest_path = dir.path().join("manifest.json");
+        manifest.write(&manifest_path).unwrap();
+        assert_eq!(Manifest::read(&manifest_path).unwrap(), manifest);
+    }
+}
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+/// An entry close to a query, with the rotation of the query achieving the
+/// distance.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Neighbor {
+    pub index:    usize,
+    pub distance: f64,
+    pub rotation: i32,
+}
+
+/// Streaming selection of the `limit` closest entries, optionally only those
+/// within `threshold`.
+///
+/// Keeps a bounded max-heap, so memory is independent of the number of
+/// entries pushed. Ties are broken by the lowest index. Entries with an
+/// undefined distance are ignored.
+pub struct Neighbors {
+    limit:     usize,
+    threshold: f64,
+    heap:      BinaryHeap<ByDistance>,
+}
+
+impl Neighbors {
+    pub fn new(limit: usize, threshold: Option<f64>) -> Self {
+        Self {
+            limit,
+            threshold: threshold.unwrap_or(f64::INFINITY),
+            heap: BinaryHeap::with_capacity(limit.min(1024)),
+        }
+    }
+
+    pub fn push(&mut self, neighbor: Neighbor) {
+        if neighbor.distance.is_nan() || neighbor.distance > self.threshold || self.limit == 0 {
+            return;
+        }
+        let neighbor = ByDistance(neighbor);
+        if self.heap.len() < self.limit {
+            self.heap.push(neighbor);
+        } else if let Some(mut worst) = self.heap.peek_mut() {
+            if neighbor < *worst {
+                *worst = neighbor;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Selected entries, closest first.
+    pub fn into_sorted_vec(self) -> Vec<Neighbor> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|ByDistance(neighbor)| neighbor)
+            .collect()
+    }
+}
+
+/// Orders neighbors by distance, then index.
+struct ByDistance(Neighbor);
+
+impl Ord for ByDistance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .distance
+            .total_cmp(&other.0.distance)
+            .then(self.0.index.cmp(&other.0.index))
+    }
+}
+
+impl PartialOrd for ByDistance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ByDistance {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ByDistance {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    fn random_neighbors(n: usize) -> Vec<Neighbor> {
+        let mut rng = thread_rng();
+        (0..n)
+            .map(|index| Neighbor {
+                index,
+                distance: rng.gen_range(0..100) as f64 / 100.0,
+                rotation: rng.gen_range(-15..=15),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_top_k() {
+        let entries = random_neighbors(1000);
+        let mut neighbors = Neighbors::new(10, None);
+        for &entry in &entries {
+            neighbors.push(entry);
+        }
+        assert_eq!(neighbors.len(), 10);
+
+        let mut expected = entries.clone();
+        expected.sort_by(|a, b| {
+            a.distance
+                .total_cmp(&b.distance)
+                .then(a.index.cmp(&b.index))
+        });
+        expected.truncate(10);
+        assert_eq!(neighbors.into_sorted_vec(), expected);
+    }
+
+    #[test]
+    fn test_threshold() {
+        let entries = random_neighbors(1000);
+        let mut neighbors = Neighbors::new(usize::MAX, Some(0.3));
+        for &entry in &entries {
+            neighbors.push(entry);
+        }
+        let selected = neighbors.into_sorted_vec();
+        assert_eq!(
+            selected.len(),
+            entries.iter().filter(|e| e.distance <= 0.3).count()
+        );
+        assert!(selected.windows(2).all(|w| w[0].distance <= w[1].distance));
+        assert!(selected.iter().all(|e| e.distance <= 0.3));
+    }
+
+    #[test]
+    fn test_ignores_nan() {
+        let mut neighbors = Neighbors::new(1, None);
+        neighbors.push(Neighbor {
+            index:    0,
+            distance: f64::NAN,
+            rotation: 0,
+        });
+        assert!(neighbors.is_empty());
+    }
+}
@@ -0,0 +1,863 @@
+use crate::{
+    enroll,
+    format::{Kind, Records},
+    handshake::Announcement,
+    merkle::Hash,
+    protocol::{self, Channel, Frame, Message, Mux, ResultSink},
+    secure_threshold::{self, Comparisons, Slot},
+    shutdown::Signals,
+    tombstones,
+    triples::{add_products, Triples},
+    ParticipantArgs, MAX_QUERIES,
+};
+use anyhow::{bail, ensure, Result};
+use bytemuck::{bytes_of, bytes_of_mut, cast_slice, cast_slice_mut};
+use indicatif::{HumanBytes, HumanCount, MultiProgress, ProgressBar, ProgressStyle};
+use mpc_iris_code::{comparison, encode, DistanceEngine, EncodedBits, Template, BITS};
+use std::{
+    cmp::{max, min},
+    iter,
+    mem::size_of,
+    net::SocketAddr,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::BufReader,
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::{mpsc, watch, Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore},
+};
+
+/// Serves a share of the database to resolvers.
+///
+/// Every accepted connection carries concurrent requests, each evaluated in
+/// its own task against the records served when it arrived. Scans beyond the
+/// configured maximum wait for a turn, or are rejected when too many wait.
+///
+/// On SIGINT or SIGTERM it stops accepting connections and requests, and lets
+/// those in flight finish before exiting. Past the grace period they are
+/// cancelled, except for enrollments and deletions, which always complete
+/// their updates on disk.
+pub struct Participant {
+    input:       PathBuf,
+    masks_path:  Option<PathBuf>,
+    tombstones:  PathBuf,
+    legacy:      bool,
+    parts:       usize,
+    triples:     Option<Triples>,
+    comparisons: Option<Comparisons>,
+    count_style: ProgressStyle,
+    byte_style:  ProgressStyle,
+    /// Progress of the scans in flight.
+    progress:    MultiProgress,
+    admission:   Admission,
+    in_flight:   InFlight,
+    /// Set to close all connections, cancelling their requests.
+    cancel:      watch::Sender<bool>,
+    /// Time requests in flight are given to finish on shutdown.
+    grace:       Duration,
+
+    /// Records currently served, replaced after enrollment.
+    served:  RwLock<Served>,
+    /// Held while checking and consuming a slot of preprocessed material, so
+    /// concurrent requests can not use the same slot.
+    slots:   Mutex<()>,
+    /// Held while enrolling or deleting, which update files on disk.
+    updates: AsyncMutex<()>,
+}
+
+/// The records of every part, with their number.
+#[derive(Clone)]
+struct Served {
+    count: usize,
+    share: Arc<Records>,
+    masks: Option<Arc<Records>>,
+}
+
+impl Served {
+    fn announcement(&self) -> Announcement {
+        Announcement::new(self.count, &self.share, self.masks.as_deref())
+    }
+
+    /// Merkle roots of the share and the mask share, as acknowledged to
+    /// enrollments.
+    fn roots(&self) -> [Hash; 2] {
+        let mut roots = [Hash::default(); 2];
+        for (root, records) in roots
+            .iter_mut()
+            .zip(iter::once(&self.share).chain(&self.masks))
+        {
+            *root = records.root().unwrap_or_default();
+        }
+        roots
+    }
+}
+
+/// Limits the number of scans in flight, queueing or rejecting further ones.
+struct Admission {
+    /// A permit per scan in flight. Queued scans are admitted in order.
+    scans:      Arc<Semaphore>,
+    max_scans:  usize,
+    max_queued: usize,
+    queued:     AtomicUsize,
+}
+
+impl Admission {
+    fn new(max_scans: usize, max_queued: usize) -> Self {
+        Self {
+            scans: Arc::new(Semaphore::new(max_scans)),
+            max_scans,
+            max_queued,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for a scan to be admitted, unless the queue is full. The scan is
+    /// in flight until the permit is dropped.
+    async fn admit(&self) -> Result<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.scans.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        let queued = Queued::new(&self.queued);
+        if queued.position >= self.max_queued {
+            bail!(
+                "Participant is busy with {} scans and {} queued.",
+                self.max_scans,
+                self.max_queued
+            );
+        }
+        eprintln!("Scan queued behind {} others.", queued.position);
+        Ok(self.scans.clone().acquire_owned().await?)
+    }
+}
+
+/// Tracks the requests in flight, so shutdown can wait for them.
+struct InFlight {
+    /// Cloned for every request, `None` once new requests are refused.
+    sender:   Mutex<Option<mpsc::Sender<()>>>,
+    /// Closed once every request is done and new ones are refused.
+    receiver: AsyncMutex<mpsc::Receiver<()>>,
+}
+
+impl InFlight {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(1);
+        Self {
+            sender:   Mutex::new(Some(sender)),
+            receiver: AsyncMutex::new(receiver),
+        }
+    }
+
+    /// Count a request as in flight until the guard is dropped, unless new
+    /// requests are refused.
+    fn enter(&self) -> Option<mpsc::Sender<()>> {
+        self.sender.lock().unwrap().clone()
+    }
+
+    /// Refuse new requests.
+    fn close(&self) {
+        self.sender.lock().unwrap().take();
+    }
+
+    /// Wait for the requests in flight after [`Self::close`].
+    async fn finish(&self) {
+        // Nothing is sent, so this returns once all guards are dropped.
+        let _ = self.receiver.lock().await.recv().await;
+    }
+}
+
+/// A scan counted as queued until dropped, also when it stops waiting.
+struct Queued<'a> {
+    queued:   &'a AtomicUsize,
+    /// Number of scans queued before this one.
+    position: usize,
+}
+
+impl<'a> Queued<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        let position = queued.fetch_add(1, Ordering::Relaxed);
+        Self { queued, position }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Participant {
+    pub(crate) fn new(
+        args: &ParticipantArgs,
+        count_style: ProgressStyle,
+        byte_style: ProgressStyle,
+    ) -> Result<Self> {
+        if args.max_scans == 0 {
+            bail!("Maximum number of scans must be positive.");
+        }
+
+        // Read share as memory mapped file.
+        let size = HumanBytes(std::fs::metadata(&args.input)?.size());
+        // Records are verified lazily against the Merkle tree as they are scanned.
+        let share = Arc::new(Records::map(&args.input, Kind::Share, args.legacy)?);
+        let count = share.len();
+        eprintln!(
+            "Opened {}share {:?} with {} encrypted patterns ({})",
+            if share.is_seeded() { "seeded " } else { "" },
+            args.input,
+            HumanCount(count as u64),
+            size
+        );
+        let header = share.header().copied();
+        if let Some(header) = &header {
+            eprintln!(
+                "Share is for party {} of {} from dataset {} with Merkle root {}.",
+                header.party(),
+                header.parties(),
+                header.dataset(),
+                header.root()
+            );
+            if let Some(party) = args.party.filter(|&party| party != header.party()) {
+                bail!("Share is for party {}, expected {party}.", header.party());
+            }
+            if let Some(dataset) = args.dataset.filter(|&dataset| dataset != header.dataset()) {
+                bail!(
+                    "Share is from dataset {}, expected {dataset}.",
+                    header.dataset()
+                );
+            }
+        } else if args.party.is_some() || args.dataset.is_some() {
+            bail!("Legacy shares do not record their party and dataset.");
+        }
+
+        // Open mask shares, which form a second part of every record.
+        let masks = if let Some(path) = &args.masks {
+            let mmap = Arc::new(Records::map(path, Kind::MaskShare, args.legacy)?);
+            if mmap.len() != count {
+                bail!(
+                    "Mask share file {path:?} has {} records, expected {count}.",
+                    mmap.len()
+                );
+            }
+            if let (Some(share), Some(mask)) = (&header, mmap.header()) {
+                if (mask.party(), mask.dataset()) != (share.party(), share.dataset()) {
+                    bail!(
+                        "Mask share file {path:?} is for party {} from dataset {}, expected the \
+                         share's.",
+                        mask.party(),
+                        mask.dataset()
+                    );
+                }
+            }
+            eprintln!("Opened mask share {path:?}, computing denominators in MPC.");
+            Some(mmap)
+        } else {
+            None
+        };
+        let parts = if masks.is_some() { 2 } else { 1 };
+
+        // Open preprocessed triples for secret-shared queries.
+        let triples = args
+            .triples
+            .as_deref()
+            .map(|path| Triples::open(path, count, parts))
+            .transpose()?;
+        if let Some(triples) = &triples {
+            eprintln!(
+                "Opened triples {:?} with {} slots, only accepting secret-shared queries.",
+                args.triples.as_ref().unwrap(),
+                triples.slots()
+            );
+        }
+
+        // Open preprocessed material for secure comparisons.
+        let comparisons = args
+            .comparisons
+            .as_deref()
+            .map(|path| Comparisons::open(path, count, masks.is_some()))
+            .transpose()?;
+        if let (Some(comparisons), Some(header)) = (&comparisons, &header) {
+            if comparisons.party() != header.party() {
+                bail!(
+                    "Comparisons are for party {}, the share for party {}.",
+                    comparisons.party(),
+                    header.party()
+                );
+            }
+        }
+        if let Some(comparisons) = &comparisons {
+            eprintln!(
+                "Opened comparisons {:?} for party {} with {} slots, only revealing matches.",
+                args.comparisons.as_ref().unwrap(),
+                comparisons.party(),
+                comparisons.slots()
+            );
+        }
+
+        // Deleted entries are recorded for compaction.
+        let tombstones = args.tombstones.clone().unwrap_or_else(|| {
+            let mut path = args.input.clone().into_os_string();
+            path.push(".tombstones");
+            path.into()
+        });
+
+        Ok(Self {
+            input: args.input.clone(),
+            masks_path: args.masks.clone(),
+            tombstones,
+            legacy: args.legacy,
+            parts,
+            triples,
+            comparisons,
+            count_style,
+            byte_style,
+            progress: MultiProgress::new(),
+            admission: Admission::new(args.max_scans, args.max_queued),
+            in_flight: InFlight::new(),
+            cancel: watch::Sender::new(false),
+            grace: Duration::from_millis(args.shutdown_grace),
+            served: RwLock::new(Served {
+                count,
+                share,
+                masks,
+            }),
+            slots: Mutex::new(()),
+            updates: AsyncMutex::new(()),
+        })
+    }
+
+    /// Accept connections from resolvers and serve their requests until
+    /// shut down by `signals`. Fails if requests had to be cancelled.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, mut signals: Signals) -> Result<()> {
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = accepted?;
+                    eprintln!("Inbound from {peer:?}");
+                    tokio::spawn(self.clone().connection(stream, peer));
+                }
+                name = signals.recv() => {
+                    eprintln!("Received {name}, shutting down.");
+                    break;
+                }
+            }
+        }
+        drop(listener);
+
+        // Let requests in flight finish, or cancel them. Enrollments and
+        // deletions do not wait on the resolver, so they still complete.
+        self.in_flight.close();
+        if signals.drain(self.grace, self.in_flight.finish()).await {
+            eprintln!("All requests finished.");
+            return Ok(());
+        }
+        self.cancel.send_replace(true);
+        self.in_flight.finish().await;
+        bail!("Cancelled requests in flight on shutdown.");
+    }
+
+    async fn connection(self: Arc<Self>, mut stream: TcpStream, peer: SocketAddr) {
+        // Announce the records and the data they are from.
+        let announced = async {
+            // Frames are flushed whole, so they are sent without waiting for more.
+            stream.set_nodelay(true)?;
+            self.served().announcement().write(&mut stream).await
+        };
+        if let Err(err) = announced.await {
+            eprintln!("Handshake with {peer:?} failed: {err:#}");
+            return;
+        }
+        let (mux, reader) = Mux::new(stream);
+        let mut cancel = self.cancel.subscribe();
+        let err = tokio::select! {
+            result = self.dispatch(&mux, reader) => result.unwrap_err(),
+            _ = cancel.wait_for(|&cancel| cancel) => anyhow::format_err!("Shutting down."),
+        };
+        mux.close();
+        eprintln!("Connection from {peer:?} closed: {err:#}");
+    }
+
+    /// Start a task for every request received on the connection, and pass
+    /// further frames to the requests they belong to.
+    async fn dispatch(
+        self: &Arc<Self>,
+        mux: &Arc<Mux>,
+        mut reader: BufReader<OwnedReadHalf>,
+    ) -> Result<()> {
+        loop {
+            let (request, frame) = protocol::read_frame(&mut reader).await?;
+            // Frames of finished requests, e.g. late credit, are dropped.
+            let Some(frame) = mux.dispatch(request, frame) else {
+                continue;
+            };
+            if frame.message.is_request() {
+                let channel = mux.register(request)?;
+                let Some(in_flight) = self.in_flight.enter() else {
+                    let err = anyhow::format_err!("Participant is shutting down.");
+                    channel.send_error(&err).await?;
+                    continue;
+                };
+                let participant = self.clone();
+                tokio::spawn(async move {
+                    participant.request(channel, frame).await;
+                    drop(in_flight);
+                });
+            }
+        }
+    }
+
+    async fn request(self: Arc<Self>, mut channel: Channel, frame: Frame) {
+        let message = frame.message;
+        let result = match message {
+            Message::Status => self.status(&channel).await,
+            Message::Enroll => self.enroll(&channel, frame.payload).await,
+            Message::Delete => self.delete(&channel, &frame.payload).await,
+            Message::Truncate => self.truncate(&channel, &frame).await,
+            _ => self.query(&mut channel, frame).await,
+        };
+        // The resolver may have hung up or cancelled the request already.
+        if let Err(err) = result {
+            if channel.is_cancelled() {
+                eprintln!("Cancelled {message} request {}.", channel.request());
+                return;
+            }
+            eprintln!("Failed {message} request {}: {err:#}", channel.request());
+            if let Err(err) = channel.send_error(&err).await {
+                eprintln!("Could not report the failure: {err:#}");
+            }
+        }
+    }
+
+    fn served(&self) -> Served {
+        self.served.read().unwrap().clone()
+    }
+
+    async fn status(&self, channel: &Channel) -> Result<()> {
+        let announcement = self.served().announcement();
+        channel
+            .send(Message::Announce, &[bytes_of(&announcement)])
+            .await
+    }
+
+    /// Append enrolled records and serve them from the next request on.
+    async fn enroll(&self, channel: &Channel, payload: Vec<u8>) -> Result<()> {
+        ensure!(
+            self.triples.is_none() && self.comparisons.is_none(),
+            "Enrollment is not possible with preprocessed material, which is sized for the \
+             current records."
+        );
+        let _update = self.updates.lock().await;
+        let served = self.served();
+        ensure!(
+            !served.share.is_seeded() && !served.masks.as_ref().is_some_and(|m| m.is_seeded()),
+            "Seeded shares can not be extended without re-sharing the entries."
+        );
+        let paths = iter::once(self.input.as_path())
+            .chain(self.masks_path.as_deref())
+            .collect::<Vec<_>>();
+        let (count, roots) = enroll::append(payload, &paths, served.count, self.legacy).await?;
+
+        // Serve the new records before acknowledging them, so the resolver's
+        // next query sees them.
+        let share = Arc::new(Records::map(&self.input, Kind::Share, self.legacy)?);
+        let masks = self
+            .masks_path
+            .as_deref()
+            .map(|path| Records::map(path, Kind::MaskShare, self.legacy).map(Arc::new))
+            .transpose()?;
+        *self.served.write().unwrap() = Served {
+            count,
+            share,
+            masks,
+        };
+        eprintln!(
+            "Enrolled {} records, {count} in total.",
+            count - served.count
+        );
+        let total = count as u64;
+        channel
+            .send(Message::Enrolled, &[bytes_of(&total), bytes_of(&roots)])
+            .await
+    }
+
+    /// Drop the records beyond those to keep, enrolled by an enrollment that
+    /// failed on other participants, and serve the rest from the next request
+    /// on.
+    async fn truncate(&self, channel: &Channel, frame: &Frame) -> Result<()> {
+        let mut total = 0_u64;
+        frame.read_into(bytes_of_mut(&mut total))?;
+        let count = total as usize;
+        let _update = self.updates.lock().await;
+        let served = self.served();
+        ensure!(
+            count <= served.count,
+            "Can not truncate {} records to {count}.",
+            served.count
+        );
+        let roots = if count < served.count {
+            ensure!(
+                self.triples.is_none() && self.comparisons.is_none(),
+                "Preprocessed material is sized for the current records, which can not be \
+                 truncated."
+            );
+            ensure!(
+                !served.share.is_seeded() && !served.masks.as_ref().is_some_and(|m| m.is_seeded()),
+                "Seeded shares can not be truncated."
+            );
+            let paths = iter::once((self.input.as_path(), Kind::Share))
+                .chain(
+                    self.masks_path
+                        .as_deref()
+                        .map(|path| (path, Kind::MaskShare)),
+                )
+                .collect::<Vec<_>>();
+            let roots = enroll::truncate(&paths, count, self.legacy).await?;
+            let share = Arc::new(Records::map(&self.input, Kind::Share, self.legacy)?);
+            let masks = self
+                .masks_path
+                .as_deref()
+                .map(|path| Records::map(path, Kind::MaskShare, self.legacy).map(Arc::new))
+                .transpose()?;
+            *self.served.write().unwrap() = Served {
+                count,
+                share,
+                masks,
+            };
+            eprintln!(
+                "Rolled back {} records, {count} in total.",
+                served.count - count
+            );
+            roots
+        } else {
+            served.roots()
+        };
+        channel
+            .send(Message::Enrolled, &[bytes_of(&total), bytes_of(&roots)])
+            .await
+    }
+
+    /// Record deleted entries, which the resolver excludes from results.
+    async fn delete(&self, channel: &Channel, payload: &[u8]) -> Result<()> {
+        let _update = self.updates.lock().await;
+        let total = tombstones::receive(
+            channel,
+            payload,
+            &self.tombstones,
+            self.served().count,
+            size_of::<EncodedBits>(),
+        )
+        .await?;
+        eprintln!("Deletion recorded, {total} in total.");
+        Ok(())
+    }
+
+    /// Take what a request needs from a slot of preprocessed material and
+    /// mark the slot used. Runs on a blocking thread, as marking it syncs the
+    /// file.
+    async fn use_slot<T: Send + 'static>(
+        self: &Arc<Self>,
+        take: impl FnOnce(&Self) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let participant = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _slots = participant.slots.lock().unwrap();
+            take(&participant)
+        })
+        .await?
+    }
+
+    async fn query(self: &Arc<Self>, channel: &mut Channel, frame: Frame) -> Result<()> {
+        // Wait for a turn, so the resolver only learns the records once the
+        // scan starts. The resolver may give up in the meantime.
+        let _scan = tokio::select! {
+            scan = self.admission.admit() => scan?,
+            frame = channel.receive() => {
+                bail!("Received a {} frame before the scan started.", frame?.message)
+            }
+        };
+
+        // Announce the records the query is evaluated against.
+        let served = self.served();
+        let announcement = served.announcement();
+        channel
+            .send(Message::Announce, &[bytes_of(&announcement)])
+            .await?;
+        let (count, parts) = (served.count, self.parts);
+        let length = frame.payload.len();
+
+        // Read request, the queries for each part.
+        let (queries, query, products) = match (frame.message, &self.triples) {
+            (Message::SharedQuery, Some(triples)) => {
+                // Secret-shared query, open it masked with the slot's random masks.
+                let mut slot = 0_u64;
+                let mut shares = vec![EncodedBits::default(); parts];
+                ensure!(
+                    length == size_of::<u64>() + parts * size_of::<EncodedBits>(),
+                    "Secret-shared query of {length} bytes, expected a slot and {parts} shares."
+                );
+                frame.read_parts(&mut [bytes_of_mut(&mut slot), cast_slice_mut(&mut shares)])?;
+                let slot = slot as usize;
+                let masked = self
+                    .use_slot(move |participant| {
+                        let triples = participant.triples.as_ref().unwrap();
+                        let masked = shares
+                            .iter()
+                            .zip(triples.mask_shares(slot)?.iter())
+                            .map(|(share, mask)| *share - mask)
+                            .collect::<Vec<_>>();
+                        triples.consume(slot)?;
+                        Ok(masked)
+                    })
+                    .await?;
+                channel
+                    .send(Message::Masked, &[cast_slice(&masked)])
+                    .await?;
+                let mut masked = vec![EncodedBits::default(); parts];
+                channel
+                    .receive_exact(Message::Opened, cast_slice_mut(&mut masked))
+                    .await?;
+                eprintln!("Secret-shared request received using slot {slot}.");
+                let query = masked.into_iter().map(|part| vec![part]).collect();
+                (1, query, Some(triples.products(slot)))
+            }
+            (Message::Query, None) => {
+                let queries = length / size_of::<Template>();
+                if !length.is_multiple_of(size_of::<Template>())
+                    || queries == 0
+                    || queries > MAX_QUERIES
+                {
+                    bail!("Query of {length} bytes, expected 1 to {MAX_QUERIES} templates.");
+                }
+                ensure!(
+                    queries == 1 || self.comparisons.is_none(),
+                    "Preprocessed material only supports single queries."
+                );
+                let mut templates = vec![Template::default(); queries];
+                frame.read_into(cast_slice_mut(&mut templates))?;
+                eprintln!("Request received with {queries} queries.");
+                let patterns = templates.iter().map(encode).collect::<Vec<_>>();
+                let masks = templates
+                    .iter()
+                    .map(|t| EncodedBits::from(&t.mask))
+                    .collect();
+                let query: Vec<Vec<EncodedBits>> = if parts == 2 {
+                    vec![patterns, masks]
+                } else {
+                    vec![patterns]
+                };
+                (queries, query, None)
+            }
+            (Message::Query, Some(_)) => bail!("Only secret-shared queries are accepted."),
+            (request, _) => bail!("Unexpected {request} request."),
+        };
+
+        // Secure comparison material
+        let comparison = if let Some(comparisons) = &self.comparisons {
+            let mut compare = [0_u64; 3];
+            channel
+                .receive_exact(Message::Compare, cast_slice_mut(&mut compare))
+                .await?;
+            let [slot, p, q] = compare;
+            ensure!(
+                q > 0 && q <= BITS as u64 && p <= 2 * q,
+                "Threshold fraction {p} / {q} out of range."
+            );
+            let (slot, fraction) = (slot as usize, (p as u32, q as u32));
+            let seed = self
+                .use_slot(move |participant| {
+                    let comparisons = participant.comparisons.as_ref().unwrap();
+                    let seed = comparisons.seed(slot)?;
+                    comparisons.consume(slot)?;
+                    Ok(seed)
+                })
+                .await?;
+            eprintln!("Comparing using slot {slot}.");
+            Some(Slot {
+                seed,
+                corrections: comparisons.corrections(slot),
+                fraction,
+            })
+        } else {
+            None
+        };
+
+        // Process in worker thread
+        // Results are interleaved per record and query, one for each part.
+        // Every batch is queued on the rayon pool behind those of other scans,
+        // which take turns as their batches are of about equal work.
+        // The worker checks for cancellation before every batch, as it only
+        // notices a hang up once its next batch is sent.
+        let (sender, mut receiver) = mpsc::channel(4);
+        let cancelled = channel.cancelled();
+        let mmaps = iter::once(served.share)
+            .chain(served.masks)
+            .collect::<Vec<_>>();
+        let worker = tokio::task::spawn_blocking(move || {
+            // Batches must align with those of the comparison protocol.
+            let batch_size = max(comparison::BATCH / queries, 1);
+            let engines = query
+                .iter()
+                .map(|part| DistanceEngine::new_batch(part))
+                .collect::<Vec<_>>();
+            for start in (0..count).step_by(batch_size) {
+                ensure!(!cancelled.load(Ordering::Relaxed), "Request cancelled.");
+                let end = min(start + batch_size, count);
+                let mut result = vec![[0_u16; 31]; (end - start) * queries * parts];
+                let mut part_result = vec![[0_u16; 31]; (end - start) * queries];
+                for (part, (engine, mmap)) in engines.iter().zip(mmaps.iter()).enumerate() {
+                    // Records are verified against the Merkle tree on first use, or
+                    // expanded from the seed.
+                    mmap.process(engine, &mut part_result, start..end)?;
+                    for (i, r) in part_result.iter().enumerate() {
+                        result[i * parts + part] = *r;
+                    }
+                }
+                if let Some((products, range)) = &products {
+                    let start = range.start + start * parts * size_of::<[u16; 31]>();
+                    let end = start + result.len() * size_of::<[u16; 31]>();
+                    add_products(&mut result, &products[start..end]);
+                }
+                sender.blocking_send(result)?;
+            }
+            Result::<_>::Ok(())
+        });
+
+        // Evaluate comparisons on the batches
+        if let Some(slot) = comparison {
+            let leader = self.comparisons.as_ref().unwrap().party() == 0;
+            let progress_bar = self
+                .progress
+                .add(ProgressBar::new(count as u64).with_style(self.count_style.clone()));
+            let mut start = 0;
+            while let Some(result) = receiver.recv().await {
+                let len = result.len() / parts;
+                let (numerators, denominators) = if parts == 2 {
+                    let (numerators, denominators) = result.chunks(2).map(|r| (r[0], r[1])).unzip();
+                    (numerators, Some(denominators))
+                } else {
+                    (result, None)
+                };
+                secure_threshold::participate(
+                    channel,
+                    leader,
+                    slot.clone(),
+                    start,
+                    numerators,
+                    denominators,
+                )
+                .await?;
+                start += len;
+                progress_bar.inc(len as u64);
+            }
+            progress_bar.finish();
+            worker.await??;
+            channel
+                .send(Message::End, &[bytes_of(&(count as u64))])
+                .await?;
+            eprintln!("Comparisons done.");
+            return Ok(());
+        }
+
+        // Stream output, a frame per batch starting at its first record.
+        let progress_bar = self.progress.add(
+            ProgressBar::new((count * queries * parts * size_of::<[u16; 31]>()) as u64)
+                .with_style(self.byte_style.clone()),
+        );
+        let mut sink = ResultSink::new(channel, queries * parts);
+        let mut sent = Ok(());
+        while let Some(result) = receiver.recv().await {
+            sent = sink.send(&result).await;
+            if sent.is_err() {
+                // Stops the worker at its next batch.
+                break;
+            }
+            progress_bar.inc((result.len() * size_of::<[u16; 31]>()) as u64);
+        }
+        progress_bar.finish();
+        drop(receiver);
+        if let Err(err) = sent {
+            let _ = worker.await?;
+            return Err(err);
+        }
+        worker.await??;
+        sink.finish().await?;
+        eprintln!("Reply sent.");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown;
+    use std::future::pending;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn test_admission() {
+        let admission = Arc::new(Admission::new(2, 2));
+        let mut running = vec![
+            admission.admit().await.unwrap(),
+            admission.admit().await.unwrap(),
+        ];
+
+        // Further scans queue, each reporting when it is admitted.
+        let (sender, mut admitted) = mpsc::unbounded_channel();
+        for scan in 0..2 {
+            let (queue, sender) = (admission.clone(), sender.clone());
+            tokio::spawn(async move {
+                let permit = queue.admit().await.unwrap();
+                sender.send((scan, permit)).unwrap();
+            });
+            while admission.queued.load(Ordering::Relaxed) <= scan {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        // Beyond the queue they are rejected.
+        let err = admission.admit().await.err().unwrap();
+        assert!(err.to_string().contains("busy"), "{err}");
+
+        // Queued scans are admitted in order, as permits are returned.
+        running.pop();
+        let (scan, permit) = admitted.recv().await.unwrap();
+        assert_eq!(scan, 0);
+        running.push(permit);
+        assert!(admitted.try_recv().is_err());
+        running.clear();
+        let (scan, _permit) = admitted.recv().await.unwrap();
+        assert_eq!(scan, 1);
+
+        // With the queue drained, scans are admitted right away.
+        assert_eq!(admission.queued.load(Ordering::Relaxed), 0);
+        assert!(admission.admit().await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_in_flight() {
+        let grace = Duration::from_secs(10);
+        for (seconds, finished) in [(3, true), (30, false)] {
+            // A request is running when new ones are refused.
+            let in_flight = InFlight::new();
+            let request = in_flight.enter().unwrap();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(seconds)).await;
+                drop(request);
+            });
+            in_flight.close();
+            assert!(in_flight.enter().is_none());
+
+            // Draining returns once it finishes, or gives up after the grace
+            // period.
+            let start = Instant::now();
+            let drained = shutdown::drain(grace, in_flight.finish(), pending()).await;
+            assert_eq!(drained, finished);
+            assert_eq!(start.elapsed(), Duration::from_secs(seconds).min(grace));
+        }
+    }
+}
@@ -0,0 +1,865 @@
+use crate::{handshake::Announcement, merkle::Hash, MAX_ENROLL, MAX_QUERIES};
+use anyhow::{bail, ensure, format_err, Result};
+use bytemuck::{bytes_of, bytes_of_mut, cast_slice, cast_slice_mut, Pod, Zeroable};
+use mpc_iris_code::{comparison::BATCH, EncodedBits, Template};
+use std::{
+    cmp::min,
+    collections::HashMap,
+    fmt::{self, Display},
+    mem::{replace, size_of},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{tcp::OwnedReadHalf, TcpStream},
+    runtime::Handle,
+    sync::{mpsc, Mutex as AsyncMutex},
+};
+
+/// Version of the protocol between resolver and participants, announced by
+/// participants when they accept a connection.
+pub const VERSION: u32 = 4;
+
+/// Largest payload of an error frame.
+const MAX_ERROR: usize = 1 << 16;
+
+/// Largest message of the comparison protocol, the masked differences and
+/// denominators of a batch. The scaled differences take as many bytes.
+const MAX_EXCHANGE: usize = 2 * BATCH * size_of::<[u16; 31]>();
+
+/// Bytes reserved for a payload before it arrives. Larger payloads grow as
+/// they are read, so a peer can not make the receiver allocate a length it
+/// does not send.
+const INITIAL_PAYLOAD: usize = 1 << 20;
+
+/// Results frames a participant sends ahead of the resolver's credit.
+const WINDOW: u64 = 4;
+
+/// Type of a frame's payload.
+///
+/// After the handshake, resolver and participant only exchange frames. Every
+/// frame belongs to a request, identified by the resolver, and frames of
+/// concurrent requests are interleaved. Each request is answered with the
+/// frames of its response, or an error frame at any point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// Plaintext queries, as packed templates.
+    Query,
+    /// Secret-shared query: the slot of triples to use, then a share per part.
+    SharedQuery,
+    /// A participant's shares of the query masked with the slot's triples.
+    Masked,
+    /// The opened masked query, sent back to the participants.
+    Opened,
+    /// Slot of comparisons to use and the match threshold as a fraction
+    /// `[slot, p, q]`, following the query.
+    Compare,
+    /// A message of the comparison protocol, in either direction.
+    Exchange,
+    /// Results for consecutive records: the index of the first record, then
+    /// the results of every record.
+    Results,
+    /// End of the response, with the number of records evaluated.
+    End,
+    /// New records of every part, one part after the other.
+    Enroll,
+    /// New number of records after enrollment or truncation, then the Merkle
+    /// roots.
+    Enrolled,
+    /// Records per entry, then the indices of the entries to delete.
+    Delete,
+    /// Number of entries deleted in total.
+    Deleted,
+    /// Failure of the request, described in UTF-8.
+    Error,
+    /// Request for the participant's current announcement.
+    Status,
+    /// The participant's announcement, answering a status request and
+    /// preceding the response to every query.
+    Announce,
+    /// Number of further results frames the resolver is ready to receive.
+    Credit,
+    /// The resolver no longer needs the response. Ignored for requests that
+    /// are finished.
+    Cancel,
+    /// Number of records to keep, rolling back an enrollment that failed on
+    /// other participants.
+    Truncate,
+}
+
+impl Message {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::Query),
+            2 => Some(Self::SharedQuery),
+            3 => Some(Self::Masked),
+            4 => Some(Self::Opened),
+            5 => Some(Self::Compare),
+            6 => Some(Self::Exchange),
+            7 => Some(Self::Results),
+            8 => Some(Self::End),
+            9 => Some(Self::Enroll),
+            10 => Some(Self::Enrolled),
+            11 => Some(Self::Delete),
+            12 => Some(Self::Deleted),
+            13 => Some(Self::Error),
+            14 => Some(Self::Status),
+            15 => Some(Self::Announce),
+            16 => Some(Self::Credit),
+            17 => Some(Self::Cancel),
+            18 => Some(Self::Truncate),
+            _ => None,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            Self::Query => 1,
+            Self::SharedQuery => 2,
+            Self::Masked => 3,
+            Self::Opened => 4,
+            Self::Compare => 5,
+            Self::Exchange => 6,
+            Self::Results => 7,
+            Self::End => 8,
+            Self::Enroll => 9,
+            Self::Enrolled => 10,
+            Self::Delete => 11,
+            Self::Deleted => 12,
+            Self::Error => 13,
+            Self::Status => 14,
+            Self::Announce => 15,
+            Self::Credit => 16,
+            Self::Cancel => 17,
+            Self::Truncate => 18,
+        }
+    }
+
+    /// Whether the message ends the response to a request.
+    fn is_final(self) -> bool {
+        matches!(
+            self,
+            Self::End | Self::Enrolled | Self::Deleted | Self::Error | Self::Cancel
+        )
+    }
+
+    /// Largest payload of a frame of this type.
+    fn max_payload(self) -> usize {
+        let parts = 2 * size_of::<EncodedBits>();
+        match self {
+            Self::Query => MAX_QUERIES * size_of::<Template>(),
+            Self::SharedQuery => size_of::<u64>() + parts,
+            Self::Masked | Self::Opened => parts,
+            Self::Compare => size_of::<[u64; 3]>(),
+            Self::Exchange => MAX_EXCHANGE,
+            // Batches of records are aligned with those of the comparisons.
+            Self::Results => size_of::<u64>() + MAX_EXCHANGE,
+            Self::Enroll => MAX_ENROLL * parts,
+            Self::Enrolled => size_of::<u64>() + size_of::<[Hash; 2]>(),
+            Self::Delete => (1 + MAX_ENROLL) * size_of::<u64>(),
+            Self::End | Self::Deleted | Self::Credit | Self::Truncate => size_of::<u64>(),
+            Self::Error => MAX_ERROR,
+            Self::Status | Self::Cancel => 0,
+            Self::Announce => size_of::<Announcement>(),
+        }
+    }
+
+    /// Whether the message starts a new request.
+    pub fn is_request(self) -> bool {
+        matches!(
+            self,
+            Self::Query
+                | Self::SharedQuery
+                | Self::Enroll
+                | Self::Delete
+                | Self::Status
+                | Self::Truncate
+        )
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Query => "query",
+            Self::SharedQuery => "shared query",
+            Self::Masked => "masked",
+            Self::Opened => "opened",
+            Self::Compare => "compare",
+            Self::Exchange => "exchange",
+            Self::Results => "results",
+            Self::End => "end",
+            Self::Enroll => "enroll",
+            Self::Enrolled => "enrolled",
+            Self::Delete => "delete",
+            Self::Deleted => "deleted",
+            Self::Error => "error",
+            Self::Status => "status",
+            Self::Announce => "announce",
+            Self::Credit => "credit",
+            Self::Cancel => "cancel",
+            Self::Truncate => "truncate",
+        })
+    }
+}
+
+/// Precedes the payload of every frame, in native byte order like the
+/// payloads.
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+struct FrameHeader {
+    message:  u32,
+    reserved: u32,
+    /// Request the frame belongs to.
+    request:  u64,
+    /// Length of the payload in bytes.
+    length:   u64,
+}
+
+/// A received frame.
+pub struct Frame {
+    pub message: Message,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Copy the payload into `buffer`, which it must fill exactly.
+    pub fn read_into(&self, buffer: &mut [u8]) -> Result<()> {
+        ensure!(
+            self.payload.len() == buffer.len(),
+            "Expected a {} frame of {} bytes, received {} bytes.",
+            self.message,
+            buffer.len(),
+            self.payload.len()
+        );
+        buffer.copy_from_slice(&self.payload);
+        Ok(())
+    }
+
+    /// Copy the payload into consecutive `buffers`, which it must fill
+    /// exactly.
+    pub fn read_parts(&self, buffers: &mut [&mut [u8]]) -> Result<()> {
+        let expected = buffers.iter().map(|buffer| buffer.len()).sum::<usize>();
+        ensure!(
+            self.payload.len() == expected,
+            "Expected a {} frame of {expected} bytes, received {} bytes.",
+            self.message,
+            self.payload.len()
+        );
+        let mut rest = &self.payload[..];
+        for buffer in buffers {
+            let (part, tail) = rest.split_at(buffer.len());
+            buffer.copy_from_slice(part);
+            rest = tail;
+        }
+        Ok(())
+    }
+}
+
+/// Read the next frame, returning the request it belongs to.
+pub async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(u64, Frame)> {
+    let mut header = FrameHeader::zeroed();
+    stream.read_exact(bytes_of_mut(&mut header)).await?;
+    let Some(message) = Message::from_u32(header.message) else {
+        bail!("Received a frame of unknown type {}.", header.message);
+    };
+    let length = header.length;
+    let max = message.max_payload();
+    ensure!(
+        length <= max as u64,
+        "Received a {message} frame of {length} bytes, at most {max} allowed."
+    );
+    let mut payload = Vec::with_capacity(min(length as usize, INITIAL_PAYLOAD));
+    stream.take(length).read_to_end(&mut payload).await?;
+    ensure!(
+        payload.len() as u64 == length,
+        "Connection closed after {} of {length} bytes of a {message} frame.",
+        payload.len()
+    );
+    Ok((header.request, Frame { message, payload }))
+}
+
+/// Write a frame whose payload is the concatenation of `payload`.
+async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: u64,
+    message: Message,
+    payload: &[&[u8]],
+) -> Result<()> {
+    let header = FrameHeader {
+        message: message.to_u32(),
+        reserved: 0,
+        request,
+        length: payload.iter().map(|part| part.len() as u64).sum(),
+    };
+    stream.write_all(bytes_of(&header)).await?;
+    for part in payload {
+        stream.write_all(part).await?;
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+/// One end of a connection carrying concurrent requests.
+///
+/// Frames are written whole, so those of different requests interleave.
+/// Received frames are dispatched to the channel of their request by
+/// whoever reads the connection.
+pub struct Mux {
+    writer:   AsyncMutex<BufWriter<Box<dyn AsyncWrite + Send + Unpin>>>,
+    /// Open requests, `None` once the connection is closed.
+    requests: Mutex<Option<HashMap<u64, Request>>>,
+    /// Identifier of the next request opened on this end.
+    next:     AtomicU64,
+}
+
+/// Where the frames of an open request go.
+struct Request {
+    frames:    mpsc::UnboundedSender<Frame>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Mux {
+    /// Multiplex requests on `stream` after the handshake. Returns the
+    /// reading half, whose frames are to be dispatched.
+    pub fn new(stream: TcpStream) -> (Arc<Self>, BufReader<OwnedReadHalf>) {
+        let (reader, writer) = stream.into_split();
+        (Self::with_writer(writer), BufReader::new(reader))
+    }
+
+    /// Multiplex requests written to `writer`, whose peer's frames are to be
+    /// dispatched by reading the other half of the connection.
+    pub fn with_writer(writer: impl AsyncWrite + Send + Unpin + 'static) -> Arc<Self> {
+        Arc::new(Self {
+            writer:   AsyncMutex::new(BufWriter::new(Box::new(writer))),
+            requests: Mutex::new(Some(HashMap::new())),
+            next:     AtomicU64::new(0),
+        })
+    }
+
+    /// Open a channel for a new request. Dropping it before the response is
+    /// complete cancels the request, see [`Channel::complete`].
+    pub fn open(self: &Arc<Self>) -> Result<Channel> {
+        let request = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut channel = self.register(request)?;
+        channel.cancel_on_drop = true;
+        Ok(channel)
+    }
+
+    /// Open the channel of a request started by the peer.
+    pub fn register(self: &Arc<Self>, request: u64) -> Result<Channel> {
+        let (sender, frames) = mpsc::unbounded_channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut requests = self.requests.lock().unwrap();
+        let Some(requests) = requests.as_mut() else {
+            bail!("Connection closed.");
+        };
+        ensure!(
+            !requests.contains_key(&request),
+            "Request {request} is already open."
+        );
+        requests.insert(request, Request {
+            frames:    sender,
+            cancelled: cancelled.clone(),
+        });
+        Ok(Channel {
+            request,
+            mux: self.clone(),
+            frames,
+            cancelled,
+            cancel_on_drop: false,
+        })
+    }
+
+    /// Pass a received frame to the channel of its request, or return it if
+    /// the request is not open. Cancellation is flagged right away.
+    pub fn dispatch(&self, request: u64, frame: Frame) -> Option<Frame> {
+        let requests = self.requests.lock().unwrap();
+        match requests
+            .as_ref()
+            .and_then(|requests| requests.get(&request))
+        {
+            Some(open) => {
+                if frame.message == Message::Cancel {
+                    open.cancelled.store(true, Ordering::Relaxed);
+                }
+                // The request may have finished in the meantime.
+                let _ = open.frames.send(frame);
+                None
+            }
+            None => Some(frame),
+        }
+    }
+
+    /// Dispatch the frames read from `reader` until the connection fails,
+    /// dropping those of requests that are not open. Closes all channels
+    /// when done.
+    pub async fn receive<R: AsyncRead + Unpin>(&self, mut reader: R) -> Result<()> {
+        let result = async {
+            loop {
+                let (request, frame) = read_frame(&mut reader).await?;
+                self.dispatch(request, frame);
+            }
+        }
+        .await;
+        self.close();
+        result
+    }
+
+    /// Close all channels, after the connection failed, which cancels their
+    /// requests.
+    pub fn close(&self) {
+        for open in self.requests.lock().unwrap().take().into_iter().flatten() {
+            open.1.cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.requests.lock().unwrap().is_none()
+    }
+
+    async fn write(&self, request: u64, message: Message, payload: &[&[u8]]) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        write_frame(&mut *writer, request, message, payload).await
+    }
+}
+
+/// The frames of one request on a multiplexed connection.
+pub struct Channel {
+    request:        u64,
+    mux:            Arc<Mux>,
+    frames:         mpsc::UnboundedReceiver<Frame>,
+    /// Set once the peer cancels the request or the connection is closed.
+    cancelled:      Arc<AtomicBool>,
+    cancel_on_drop: bool,
+}
+
+impl Channel {
+    pub fn request(&self) -> u64 {
+        self.request
+    }
+
+    /// Flag set once the peer cancels the request or the connection is
+    /// closed, for blocking work that can not wait on the channel.
+    pub fn cancelled(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Mark the response as complete, so dropping the channel no longer
+    /// cancels the request. Done on receiving the last frame of a response,
+    /// except for the announcement answering a status request.
+    pub fn complete(&mut self) {
+        self.cancel_on_drop = false;
+    }
+
+    /// Send a frame whose payload is the concatenation of `payload`.
+    pub async fn send(&self, message: Message, payload: &[&[u8]]) -> Result<()> {
+        self.mux.write(self.request, message, payload).await
+    }
+
+    /// Report the failure of the request to the peer.
+    pub async fn send_error(&self, err: &anyhow::Error) -> Result<()> {
+        // Truncation may split a character, which is read lossily.
+        let description = format!("{err:#}");
+        let end = description.len().min(MAX_ERROR);
+        self.send(Message::Error, &[&description.as_bytes()[..end]])
+            .await
+    }
+
+    /// Receive the next frame of the request. Error frames and cancellation
+    /// are returned as errors.
+    pub async fn receive(&mut self) -> Result<Frame> {
+        let frame = self
+            .frames
+            .recv()
+            .await
+            .ok_or_else(|| format_err!("Connection closed."))?;
+        if frame.message.is_final() {
+            self.complete();
+        }
+        match frame.message {
+            Message::Error => bail!("{}", String::from_utf8_lossy(&frame.payload)),
+            Message::Cancel => bail!("Request cancelled."),
+            _ => Ok(frame),
+        }
+    }
+
+    /// Receive a frame of `message`.
+    pub async fn expect(&mut self, message: Message) -> Result<Frame> {
+        let frame = self.receive().await?;
+        ensure!(
+            frame.message == message,
+            "Expected a {message} frame, received a {} frame.",
+            frame.message
+        );
+        Ok(frame)
+    }
+
+    /// Receive a frame of `message` whose payload exactly fills `buffer`.
+    pub async fn receive_exact(&mut self, message: Message, buffer: &mut [u8]) -> Result<()> {
+        self.expect(message).await?.read_into(buffer)
+    }
+
+    /// Receive the end of a response, which must be for exactly `count`
+    /// records.
+    pub async fn receive_end(&mut self, count: usize) -> Result<()> {
+        let mut total = 0_u64;
+        self.receive_exact(Message::End, bytes_of_mut(&mut total))
+            .await?;
+        ensure!(
+            total as usize == count,
+            "Participant evaluated {total} records, expected {count}."
+        );
+        Ok(())
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        if let Some(requests) = self.mux.requests.lock().unwrap().as_mut() {
+            requests.remove(&self.request);
+        }
+        // Without a runtime the cancellation can not be sent, and the peer
+        // learns of it when the connection closes.
+        if !self.cancel_on_drop || self.mux.is_closed() {
+            return;
+        }
+        if let Ok(runtime) = Handle::try_current() {
+            let (mux, request) = (self.mux.clone(), self.request);
+            runtime.spawn(async move {
+                let _ = mux.write(request, Message::Cancel, &[]).await;
+            });
+        }
+    }
+}
+
+/// Sends results for consecutive records, as far as the resolver's credit
+/// allows.
+pub struct ResultSink<'a> {
+    channel: &'a mut Channel,
+    /// Results per record.
+    record:  usize,
+    /// Records sent so far.
+    sent:    usize,
+    credit:  u64,
+}
+
+impl<'a> ResultSink<'a> {
+    pub fn new(channel: &'a mut Channel, record: usize) -> Self {
+        Self {
+            channel,
+            record,
+            sent: 0,
+            credit: WINDOW,
+        }
+    }
+
+    /// Send the results of the next records.
+    pub async fn send(&mut self, results: &[[u16; 31]]) -> Result<()> {
+        while self.credit == 0 {
+            let mut credit = 0_u64;
+            self.channel
+                .receive_exact(Message::Credit, bytes_of_mut(&mut credit))
+                .await?;
+            self.credit += credit;
+        }
+        let first = self.sent as u64;
+        self.channel
+            .send(Message::Results, &[bytes_of(&first), cast_slice(results)])
+            .await?;
+        self.sent += results.len() / self.record;
+        self.credit -= 1;
+        Ok(())
+    }
+
+    /// End the results, returning the number of records sent.
+    pub async fn finish(self) -> Result<usize> {
+        let total = self.sent as u64;
+        self.channel.send(Message::End, &[bytes_of(&total)]).await?;
+        Ok(self.sent)
+    }
+}
+
+/// Results streamed by a participant for consecutive records.
+///
+/// Frames need not align with the batches taken from them. Results are only
+/// complete once the end frame confirms their number, so a response that is
+/// cut short or out of order is an error rather than fewer results.
+pub struct ResultStream {
+    channel:  Channel,
+    /// Results per record.
+    record:   usize,
+    /// Records received so far.
+    received: usize,
+    /// Results received and not yet taken.
+    pending:  Vec<[u16; 31]>,
+}
+
+impl ResultStream {
+    pub fn new(channel: Channel, record: usize) -> Self {
+        Self {
+            channel,
+            record,
+            received: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Take the results of the next `records` records.
+    pub async fn take(&mut self, records: usize) -> Result<Vec<[u16; 31]>> {
+        let wanted = records * self.record;
+        while self.pending.len() < wanted {
+            let frame = self.channel.receive().await?;
+            match frame.message {
+                Message::Results => {
+                    let record_size = self.record * size_of::<[u16; 31]>();
+                    let length = frame.payload.len();
+                    ensure!(
+                        length >= size_of::<u64>()
+                            && (length - size_of::<u64>()).is_multiple_of(record_size),
+                        "Received a results frame of {length} bytes, which is not whole records."
+                    );
+                    let (first, results) = frame.payload.split_at(size_of::<u64>());
+                    let first = u64::from_ne_bytes(first.try_into().unwrap());
+                    ensure!(
+                        first as usize == self.received,
+                        "Received results from record {first}, expected record {}.",
+                        self.received
+                    );
+                    let start = self.pending.len();
+                    let len = results.len() / size_of::<[u16; 31]>();
+                    self.pending.resize(start + len, [0; 31]);
+                    cast_slice_mut(&mut self.pending[start..]).copy_from_slice(results);
+                    self.received += len / self.record;
+                    self.channel
+                        .send(Message::Credit, &[bytes_of(&1_u64)])
+                        .await?;
+                }
+                Message::End => {
+                    let total = self.read_end(&frame)?;
+                    bail!(
+                        "Results ended after {total} records, expected at least {}.",
+                        self.received - self.pending.len() / self.record + records
+                    );
+                }
+                message => bail!("Expected a results frame, received a {message} frame."),
+            }
+        }
+        let rest = self.pending.split_off(wanted);
+        Ok(replace(&mut self.pending, rest))
+    }
+
+    /// Receive the end of the results, which must be for exactly `count`
+    /// records.
+    pub async fn finish(&mut self, count: usize) -> Result<()> {
+        let frame = self.channel.receive().await?;
+        match frame.message {
+            Message::End => {
+                let total = self.read_end(&frame)?;
+                ensure!(
+                    total == count && self.pending.is_empty(),
+                    "Results ended after {total} records, expected {count}."
+                );
+                Ok(())
+            }
+            Message::Results => bail!("Received results past the expected {count} records."),
+            message => bail!("Expected an end frame, received a {message} frame."),
+        }
+    }
+
+    /// Number of records in an end frame, which must agree with the records
+    /// received.
+    fn read_end(&self, frame: &Frame) -> Result<usize> {
+        let mut total = 0_u64;
+        frame.read_into(bytes_of_mut(&mut total))?;
+        ensure!(
+            total as usize == self.received,
+            "Participant reports {total} records, but sent {}.",
+            self.received
+        );
+        Ok(total as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{
+        io::{duplex, split},
+        time::timeout,
+    };
+
+    /// Both ends of an in-process connection, each dispatching what it
+    /// reads.
+    fn connect() -> (Arc<Mux>, Arc<Mux>) {
+        let (left, right) = duplex(1 << 20);
+        let [left, right] = [left, right].map(|stream| {
+            let (reader, writer) = split(stream);
+            let mux = Mux::with_writer(writer);
+            tokio::spawn({
+                let mux = mux.clone();
+                async move { mux.receive(BufReader::new(reader)).await }
+            });
+            mux
+        });
+        (left, right)
+    }
+
+    async fn receive_u64(channel: &mut Channel) -> u64 {
+        let mut value = 0_u64;
+        channel
+            .receive_exact(Message::Credit, bytes_of_mut(&mut value))
+            .await
+            .unwrap();
+        value
+    }
+
+    fn header(message: Message, length: u64) -> Vec<u8> {
+        let header = FrameHeader {
+            message: message.to_u32(),
+            reserved: 0,
+            request: 7,
+            length,
+        };
+        bytes_of(&header).to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_payload_limits() {
+        // Small messages are limited to their size, checked before reading.
+        let bytes = header(Message::Credit, 9);
+        let err = read_frame(&mut bytes.as_slice()).await.err().unwrap();
+        assert!(err.to_string().contains("at most 8"), "{err}");
+        let bytes = header(Message::Status, u64::MAX);
+        assert!(read_frame(&mut bytes.as_slice()).await.is_err());
+
+        // Large payloads are only accepted as far as they arrive.
+        let length = 3 * INITIAL_PAYLOAD as u64;
+        let mut bytes = header(Message::Enroll, length);
+        bytes.resize(bytes.len() + length as usize, 0xa5);
+        let (request, frame) = read_frame(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(request, 7);
+        assert_eq!(frame.payload.len() as u64, length);
+        bytes.truncate(bytes.len() - 1);
+        let err = read_frame(&mut bytes.as_slice()).await.err().unwrap();
+        assert!(err.to_string().contains("Connection closed"), "{err}");
+        let bytes = header(Message::Enroll, Message::Enroll.max_payload() as u64 + 1);
+        assert!(read_frame(&mut bytes.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_interleaved() {
+        let (client, server) = connect();
+        let mut first = client.open().unwrap();
+        let mut second = client.open().unwrap();
+        assert_ne!(first.request(), second.request());
+        let mut served =
+            [first.request(), second.request()].map(|request| server.register(request).unwrap());
+
+        // Frames of both requests interleave, each arriving on its channel.
+        for value in 0..4_u64 {
+            let channel = if value % 2 == 0 { &first } else { &second };
+            channel
+                .send(Message::Credit, &[bytes_of(&value)])
+                .await
+                .unwrap();
+        }
+        assert_eq!(receive_u64(&mut served[1]).await, 1);
+        assert_eq!(receive_u64(&mut served[0]).await, 0);
+        assert_eq!(receive_u64(&mut served[0]).await, 2);
+        assert_eq!(receive_u64(&mut served[1]).await, 3);
+
+        // Answers are told apart the same way.
+        for (channel, value) in served.iter().zip([10_u64, 11]).rev() {
+            channel
+                .send(Message::Credit, &[bytes_of(&value)])
+                .await
+                .unwrap();
+        }
+        assert_eq!(receive_u64(&mut first).await, 10);
+        assert_eq!(receive_u64(&mut second).await, 11);
+
+        // A reused identifier leaves the open request alone.
+        let err = server.register(first.request()).err().unwrap();
+        assert!(err.to_string().contains("already open"), "{err}");
+        first
+            .send(Message::Credit, &[bytes_of(&12_u64)])
+            .await
+            .unwrap();
+        assert_eq!(receive_u64(&mut served[0]).await, 12);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let (client, server) = connect();
+        let channel = client.open().unwrap();
+        let mut served = server.register(channel.request()).unwrap();
+        let mut results = ResultStream::new(channel, 1);
+        let mut sink = ResultSink::new(&mut served, 1);
+
+        // A full window is sent without credit, the next frame waits for it.
+        for record in 0..WINDOW as u16 {
+            sink.send(&[[record; 31]]).await.unwrap();
+        }
+        let waiting = timeout(Duration::from_millis(50), sink.send(&[[4; 31]])).await;
+        assert!(waiting.is_err());
+
+        // Taking a frame returns its credit.
+        assert_eq!(results.take(1).await.unwrap(), [[0; 31]]);
+        sink.send(&[[4; 31]]).await.unwrap();
+        assert_eq!(sink.finish().await.unwrap(), 5);
+        let rest = results.take(4).await.unwrap();
+        assert_eq!(rest, (1..5).map(|record| [record; 31]).collect::<Vec<_>>());
+        results.finish(5).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_on_drop() {
+        let (client, server) = connect();
+        let channel = client.open().unwrap();
+        let request = channel.request();
+        let mut served = server.register(request).unwrap();
+        drop(channel);
+
+        // The peer is told, and the request no longer takes frames.
+        let err = served.receive().await.err().unwrap();
+        assert!(err.to_string().contains("cancelled"), "{err}");
+        assert!(served.is_cancelled());
+        let frame = Frame {
+            message: Message::Status,
+            payload: Vec::new(),
+        };
+        assert!(client.dispatch(request, frame).is_some());
+
+        // A completed response is not cancelled.
+        let mut channel = client.open().unwrap();
+        let mut served = server.register(channel.request()).unwrap();
+        channel.complete();
+        drop(channel);
+        let waiting = timeout(Duration::from_millis(50), served.receive()).await;
+        assert!(waiting.is_err());
+        assert!(!served.is_cancelled());
+    }
+
+    #[test]
+    fn test_drop_outside_runtime() {
+        let (left, _right) = duplex(64);
+        let mux = Mux::with_writer(left);
+        let channel = mux.open().unwrap();
+        let request = channel.request();
+        drop(channel);
+        let frame = Frame {
+            message: Message::Status,
+            payload: Vec::new(),
+        };
+        assert!(mux.dispatch(request, frame).is_some());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::EncodedBits;
+use anyhow::{ensure, Result};
+use itertools::Itertools;
+use rand::{thread_rng, CryptoRng, Rng};
+
+/// Replicated secret sharing where any `threshold` out of `parties` parties
+/// can reconstruct.
+///
+/// The secret is split in additive shares, one for each set of
+/// `parties - threshold + 1` parties, and every party in the set holds a copy.
+/// Any `threshold` parties together hold all additive shares. Since the shares
+/// are additive, linear operations like [`crate::DistanceEngine`] can be
+/// applied to each share separately.
+///
+/// For `threshold == parties` this is plain additive sharing where party `i`
+/// holds share `i`. A threshold of one would give every party the secret, so
+/// it is only accepted for a single party.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Replicated {
+    parties:   usize,
+    threshold: usize,
+    /// The parties holding each additive share.
+    sets:      Vec<Vec<usize>>,
+    /// The additive shares held by each party.
+    held:      Vec<Vec<usize>>,
+}
+
+impl Replicated {
+    pub fn new(parties: usize, threshold: usize) -> Result<Self> {
+        ensure!(parties > 0, "At least one party is required.");
+        ensure!(
+            threshold > 0 && threshold <= parties,
+            "Threshold must be between 1 and the number of parties {parties}, got {threshold}."
+        );
+        ensure!(
+            threshold > 1 || parties == 1,
+            "Threshold 1 would give each of the {parties} parties the secret, it must be at least \
+             2."
+        );
+        let sets = (0..parties)
+            .combinations(parties - threshold + 1)
+            .collect::<Vec<_>>();
+        let held = (0..parties)
+            .map(|party| sets.iter().positions(|set| set.contains(&party)).collect())
+            .collect();
+        Ok(Self {
+            parties,
+            threshold,
+            sets,
+            held,
+        })
+    }
+
+    pub fn parties(&self) -> usize {
+        self.parties
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// The sets of parties holding each additive share, in share order.
+    pub fn sets(&self) -> &[Vec<usize>] {
+        &self.sets
+    }
+
+    /// Indices of the additive shares held by `party`, in storage order.
+    pub fn held_by(&self, party: usize) -> &[usize] {
+        &self.held[party]
+    }
+
+    /// Number of records each party stores per entry.
+    pub fn records(&self) -> usize {
+        self.held_by(0).len()
+    }
+
+    /// Generate secret shares and return the records of each party.
+    pub fn share(&self, secret: &EncodedBits) -> Vec<Vec<EncodedBits>> {
+        self.share_with_rng(secret, &mut thread_rng())
+    }
+
+    /// Like [`Self::share`], drawing the randomness from `rng`.
+    pub fn share_with_rng<R: Rng + CryptoRng>(
+        &self,
+        secret: &EncodedBits,
+        rng: &mut R,
+    ) -> Vec<Vec<EncodedBits>> {
+        let shares = secret.share_with_rng(self.sets.len(), rng);
+        self.held
+            .iter()
+            .map(|held| held.iter().map(|&share| shares[share]).collect())
+            .collect()
+    }
+
+    /// For each additive share pick a party in `available` holding it.
+    ///
+    /// Returns pairs of the party and the position of the share in that
+    /// party's records, or `None` if the available parties are insufficient.
+    pub fn plan(&self, available: &[usize]) -> Option<Vec<(usize, usize)>> {
+        self.sets
+            .iter()
+            .enumerate()
+            .map(|(share, set)| {
+                let party = *available.iter().find(|party| set.contains(party))?;
+                let position = self.held[party].iter().position(|&s| s == share)?;
+                Some((party, position))
+            })
+            .collect()
+    }
+
+    /// Reconstruct a secret from the records of all parties.
+    ///
+    /// Returns `None` if the copies of a share held by different parties
+    /// disagree.
+    pub fn reconstruct(&self, records: &[&[EncodedBits]]) -> Option<EncodedBits> {
+        assert_eq!(records.len(), self.parties);
+        let mut result = EncodedBits::default();
+        for (share, set) in self.sets.iter().enumerate() {
+            let mut copies = set.iter().map(|&party| {
+                let position = self.held[party].binary_search(&share).unwrap();
+                &records[party][position]
+            });
+            let first = copies.next().unwrap();
+            if copies.any(|copy| copy != first) {
+                return None;
+            }
+            result += first;
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distances;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn test_additive() {
+        for n in 1..5 {
+            let scheme = Replicated::new(n, n).unwrap();
+            assert_eq!(scheme.records(), 1);
+            for party in 0..n {
+                assert_eq!(scheme.held_by(party), [party]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Replicated::new(0, 0).is_err());
+        assert!(Replicated::new(3, 0).is_err());
+        assert!(Replicated::new(3, 4).is_err());
+        assert!(Replicated::new(3, 3).is_ok());
+
+        // Every party would hold all additive shares.
+        assert!(Replicated::new(2, 1).is_err());
+        assert!(Replicated::new(5, 1).is_err());
+        assert!(Replicated::new(1, 1).is_ok());
+    }
+
+    #[test]
+    fn test_reconstruct() {
+        let mut rng = thread_rng();
+        for n in 1..6 {
+            for k in 2.min(n)..=n {
+                let scheme = Replicated::new(n, k).unwrap();
+                let secret: EncodedBits = rng.gen();
+                let records = scheme.share(&secret);
+                let records = records.iter().map(Vec::as_slice).collect::<Vec<_>>();
+                assert_eq!(scheme.reconstruct(&records), Some(secret));
+            }
+        }
+    }
+
+    #[test]
+    fn test_share_with_rng() {
+        let scheme = Replicated::new(4, 2).unwrap();
+        let secret: EncodedBits = thread_rng().gen();
+        let records = |seed| scheme.share_with_rng(&secret, &mut ChaCha20Rng::seed_from_u64(seed));
+        assert_eq!(records(7), records(7));
+        assert_ne!(records(7), records(8));
+        let records = records(7);
+        let records = records.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        assert_eq!(scheme.reconstruct(&records), Some(secret));
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut rng = thread_rng();
+        for n in 1..6 {
+            for k in 2.min(n)..=n {
+                let scheme = Replicated::new(n, k).unwrap();
+                let query: EncodedBits = rng.gen();
+                let secret: EncodedBits = rng.gen();
+                let records = scheme.share(&secret);
+                for available in (0..n).combinations(k) {
+                    // Linear evaluation on the records of the available parties
+                    let plan = scheme.plan(&available).unwrap();
+                    let mut result = [0_u16; 31];
+                    for (party, position) in plan {
+                        let partial = distances(&query, &records[party][position]);
+                        for (r, p) in result.iter_mut().zip(partial.iter()) {
+                            *r = r.wrapping_add(*p);
+                        }
+                    }
+                    assert_eq!(result, distances(&query, &secret));
+                }
+                for available in (0..n).combinations(k - 1) {
+                    assert!(scheme.plan(&available).is_none());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1320 @@
+use crate::{
+    enroll,
+    format::{self, DataFile, Dataset, Kind},
+    handshake::{self, Announcement},
+    ids::{self, Entry, Ids},
+    merkle::{Hash, Manifest},
+    protocol::{Channel, Message, Mux, ResultStream},
+    secure_threshold,
+    slot::NextSlot,
+    tombstones::Tombstones,
+    ResolverArgs, MAX_ENROLL, MAX_QUERIES,
+};
+use anyhow::{bail, ensure, format_err, Context, Result};
+use bytemuck::{bytes_of, bytes_of_mut, cast_slice, cast_slice_mut};
+use futures::future::{join_all, try_join_all};
+use indicatif::{HumanBytes, HumanCount, ProgressBar, ProgressStyle};
+use mpc_iris_code::{
+    comparison, decode_rotation, encode, Bits, EncodedBits, MasksEngine, Neighbor, Neighbors,
+    Replicated, Template,
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::{max, min},
+    collections::{BTreeSet, HashSet},
+    fmt, iter,
+    mem::size_of,
+    net::SocketAddr,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    join,
+    sync::{mpsc, Mutex, RwLock},
+    task::JoinHandle,
+};
+
+const BATCH_SIZE: usize = 20_000;
+
+/// Maximum number of entries returned for a query.
+pub const MAX_NEIGHBORS: usize = 1000;
+
+/// Which entries to return for a query. By default only the closest.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Deserialize)]
+pub struct Selection {
+    /// Return up to this many closest entries.
+    pub k:         Option<usize>,
+    /// Only return entries within this distance, up to [`MAX_NEIGHBORS`]
+    /// unless `k` is given.
+    pub threshold: Option<f64>,
+}
+
+impl Selection {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(k) = self.k {
+            if k == 0 || k > MAX_NEIGHBORS {
+                bail!("k must be between 1 and {MAX_NEIGHBORS}.");
+            }
+        }
+        if self.threshold.is_some_and(f64::is_nan) {
+            bail!("Threshold must be a number.");
+        }
+        Ok(())
+    }
+
+    fn neighbors(&self) -> Neighbors {
+        let limit = match self {
+            Self {
+                k: None,
+                threshold: None,
+            } => 1,
+            Self { k, .. } => k.unwrap_or(MAX_NEIGHBORS),
+        };
+        Neighbors::new(limit, self.threshold)
+    }
+}
+
+/// Outcome of a query.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(untagged)]
+pub enum QueryResult {
+    /// The closest entry, its distance and the rotation of the query.
+    Closest {
+        index:    usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id:       Option<String>,
+        distance: f64,
+        rotation: i32,
+    },
+    /// The closest entries according to the query's [`Selection`], closest
+    /// first.
+    Neighbors { neighbors: Vec<Identified> },
+    /// Entries within the match threshold, using secure comparison, and
+    /// their identifiers if the database has any.
+    Matches {
+        matches: Vec<usize>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        ids:     Vec<Option<String>>,
+    },
+}
+
+/// A [`Neighbor`] with the external identifier of the entry, if it has one.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct Identified {
+    #[serde(flatten)]
+    pub neighbor: Neighbor,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id:       Option<String>,
+}
+
+/// Reference to an entry by index or external identifier.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum EntryRef {
+    Index(usize),
+    Id(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum QueryError {
+    /// Too few participants could be reached.
+    Unavailable(Arc<anyhow::Error>),
+    /// A participant failed or misbehaved during the query.
+    Failed(Arc<anyhow::Error>),
+    /// There are no entries to compare against.
+    Empty,
+    /// The request is not supported by this resolver.
+    Rejected(Arc<anyhow::Error>),
+    /// The query was abandoned at its deadline.
+    Timeout,
+}
+
+impl From<anyhow::Error> for QueryError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Failed(Arc::new(err))
+    }
+}
+
+impl From<tokio::task::JoinError> for QueryError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        Self::Failed(Arc::new(err.into()))
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable(err) => write!(f, "Participants unavailable: {err:#}"),
+            Self::Failed(err) => write!(f, "Query failed: {err:#}"),
+            Self::Empty => write!(f, "Database is empty."),
+            Self::Rejected(err) => write!(f, "Request rejected: {err:#}"),
+            Self::Timeout => write!(f, "Query deadline exceeded."),
+        }
+    }
+}
+
+/// Entries added by an enrollment.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub struct Enrolled {
+    /// Index of the first new entry.
+    pub first: usize,
+    /// Number of new entries.
+    pub count: usize,
+}
+
+/// Outcome of a deletion.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub struct Deleted {
+    /// Number of entries deleted by the request.
+    pub deleted:    usize,
+    /// Total number of deleted entries awaiting compaction.
+    pub tombstones: usize,
+}
+
+/// Coordinates queries with the participants.
+pub struct Resolver {
+    participants:    Vec<SocketAddr>,
+    scheme:          Replicated,
+    masks_path:      PathBuf,
+    legacy:          bool,
+    /// Dataset the participants must serve, if known.
+    dataset:         Option<Dataset>,
+    manifest_path:   Option<PathBuf>,
+    parts:           usize,
+    shared_query:    bool,
+    match_threshold: Option<f64>,
+    count_style:     ProgressStyle,
+
+    /// Connections to the participants, opened on first use and reopened
+    /// after they fail. Concurrent queries share them.
+    connections: Vec<Mutex<Option<Arc<Mux>>>>,
+    /// Next preprocessed slot, if queries use preprocessed material.
+    slot:        Option<Mutex<NextSlot>>,
+    /// Set while participants may disagree on the records after an
+    /// interrupted enrollment, so they are checked before the next query.
+    repair:      AtomicBool,
+
+    /// Scans hold the lock for reading, so they run concurrently, while an
+    /// enrollment or deletion holds it for writing to change the entries.
+    state: RwLock<State>,
+}
+
+struct State {
+    /// Main file with masks, unless they are secret-shared.
+    masks:      Option<Arc<DataFile>>,
+    /// Deleted entries, excluded from results.
+    tombstones: Tombstones,
+    /// External identifiers of entries.
+    ids:        Ids,
+    /// Merkle roots the participants' files must have, updated on enrollment.
+    manifest:   Option<Manifest>,
+}
+
+impl Resolver {
+    pub(crate) fn new(args: &ResolverArgs, count_style: ProgressStyle) -> Result<Self> {
+        if let Some(threshold) = args.match_threshold {
+            ensure!(
+                (0.0..=1.0).contains(&threshold),
+                "Match threshold {threshold} out of range, expected 0 to 1."
+            );
+        }
+
+        // Read main file with masks, unless they are secret-shared.
+        let masks = if args.shared_masks {
+            eprintln!("Using secret-shared masks.");
+            None
+        } else {
+            let size = HumanBytes(std::fs::metadata(&args.masks)?.size());
+            let mmap = Arc::new(DataFile::open(&args.masks, Kind::Masks, args.legacy)?);
+            let masks: &[Bits] = cast_slice(&mmap);
+            eprintln!(
+                "Opened main {:?} with {} masks ({})",
+                args.masks,
+                HumanCount(masks.len() as u64),
+                size
+            );
+            if let Some(header) = mmap.header() {
+                eprintln!(
+                    "Masks are from dataset {} with Merkle root {}.",
+                    header.dataset(),
+                    header.root()
+                );
+            }
+            Some(mmap)
+        };
+
+        // Roots of the expected data, which must be of the same dataset as the masks.
+        let manifest = args.manifest.as_deref().map(Manifest::read).transpose()?;
+        let masks_header = masks.as_ref().and_then(|masks| masks.header());
+        if let Some(manifest) = &manifest {
+            eprintln!(
+                "Expecting dataset {} with share roots {:?}.",
+                manifest.dataset, manifest.shares
+            );
+            let mask_shares = if args.shared_masks {
+                args.participants.len()
+            } else {
+                0
+            };
+            if manifest.shares.len() != args.participants.len()
+                || manifest.mask_shares.len() != mask_shares
+            {
+                bail!(
+                    "Manifest lists {} shares and {} mask shares, expected {} and {mask_shares}.",
+                    manifest.shares.len(),
+                    manifest.mask_shares.len(),
+                    args.participants.len()
+                );
+            }
+            if let Some(header) = masks_header {
+                if header.dataset() != manifest.dataset || Some(header.root()) != manifest.masks {
+                    bail!(
+                        "Masks {:?} are not the version in the manifest.",
+                        args.masks
+                    );
+                }
+            }
+        }
+        let dataset = manifest
+            .as_ref()
+            .map(|manifest| manifest.dataset)
+            .or(masks_header.map(|header| header.dataset()));
+
+        // TODO: Local share.
+        if args.share.is_some() {
+            bail!("Local shares are not supported yet.");
+        }
+
+        eprintln!("Participants: {:?}", &args.participants);
+        let parties = args.participants.len();
+        let scheme = Replicated::new(parties, args.threshold.unwrap_or(parties))?;
+        if args.shared_query && scheme.threshold() != parties {
+            bail!("Secret-shared queries require all participants.");
+        }
+        if args.match_threshold.is_some() && scheme.threshold() != parties {
+            bail!("Secure comparison requires all participants.");
+        }
+
+        let tombstones = Tombstones::open(&args.tombstones, size_of::<Bits>())?;
+        if tombstones.len() > 0 {
+            eprintln!(
+                "Excluding {} deleted entries listed in {:?}.",
+                tombstones.len(),
+                args.tombstones
+            );
+        }
+
+        let ids = Ids::open(&args.ids)?;
+        if ids.len() > 0 {
+            eprintln!("Opened {} identifiers from {:?}.", ids.len(), args.ids);
+        }
+
+        let slot = if args.shared_query || args.match_threshold.is_some() {
+            let slot = NextSlot::open(&args.next_slot, args.first_slot)?;
+            eprintln!("Continuing from preprocessed slot {}.", slot.peek());
+            Some(Mutex::new(slot))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            participants: args.participants.clone(),
+            scheme,
+            masks_path: args.masks.clone(),
+            legacy: args.legacy,
+            dataset,
+            manifest_path: args.manifest.clone(),
+            parts: if args.shared_masks { 2 } else { 1 },
+            shared_query: args.shared_query,
+            match_threshold: args.match_threshold,
+            count_style,
+            connections: args.participants.iter().map(|_| Mutex::default()).collect(),
+            slot,
+            // The last run may have been interrupted during an enrollment.
+            repair: AtomicBool::new(!args.shared_query && args.match_threshold.is_none()),
+            state: RwLock::new(State {
+                masks,
+                tombstones,
+                ids,
+                manifest,
+            }),
+        })
+    }
+
+    /// Wait for enrollments and deletions in progress to complete, and hold
+    /// off further ones for as long as the returned guard lives.
+    pub async fn pause_updates(&self) -> impl Sized + '_ {
+        self.state.write().await
+    }
+
+    /// Add entries to the database.
+    ///
+    /// All participants must be available. They append their shares of the
+    /// new entries and serve them to subsequent queries. Identifiers must be
+    /// unique among entries that are not deleted.
+    pub async fn enroll(&self, entries: &[Entry]) -> Result<Enrolled, QueryError> {
+        if self.shared_query || self.match_threshold.is_some() {
+            return Err(QueryError::Rejected(Arc::new(format_err!(
+                "Enrollment is not supported with preprocessed material."
+            ))));
+        }
+        if entries.is_empty() {
+            return Err(QueryError::Rejected(Arc::new(format_err!(
+                "No entries to enroll."
+            ))));
+        }
+        let records = self.scheme.records();
+        let mut state = self.state.write().await;
+        let mut seen = HashSet::new();
+        for id in entries.iter().filter_map(|entry| entry.id.as_deref()) {
+            ids::validate(id).map_err(|err| QueryError::Rejected(Arc::new(err)))?;
+            let live = state
+                .ids
+                .find(id)
+                .is_some_and(|entry| !state.tombstones.contains(entry));
+            if !seen.insert(id) || live {
+                return Err(QueryError::Rejected(Arc::new(format_err!(
+                    "Identifier {id:?} is already enrolled."
+                ))));
+            }
+        }
+
+        let mut enrolled = Enrolled { first: 0, count: 0 };
+        for (i, chunk) in entries.chunks(max(MAX_ENROLL / records, 1)).enumerate() {
+            let result = self.enroll_chunk(&mut state, chunk).await?;
+            if i == 0 {
+                enrolled.first = result.first;
+            }
+            enrolled.count += result.count;
+        }
+        Ok(enrolled)
+    }
+
+    /// Delete entries from the database.
+    ///
+    /// All participants must be available. They record the deletions, and the
+    /// entries are excluded from results until the files are compacted.
+    pub async fn delete(&self, entries: &[EntryRef]) -> Result<Deleted, QueryError> {
+        let mut state = self.state.write().await;
+        let records = self.scheme.records();
+        let entries = entries
+            .iter()
+            .map(|entry| match entry {
+                EntryRef::Index(index) => Ok(*index),
+                EntryRef::Id(id) => state.ids.find(id).ok_or_else(|| {
+                    QueryError::Rejected(Arc::new(format_err!("Unknown identifier {id:?}.")))
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let new = entries
+            .into_iter()
+            .filter(|&entry| !state.tombstones.contains(entry))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        for chunk in new.chunks(MAX_ENROLL) {
+            let count = self.check_all(&mut state).await?;
+            if let Some(entry) = chunk.iter().find(|&&entry| entry >= count) {
+                return Err(QueryError::Rejected(Arc::new(format_err!(
+                    "Entry {entry} out of range, there are {count} entries."
+                ))));
+            }
+
+            // Participants persist the deletion before acknowledging.
+            let payload = iter::once(records)
+                .chain(chunk.iter().copied())
+                .map(|value| value as u64)
+                .collect::<Vec<_>>();
+            let payload = &payload;
+            try_join_all((0..self.participants.len()).map(|party| async move {
+                let mut channel = self.open(party).await?;
+                channel
+                    .send(Message::Delete, &[cast_slice(payload)])
+                    .await?;
+                let mut total = 0_u64;
+                channel
+                    .receive_exact(Message::Deleted, bytes_of_mut(&mut total))
+                    .await?;
+                Result::<_>::Ok(())
+            }))
+            .await?;
+            state.tombstones.insert(chunk)?;
+        }
+
+        eprintln!(
+            "Deleted {} entries, {} awaiting compaction.",
+            new.len(),
+            state.tombstones.len()
+        );
+        Ok(Deleted {
+            deleted:    new.len(),
+            tombstones: state.tombstones.len(),
+        })
+    }
+
+    /// Open a channel for a new request to participant `party`, connecting
+    /// first unless a connection is open.
+    async fn open(&self, party: usize) -> Result<Channel> {
+        let mut connection = self.connections[party].lock().await;
+        if let Some(mux) = connection.as_ref().filter(|mux| !mux.is_closed()) {
+            return mux.open();
+        }
+        let address = self.participants[party];
+        let (stream, _) = handshake::connect(address).await?;
+        eprintln!("Connected to {address}");
+        let (mux, reader) = Mux::new(stream);
+        let receiver = mux.clone();
+        tokio::spawn(async move {
+            if let Err(err) = receiver.receive(reader).await {
+                eprintln!("Connection to {address} closed: {err:#}");
+            }
+        });
+        *connection = Some(mux.clone());
+        mux.open()
+    }
+
+    /// Send a request to participant `party` and check the announcement
+    /// that precedes the response. Returns the channel and the number of
+    /// records the participant serves.
+    async fn request(
+        &self,
+        party: usize,
+        message: Message,
+        payload: &[&[u8]],
+        manifest: Option<&Manifest>,
+    ) -> Result<(Channel, usize)> {
+        let address = self.participants[party];
+        let mut channel = self.open(party).await?;
+        channel.send(message, payload).await?;
+        let announcement = Announcement::parse(&channel.expect(Message::Announce).await?)?;
+        announcement.check(address, party, self.parts, self.dataset, manifest)?;
+        Ok((channel, announcement.count as usize))
+    }
+
+    /// Ask all participants for their status and check they agree on the
+    /// entries.
+    ///
+    /// Used before modifying the database, so that either all or none of the
+    /// participants are asked to. Records of an interrupted enrollment that
+    /// only some of them kept are rolled back first. Returns the number of
+    /// entries.
+    async fn check_all(&self, state: &mut State) -> Result<usize, QueryError> {
+        // Enrollments only add records, so those all participants have are
+        // the committed ones. Roots are checked once they are rolled back.
+        let counts = self.statuses(None).await?;
+        let committed = counts.iter().min().copied().unwrap_or_default() / self.scheme.records();
+        if counts
+            .iter()
+            .any(|&count| count > committed * self.scheme.records())
+        {
+            eprintln!(
+                "Participants disagree on the number of records {counts:?}, rolling back to \
+                 {committed} entries."
+            );
+            self.roll_back(state, committed).await?;
+        }
+
+        let counts = self.statuses(state.manifest.as_ref()).await?;
+        let count = self.count(&counts, state)?;
+        self.repair.store(false, Ordering::Relaxed);
+        Ok(count)
+    }
+
+    /// Ask all participants for their status, checking their data matches
+    /// `manifest` if given. Returns their numbers of records.
+    async fn statuses(&self, manifest: Option<&Manifest>) -> Result<Vec<usize>, QueryError> {
+        try_join_all((0..self.participants.len()).map(|party| async move {
+            let (mut channel, count) = self.request(party, Message::Status, &[], manifest).await?;
+            channel.complete();
+            Result::<_>::Ok(count)
+        }))
+        .await
+        .map_err(|err| QueryError::Unavailable(Arc::new(err)))
+    }
+
+    /// Number of entries, which all participants and the masks must agree on.
+    fn count(&self, counts: &[usize], state: &State) -> Result<usize, QueryError> {
+        let records = self.scheme.records();
+        let count = counts[0] / records;
+        if counts.iter().any(|&c| c != count * records) {
+            return Err(
+                format_err!("Participants disagree on the number of records: {counts:?}").into(),
+            );
+        }
+        if let Some(masks) = &state.masks {
+            let masks = cast_slice::<_, Bits>(masks).len();
+            if masks != count {
+                return Err(format_err!(
+                    "Participants have {count} entries, but there are {masks} masks."
+                )
+                .into());
+            }
+        }
+        Ok(count)
+    }
+
+    /// Enroll a chunk of entries, rolling back the participants and the
+    /// masks if any of them fails.
+    async fn enroll_chunk(
+        &self,
+        state: &mut State,
+        entries: &[Entry],
+    ) -> Result<Enrolled, QueryError> {
+        let count = self.check_all(state).await?;
+        let manifest = state.manifest.clone();
+        let result = self.append(state, count, entries).await;
+        if let Err(err) = &result {
+            eprintln!("Enrollment failed, rolling back to {count} entries: {err:#}");
+            if let Err(err) = self.roll_back(state, count).await {
+                eprintln!("Rollback failed, retrying before the next request: {err:#}");
+            }
+            if let (Some(manifest), Some(path)) = (&manifest, &self.manifest_path) {
+                if state.manifest.as_ref() != Some(manifest) {
+                    manifest.write(path)?;
+                }
+            }
+            state.manifest = manifest;
+        }
+        result
+    }
+
+    /// Append entries to the participants' shares, the masks, the identifiers
+    /// and the manifest, following the `count` existing entries.
+    async fn append(
+        &self,
+        state: &mut State,
+        count: usize,
+        entries: &[Entry],
+    ) -> Result<Enrolled, QueryError> {
+        let parties = self.participants.len();
+        let parts = self.parts;
+        let records = self.scheme.records();
+        let templates = entries.iter().map(Entry::template).collect::<Vec<_>>();
+
+        // Secret share the parts of every template, grouping per party and part.
+        let shares = {
+            let templates = templates.clone();
+            let scheme = self.scheme.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut shares = vec![vec![Vec::new(); parts]; parties];
+                for template in &templates {
+                    let template_parts = [encode(template), EncodedBits::from(&template.mask)];
+                    for (part, secret) in template_parts[..parts].iter().enumerate() {
+                        for (party, records) in scheme.share(secret).into_iter().enumerate() {
+                            shares[party][part].extend(records);
+                        }
+                    }
+                }
+                shares
+            })
+            .await?
+        };
+
+        // Send shares and await acknowledgement of the new number of records,
+        // followed by the new roots of the participants' files. All of them
+        // are asked, so none is left behind if the request of another fails.
+        let expected = (count + templates.len()) * records;
+        let roots = join_all(
+            shares
+                .into_iter()
+                .enumerate()
+                .map(|(party, shares)| async move {
+                    let payload = shares
+                        .iter()
+                        .map(|part| cast_slice(part))
+                        .collect::<Vec<_>>();
+                    let mut channel = self.open(party).await?;
+                    channel.send(Message::Enroll, &payload).await?;
+                    let mut total = 0_u64;
+                    let mut roots = [Hash::default(); 2];
+                    channel
+                        .expect(Message::Enrolled)
+                        .await?
+                        .read_parts(&mut [bytes_of_mut(&mut total), cast_slice_mut(&mut roots)])?;
+                    if total as usize != expected {
+                        bail!(
+                            "Participant has {total} records after enrollment, expected \
+                             {expected}."
+                        );
+                    }
+                    Result::<_>::Ok(roots)
+                }),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+
+        // Append the plain masks to the main file.
+        let mut masks_root = None;
+        if state.masks.is_some() {
+            let path = self.masks_path.clone();
+            let masks = templates.iter().map(|t| t.mask).collect::<Vec<_>>();
+            let legacy = self.legacy;
+            let header = tokio::task::spawn_blocking(move || {
+                format::append(&path, cast_slice(&masks), legacy)
+            })
+            .await??;
+            masks_root = header.map(|header| header.root());
+            state.masks = Some(enroll::remap(&self.masks_path, Kind::Masks, self.legacy)?);
+        }
+
+        // Expect the new version of the data from now on.
+        if let (Some(manifest), Some(path)) = (&mut state.manifest, &self.manifest_path) {
+            for (party, roots) in roots.iter().enumerate() {
+                manifest.shares[party] = roots[0];
+                if parts == 2 {
+                    manifest.mask_shares[party] = roots[1];
+                }
+            }
+            if masks_root.is_some() {
+                manifest.masks = masks_root;
+            }
+            manifest.write(path)?;
+        }
+
+        let ids = entries
+            .iter()
+            .map(|entry| entry.id.clone())
+            .collect::<Vec<_>>();
+        state.ids.append(count, &ids)?;
+
+        eprintln!(
+            "Enrolled {} entries, {} in total.",
+            templates.len(),
+            count + templates.len()
+        );
+        Ok(Enrolled {
+            first: count,
+            count: templates.len(),
+        })
+    }
+
+    /// Roll back the participants and the masks to the first `count` entries,
+    /// dropping those of an enrollment that failed.
+    ///
+    /// All participants are asked. Those that can not be reached are rolled
+    /// back by the check before the next request.
+    async fn roll_back(&self, state: &mut State, count: usize) -> Result<(), QueryError> {
+        let total = (count * self.scheme.records()) as u64;
+        let total = &total;
+        let results = join_all((0..self.participants.len()).map(|party| async move {
+            let mut channel = self.open(party).await?;
+            channel.send(Message::Truncate, &[bytes_of(total)]).await?;
+            let mut kept = 0_u64;
+            let mut roots = [Hash::default(); 2];
+            channel
+                .expect(Message::Enrolled)
+                .await?
+                .read_parts(&mut [bytes_of_mut(&mut kept), cast_slice_mut(&mut roots)])?;
+            ensure!(
+                kept == *total,
+                "Participant has {kept} records after truncation, expected {total}."
+            );
+            Result::<_>::Ok(())
+        }))
+        .await;
+
+        if let Some(masks) = &state.masks {
+            if cast_slice::<_, Bits>(masks).len() > count {
+                let path = self.masks_path.clone();
+                let legacy = self.legacy;
+                tokio::task::spawn_blocking(move || {
+                    format::truncate(&path, Kind::Masks, count, legacy)
+                })
+                .await??;
+                state.masks = Some(enroll::remap(&self.masks_path, Kind::Masks, self.legacy)?);
+            }
+        }
+
+        if let Err(err) = results.into_iter().collect::<Result<Vec<_>>>() {
+            self.repair.store(true, Ordering::Relaxed);
+            return Err(QueryError::Unavailable(Arc::new(err)));
+        }
+        eprintln!("Rolled back to {count} entries.");
+        Ok(())
+    }
+
+    /// Run multiple queries, sharing database scans where possible.
+    ///
+    /// Selections are ignored when using secure comparison.
+    pub async fn query_batch(
+        &self,
+        queries: &[Template],
+        selections: &[Selection],
+    ) -> Result<Vec<QueryResult>, QueryError> {
+        assert_eq!(queries.len(), selections.len());
+        if self.repair.load(Ordering::Relaxed) {
+            let mut state = self.state.write().await;
+            if self.repair.load(Ordering::Relaxed) {
+                if let Err(err) = self.check_all(&mut state).await {
+                    eprintln!("Could not check the participants agree on the entries: {err:#}");
+                }
+            }
+        }
+        let chunk_size = if self.shared_query || self.match_threshold.is_some() {
+            // Preprocessed material is consumed per query.
+            1
+        } else {
+            MAX_QUERIES
+        };
+        // Scans of the chunks share the connections, so they run concurrently.
+        let results = try_join_all(
+            queries
+                .chunks(chunk_size)
+                .zip(selections.chunks(chunk_size))
+                .map(|(queries, selections)| self.scan(queries, selections)),
+        )
+        .await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Evaluate queries in a single scan of the database.
+    async fn scan(
+        &self,
+        queries: &[Template],
+        selections: &[Selection],
+    ) -> Result<Vec<QueryResult>, QueryError> {
+        let query = queries[0];
+        let queries = queries.to_vec();
+        let n_queries = queries.len();
+        let parties = self.participants.len();
+        let parts = self.parts;
+        let records = self.scheme.records();
+
+        // Queries using preprocessed material consume a slot.
+        let state = self.state.read().await;
+        let query_slot = match &self.slot {
+            Some(slot) => slot.lock().await.take().await?,
+            None => 0,
+        };
+        let masks = state.masks.clone();
+        let manifest = state.manifest.as_ref();
+
+        // Secret share the query parts if requested.
+        let query_parts = [encode(&query), EncodedBits::from(&query.mask)];
+        let query_shares = self.shared_query.then(|| {
+            assert_eq!(n_queries, 1);
+            query_parts[..parts]
+                .iter()
+                .map(|part| part.share(parties))
+                .collect::<Vec<_>>()
+        });
+
+        // Contact participants
+        eprintln!("Calling participants {:?}", self.participants);
+        let connections = join_all((0..parties).map(|i| {
+            let query_shares = &query_shares;
+            let queries = &queries;
+            async move {
+                // Send queries and check the participant serves the expected data.
+                let connection = if let Some(query_shares) = query_shares {
+                    let shares = query_shares
+                        .iter()
+                        .map(|shares| shares[i])
+                        .collect::<Vec<_>>();
+                    let slot = query_slot as u64;
+                    let payload = [bytes_of(&slot), cast_slice(&shares)];
+                    self.request(i, Message::SharedQuery, &payload, manifest)
+                        .await?
+                } else {
+                    self.request(i, Message::Query, &[cast_slice(queries)], manifest)
+                        .await?
+                };
+                eprintln!("Request send.");
+                Result::<_>::Ok(connection)
+            }
+        }))
+        .await;
+
+        // Continue with the available participants if there are sufficiently many.
+        let mut available = Vec::with_capacity(parties);
+        let mut channels = Vec::with_capacity(parties);
+        let mut counts = Vec::with_capacity(parties);
+        for (party, connection) in connections.into_iter().enumerate() {
+            match connection {
+                Ok((channel, share_count)) => {
+                    available.push(party);
+                    channels.push(channel);
+                    counts.push(share_count);
+                }
+                Err(err) => eprintln!("Participant {party} unavailable: {err:#}"),
+            }
+        }
+        if (self.shared_query || self.match_threshold.is_some()) && available.len() != parties {
+            return Err(QueryError::Unavailable(Arc::new(format_err!(
+                "Preprocessed material requires all participants, only {} available.",
+                available.len()
+            ))));
+        }
+        let plan = self
+            .scheme
+            .plan(&available)
+            .ok_or_else(|| {
+                QueryError::Unavailable(Arc::new(format_err!(
+                    "Only {} participants available, {} required.",
+                    available.len(),
+                    self.scheme.threshold()
+                )))
+            })?
+            .into_iter()
+            .map(|(party, position)| {
+                let stream = available.iter().position(|&p| p == party).unwrap();
+                (stream, position)
+            })
+            .collect::<Vec<_>>();
+
+        let count = self.count(&counts, &state)?;
+
+        // Open the masked query parts and send them back to the participants.
+        if self.shared_query {
+            let masked_shares = try_join_all(channels.iter_mut().map(|channel| async {
+                let mut shares = vec![EncodedBits::default(); parts];
+                channel
+                    .receive_exact(Message::Masked, cast_slice_mut(&mut shares))
+                    .await?;
+                Result::<_>::Ok(shares)
+            }))
+            .await?;
+            let masked = (0..parts)
+                .map(|part| masked_shares.iter().map(|shares| &shares[part]).sum())
+                .collect::<Vec<EncodedBits>>();
+            let payload = [cast_slice(&masked)];
+            try_join_all(
+                channels
+                    .iter()
+                    .map(|channel| channel.send(Message::Opened, &payload)),
+            )
+            .await?;
+            eprintln!("Opened masked query using slot {query_slot}.");
+        }
+
+        // Securely compare and only learn the matching entries.
+        if let Some(threshold) = self.match_threshold {
+            assert_eq!(n_queries, 1);
+            // The threshold as an exact fraction for comparing shared denominators.
+            let (p, q) = comparison::fraction(threshold);
+            let compare = [query_slot as u64, p as u64, q as u64];
+            let payload = [cast_slice(&compare)];
+            try_join_all(
+                channels
+                    .iter()
+                    .map(|channel| channel.send(Message::Compare, &payload)),
+            )
+            .await?;
+            eprintln!("Comparing using slot {query_slot}.");
+            let progress_bar = ProgressBar::new(count as u64).with_style(self.count_style.clone());
+            let matches = secure_threshold::resolve(
+                &mut channels,
+                count,
+                masks.clone().map(|masks| (masks, query.mask)),
+                threshold,
+                |n| progress_bar.inc(n as u64),
+            )
+            .await?;
+            try_join_all(
+                channels
+                    .iter_mut()
+                    .map(|channel| channel.receive_end(count * records)),
+            )
+            .await?;
+            progress_bar.finish();
+            let matches = matches
+                .into_iter()
+                .filter(|&entry| !state.tombstones.contains(entry))
+                .collect::<Vec<_>>();
+            let ids = if state.ids.len() > 0 {
+                matches
+                    .iter()
+                    .map(|&entry| state.ids.get(entry).map(str::to_owned))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            eprintln!(
+                "Found {} entries out of {count} within distance {threshold}: {matches:?}",
+                matches.len()
+            );
+            return Ok(vec![QueryResult::Matches { matches, ids }]);
+        }
+
+        // Read the results of each participant, one per record, query and part.
+        let mut streams = channels
+            .into_iter()
+            .map(|channel| ResultStream::new(channel, n_queries * parts))
+            .collect::<Vec<_>>();
+
+        // Entries per batch, bounding memory use for many queries.
+        let batch_entries = max(BATCH_SIZE / n_queries, 1);
+
+        // Prepare local computation of denominators
+        let (sender, mut denom_receiver) = mpsc::channel(4);
+        let denomoninator_worker = if let Some(mmap) = &masks {
+            eprintln!("Locally computing denominators.");
+            let mmap_ref = mmap.clone();
+            let query_masks = queries.iter().map(|query| query.mask).collect::<Vec<_>>();
+            tokio::task::spawn_blocking(move || {
+                let masks: &[Bits] = cast_slice(&mmap_ref);
+                let engine = MasksEngine::new_batch(&query_masks);
+                for chunk in masks.chunks(batch_entries) {
+                    let mut result = vec![[0_u16; 31]; chunk.len() * n_queries];
+                    engine.batch_process(&mut result, chunk);
+                    if sender.blocking_send(result).is_err() {
+                        // The results failed and were abandoned.
+                        break;
+                    }
+                }
+                Result::<_>::Ok(())
+            })
+        } else {
+            tokio::task::spawn_blocking(|| Ok(()))
+        };
+        let local_denominators = masks.is_some();
+
+        // Collect batches of shares
+        let (sender, mut receiver) = mpsc::channel(4);
+        let mut batch_worker = AbortOnDrop(tokio::task::spawn(async move {
+            for start in (0..count).step_by(batch_entries) {
+                let batch_size = min(batch_entries, count - start);
+
+                // Wait on all parts concurrently
+                let streams_future = try_join_all(streams.iter_mut().enumerate().map(
+                    |(i, stream)| async move {
+                        stream
+                            .take(batch_size * records)
+                            .await
+                            .with_context(|| format!("Results of participant {i} failed"))
+                    },
+                ));
+                let denom_future = async {
+                    if local_denominators {
+                        Some(denom_receiver.recv().await.unwrap_or_default())
+                    } else {
+                        None
+                    }
+                };
+                let (denom, shares) = join!(denom_future, streams_future);
+                let shares = shares?;
+                if let Some(denom) = &denom {
+                    ensure!(
+                        denom.len() == batch_size * n_queries,
+                        "Denominators ended before the results."
+                    );
+                }
+
+                // Send batches
+                sender.send((batch_size, denom, shares)).await?;
+            }
+
+            // Participants confirm they sent results for all records.
+            try_join_all(
+                streams
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(i, stream)| async move {
+                        stream
+                            .finish(count * records)
+                            .await
+                            .with_context(|| format!("Results of participant {i} failed"))
+                    }),
+            )
+            .await?;
+            Result::<_>::Ok(())
+        }));
+
+        // Keep track of the closest entries for each query.
+        let mut neighbors = selections
+            .iter()
+            .map(Selection::neighbors)
+            .collect::<Vec<_>>();
+
+        // Process results
+        eprintln!("Processing results for {n_queries} queries.");
+        let progress_bar = ProgressBar::new(count as u64).with_style(self.count_style.clone());
+        let mut i = 0;
+        loop {
+            // Fetch batches of denominators and shares
+            let Some((batch_size, denom_batch, shares)) = receiver.recv().await else {
+                break;
+            };
+
+            // Compute batch of distances in Rayon
+            let plan = plan.clone();
+            let worker = tokio::task::spawn_blocking(move || {
+                (0..batch_size * n_queries)
+                    .into_par_iter()
+                    .map(|index| {
+                        let (i, q) = (index / n_queries, index % n_queries);
+
+                        // Reconstruct each part from the planned records
+                        let mut sums = [[0_u16; 31]; 2];
+                        for &(stream, position) in plan.iter() {
+                            let record = ((i * records + position) * n_queries + q) * parts;
+                            for (part, sum) in sums[..parts].iter_mut().enumerate() {
+                                let share = shares[stream][record + part];
+                                for (n, &s) in sum.iter_mut().zip(share.iter()) {
+                                    *n = n.wrapping_add(s);
+                                }
+                            }
+                        }
+                        let [numerator, denominator] = sums;
+                        let denominator = denom_batch.as_ref().map_or(denominator, |d| d[index]);
+                        decode_rotation(&numerator, &denominator)
+                    })
+                    .collect::<Vec<_>>()
+            });
+            let distances = worker.await?;
+
+            // Aggregate distances
+            for (index, (distance, rotation)) in distances.into_iter().enumerate() {
+                let (j, q) = (index / n_queries, index % n_queries);
+                if state.tombstones.contains(i + j) {
+                    continue;
+                }
+                neighbors[q].push(Neighbor {
+                    index: i + j,
+                    distance,
+                    rotation,
+                });
+            }
+
+            // Update counter
+            i += batch_size;
+            progress_bar.inc(batch_size as u64);
+        }
+        progress_bar.finish();
+
+        // Await processes.
+        // Closing the receiver stops the batch worker at its next batch, which
+        // drops the streams and the receiver of denominators, stopping their
+        // workers too. Should the scan be abandoned instead, the batch worker is
+        // aborted, to the same effect.
+        drop(receiver);
+        denomoninator_worker.await??;
+        (&mut batch_worker.0).await??;
+
+        if i != count {
+            return Err(format_err!("Received results for {i} out of {count} entries.").into());
+        }
+        neighbors
+            .into_iter()
+            .zip(selections)
+            .map(|(neighbors, selection)| {
+                let neighbors = neighbors.into_sorted_vec();
+                if *selection != Selection::default() {
+                    eprintln!("Found {} entries out of {i}.", neighbors.len());
+                    let neighbors = neighbors
+                        .into_iter()
+                        .map(|neighbor| Identified {
+                            id: state.ids.get(neighbor.index).map(str::to_owned),
+                            neighbor,
+                        })
+                        .collect();
+                    return Ok(QueryResult::Neighbors { neighbors });
+                }
+                let Some(&Neighbor {
+                    index,
+                    distance,
+                    rotation,
+                }) = neighbors.first()
+                else {
+                    return Err(QueryError::Empty);
+                };
+                eprintln!(
+                    "Found closest entry at {index} out of {i} at distance {distance} (rotation \
+                     {rotation})."
+                );
+                Ok(QueryResult::Closest {
+                    index,
+                    id: state.ids.get(index).map(str::to_owned),
+                    distance,
+                    rotation,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Aborts a spawned task when dropped, so it does not outlive an abandoned
+/// scan.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{Header, Writer},
+        merkle,
+        participant::Participant,
+        shutdown::Signals,
+        ParticipantArgs,
+    };
+    use rand::{thread_rng, Rng};
+    use std::{fs, path::Path};
+    use tokio::net::TcpListener;
+
+    fn entries(ids: &[&str]) -> Vec<Entry> {
+        ids.iter()
+            .map(|id| Entry {
+                pattern: thread_rng().gen(),
+                mask:    thread_rng().gen(),
+                id:      Some(id.to_string()),
+            })
+            .collect()
+    }
+
+    /// Start a participant serving the share at `input`.
+    async fn participant(input: &Path) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let args = ParticipantArgs {
+            input:          input.to_path_buf(),
+            bind:           listener.local_addr().unwrap(),
+            triples:        None,
+            comparisons:    None,
+            masks:          None,
+            tombstones:     None,
+            party:          None,
+            dataset:        None,
+            legacy:         false,
+            max_scans:      4,
+            max_queued:     16,
+            shutdown_grace: 0,
+        };
+        let style = ProgressStyle::default_bar();
+        let participant = Arc::new(Participant::new(&args, style.clone(), style).unwrap());
+        tokio::spawn(participant.serve(listener, Signals::new().unwrap()));
+        args.bind
+    }
+
+    /// Arguments for a resolver of `participants`, keeping its files in `dir`.
+    fn args(dir: &Path, participants: Vec<SocketAddr>) -> ResolverArgs {
+        ResolverArgs {
+            masks: dir.join("masks"),
+            shared_masks: false,
+            share: None,
+            bind: "127.0.0.1:0".parse().unwrap(),
+            shared_query: false,
+            first_slot: 0,
+            next_slot: dir.join("next-slot"),
+            threshold: None,
+            match_threshold: None,
+            tombstones: dir.join("tombstones"),
+            ids: dir.join("ids"),
+            coalesce_window: 0,
+            max_batch: 64,
+            query_timeout: 0,
+            shutdown_grace: 0,
+            manifest: None,
+            legacy: false,
+            participants,
+        }
+    }
+
+    #[test]
+    fn test_match_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let participants = vec!["127.0.0.1:1".parse().unwrap(); 2];
+        let mut args = args(dir.path(), participants);
+        for threshold in [-0.1, 1.5, f64::NAN, f64::INFINITY] {
+            args.match_threshold = Some(threshold);
+            let err = Resolver::new(&args, ProgressStyle::default_bar())
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("out of range"), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_enrollment() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheme = Replicated::new(2, 2).unwrap();
+        let records = scheme.records();
+        let shares = [dir.path().join("share0"), dir.path().join("share1")];
+        for (party, path) in shares.iter().enumerate() {
+            let header = Header::new(Kind::Share, party, 2, 2, records, Dataset::default());
+            Writer::create(path, header)
+                .await
+                .unwrap()
+                .finish()
+                .await
+                .unwrap();
+        }
+        let masks = dir.path().join("masks");
+        let header = Header::new(Kind::Masks, 0, 2, 2, 1, Dataset::default());
+        Writer::create(&masks, header)
+            .await
+            .unwrap()
+            .finish()
+            .await
+            .unwrap();
+
+        // The first participant kept an entry of an enrollment interrupted
+        // before the previous run of the resolver could roll it back.
+        let kept = (0..records)
+            .map(|_| thread_rng().gen())
+            .collect::<Vec<EncodedBits>>();
+        format::append(&shares[0], cast_slice(&kept), false).unwrap();
+
+        let participants = vec![participant(&shares[0]).await, participant(&shares[1]).await];
+        let args = args(dir.path(), participants);
+        let resolver = Resolver::new(&args, ProgressStyle::default_bar()).unwrap();
+        let counts = || async { resolver.statuses(None).await.unwrap() };
+        let enrolled = resolver.enroll(&entries(&["a", "b"])).await.unwrap();
+        assert_eq!(enrolled, Enrolled { first: 0, count: 2 });
+        assert_eq!(counts().await, [2 * records; 2]);
+
+        // The second participant fails mid-enrollment, so the first one rolls
+        // back the entries it enrolled.
+        let moved = dir.path().join("moved");
+        fs::rename(&shares[1], &moved).unwrap();
+        assert!(resolver.enroll(&entries(&["c", "d"])).await.is_err());
+        assert_eq!(counts().await, [2 * records; 2]);
+        let state = resolver.state.read().await;
+        assert_eq!(
+            cast_slice::<_, Bits>(state.masks.as_ref().unwrap()).len(),
+            2
+        );
+        assert_eq!(state.ids.find("c"), None);
+        drop(state);
+
+        // Later enrollments continue from the committed entries.
+        fs::rename(&moved, &shares[1]).unwrap();
+        let enrolling = entries(&["c", "d"]);
+        let enrolled = resolver.enroll(&enrolling).await.unwrap();
+        assert_eq!(enrolled, Enrolled { first: 2, count: 2 });
+        assert_eq!(counts().await, [4 * records; 2]);
+        assert_eq!(resolver.state.read().await.ids.find("d"), Some(3));
+        let masks = DataFile::open(&masks, Kind::Masks, false).unwrap();
+        assert_eq!(cast_slice::<_, Bits>(&masks).len(), 4);
+        for share in &shares {
+            let header = DataFile::open(share, Kind::Share, false)
+                .unwrap()
+                .header()
+                .copied()
+                .unwrap();
+            assert_eq!(header.count(), 4 * records);
+            assert!(merkle::check(share, &header).unwrap().is_empty());
+        }
+
+        // And are found by queries.
+        let query = enrolling[1].template();
+        let results = resolver
+            .query_batch(&[query], &[Selection::default()])
+            .await
+            .unwrap();
+        let QueryResult::Closest { index, id, .. } = &results[0] else {
+            panic!("Expected the closest entry, got {results:?}");
+        };
+        assert_eq!((*index, id.as_deref()), (3, Some("d")));
+    }
+}
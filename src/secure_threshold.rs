@@ -0,0 +1,410 @@
+use crate::{
+    format::DataFile,
+    protocol::{Channel, Message},
+};
+use anyhow::{bail, ensure, Context, Result};
+use bytemuck::{cast_slice, cast_slice_mut, from_bytes, Pod};
+use futures::future::try_join_all;
+use memmap::{Mmap, MmapOptions};
+use mpc_iris_code::{
+    comparison::{
+        deal_shared, expand_shared, offsets, open_bits, open_masked, Comparator, Extender,
+        Material, Ring, BATCH, BLOCK, SHARED_CORRECTION_WORDS,
+    },
+    Bits, MasksEngine,
+};
+use rand::{thread_rng, Rng};
+use rayon::prelude::*;
+use std::{
+    cmp::min,
+    fs::File,
+    io::{BufWriter, Write as _},
+    mem::size_of,
+    ops::Range,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::Arc,
+};
+
+/// Preprocessed material for secure comparisons.
+///
+/// The file starts with a header `[party, parties, entries, slots, shared]` of
+/// `u64`s followed by the slots, one per query. Each slot contains this party's
+/// seed and for the last party the corrections for every block. A slot is
+/// consumed by overwriting its seed with zeros, so it can never be used twice.
+///
+/// Material for `shared` masks compares secret-shared denominators.
+pub struct Comparisons {
+    file:    File,
+    mmap:    Arc<Mmap>,
+    party:   usize,
+    parties: usize,
+    entries: usize,
+    slots:   usize,
+    shared:  bool,
+}
+
+impl Comparisons {
+    pub fn open(path: &Path, entries: usize, shared: bool) -> Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open comparisons at {path:?}"))?;
+        let mmap = Arc::new(unsafe { MmapOptions::new().map(&file)? });
+        ensure!(
+            mmap.len() >= Self::header_size(),
+            "Comparisons file {path:?} invalid."
+        );
+        let header: &[u64; 5] = from_bytes(&mmap[..Self::header_size()]);
+        let [party, parties, file_entries, slots, file_shared] = header.map(|v| v as usize);
+        ensure!(
+            file_entries == entries,
+            "Comparisons file {path:?} is for {file_entries} entries, expected {entries}."
+        );
+        if (file_shared != 0) != shared {
+            let kind = if shared { "plaintext" } else { "secret-shared" };
+            bail!("Comparisons file {path:?} is for {kind} masks.");
+        }
+        ensure!(
+            mmap.len()
+                == Self::header_size() + slots * Self::slot_size(party, parties, entries, shared),
+            "Comparisons file {path:?} invalid."
+        );
+        Ok(Self {
+            file,
+            mmap,
+            party,
+            parties,
+            entries,
+            slots,
+            shared,
+        })
+    }
+
+    pub fn header_size() -> usize {
+        size_of::<[u64; 5]>()
+    }
+
+    pub fn slot_size(party: usize, parties: usize, entries: usize, shared: bool) -> usize {
+        let words = if shared {
+            SHARED_CORRECTION_WORDS
+        } else {
+            Material::<u16>::CORRECTION_WORDS
+        };
+        let corrections = if party == parties - 1 {
+            entries.div_ceil(BLOCK) * words * size_of::<u64>()
+        } else {
+            0
+        };
+        size_of::<[u8; 32]>() + corrections
+    }
+
+    pub fn party(&self) -> usize {
+        self.party
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    fn offset(&self, slot: usize) -> usize {
+        Self::header_size()
+            + slot * Self::slot_size(self.party, self.parties, self.entries, self.shared)
+    }
+
+    /// This party's seed for `slot`.
+    pub fn seed(&self, slot: usize) -> Result<[u8; 32]> {
+        if slot >= self.slots {
+            bail!("Slot {slot} out of range, only {} available.", self.slots);
+        }
+        let start = self.offset(slot);
+        let seed: [u8; 32] = self.mmap[start..start + 32].try_into().unwrap();
+        if seed == [0; 32] {
+            bail!("Slot {slot} has already been used.");
+        }
+        Ok(seed)
+    }
+
+    /// Mark `slot` as used by durably zeroing its seed.
+    pub fn consume(&self, slot: usize) -> Result<()> {
+        self.file.write_all_at(&[0; 32], self.offset(slot) as u64)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Shared handle and byte range of the corrections for `slot`, if any.
+    pub fn corrections(&self, slot: usize) -> Option<(Arc<Mmap>, Range<usize>)> {
+        let start = self.offset(slot) + 32;
+        let end = self.offset(slot + 1);
+        (end > start).then(|| (self.mmap.clone(), start..end))
+    }
+}
+
+/// A participant's material for comparing the results of one query.
+#[derive(Clone)]
+pub struct Slot {
+    pub seed:        [u8; 32],
+    pub corrections: Option<(Arc<Mmap>, Range<usize>)>,
+    /// The resolver's threshold as a fraction, for shared denominators.
+    pub fraction:    (u32, u32),
+}
+
+/// Participant side of the comparison protocol for one batch of numerators.
+///
+/// Every message is sent in an exchange frame. With secret-shared masks the
+/// shares of the `denominators` are compared with the threshold [`fraction`]
+/// of the `slot`, without opening them.
+///
+/// [`fraction`]: mpc_iris_code::comparison::fraction
+pub async fn participate(
+    channel: &mut Channel,
+    leader: bool,
+    slot: Slot,
+    start: usize,
+    numerators: Vec<[u16; 31]>,
+    denominators: Option<Vec<[u16; 31]>>,
+) -> Result<()> {
+    assert_eq!(start % BLOCK, 0);
+    let Slot {
+        seed,
+        corrections,
+        fraction,
+    } = slot;
+    let blocks = start / BLOCK..(start + numerators.len()).div_ceil(BLOCK);
+    let Some(denominators) = denominators else {
+        let comparator = tokio::task::spawn_blocking(move || {
+            let words = Material::<u16>::CORRECTION_WORDS;
+            let corrections = corrections.as_ref().map(|(mmap, range)| {
+                let corrections: &[u64] = cast_slice(&mmap[range.clone()]);
+                &corrections[blocks.start * words..blocks.end * words]
+            });
+            Comparator::new(leader, Comparator::expand(&seed, blocks, corrections))
+        });
+        let mut comparator = comparator.await?;
+
+        // Open masked values
+        let opened = exchange(channel, &comparator.mask(&numerators)).await?;
+        comparator.open(&opened);
+        return evaluate(channel, comparator).await;
+    };
+    let material = tokio::task::spawn_blocking(move || {
+        let corrections = corrections.as_ref().map(|(mmap, range)| {
+            let corrections: &[u64] = cast_slice(&mmap[range.clone()]);
+            &corrections
+                [blocks.start * SHARED_CORRECTION_WORDS..blocks.end * SHARED_CORRECTION_WORDS]
+        });
+        let (extension, material) = expand_shared(&seed, blocks, corrections);
+        (
+            Extender::new(leader, extension),
+            Comparator::new(leader, material),
+        )
+    });
+    let (extender, mut comparator) = material.await?;
+
+    // Extend the shares of the differences and denominators
+    let opened = exchange(channel, &extender.mask(&numerators, &denominators)).await?;
+
+    // Open the masked scaled differences
+    let scaled = extender.scaled(&opened, fraction);
+    let opened = exchange(channel, &comparator.mask(&scaled)).await?;
+    comparator.open(&opened);
+    evaluate(channel, comparator).await
+}
+
+/// Send a message to the resolver and receive the opened values, of the same
+/// size.
+async fn exchange<T: Pod>(channel: &mut Channel, message: &[T]) -> Result<Vec<T>> {
+    channel
+        .send(Message::Exchange, &[cast_slice(message)])
+        .await?;
+    let mut opened = vec![T::zeroed(); message.len()];
+    channel
+        .receive_exact(Message::Exchange, cast_slice_mut(&mut opened))
+        .await?;
+    Ok(opened)
+}
+
+/// Evaluate the circuit and reveal the match bits to the resolver.
+async fn evaluate<T: Ring>(channel: &mut Channel, mut comparator: Comparator<T>) -> Result<()> {
+    for _ in 0..Comparator::<T>::ROUNDS {
+        let opened = exchange(channel, &comparator.message()).await?;
+        comparator.receive(&opened);
+    }
+    let result = comparator.result();
+    channel
+        .send(Message::Exchange, &[cast_slice(&result)])
+        .await?;
+    Ok(())
+}
+
+/// Resolver side of the comparison protocol. Returns the indices of matching
+/// entries.
+///
+/// Denominators are computed locally from the plaintext `masks` and the query
+/// mask if given, otherwise the participants compare their shares with the
+/// threshold [`fraction`] and only masked values are opened.
+///
+/// [`fraction`]: mpc_iris_code::comparison::fraction
+pub async fn resolve(
+    channels: &mut [Channel],
+    count: usize,
+    masks: Option<(Arc<DataFile>, Bits)>,
+    threshold: f64,
+    mut progress: impl FnMut(usize),
+) -> Result<Vec<usize>> {
+    let mut matches = Vec::new();
+    for start in (0..count).step_by(BATCH) {
+        let len = min(BATCH, count - start);
+        let blocks = len.div_ceil(BLOCK);
+        let opened = if let Some((masks, query)) = &masks {
+            // Compute public offsets from the denominators
+            let (masks, query) = (masks.clone(), *query);
+            let worker = tokio::task::spawn_blocking(move || {
+                let masks: &[Bits] = cast_slice(&masks);
+                let mut denominators = vec![[0_u16; 31]; len];
+                MasksEngine::new(&query)
+                    .batch_process(&mut denominators, &masks[start..start + len]);
+                offsets(&denominators, threshold)
+            });
+
+            // Open masked values
+            let messages = receive_all(channels, len).await?;
+            let mut opened = worker.await?;
+            open_masked(&mut opened, &messages);
+            broadcast(channels, cast_slice(&opened)).await?;
+            relay::<u16>(channels, blocks).await?
+        } else {
+            // Open the masked differences and denominators to extend them
+            let messages = receive_all(channels, 2 * len).await?;
+            let mut opened = vec![[0_u16; 31]; 2 * len];
+            open_masked(&mut opened, &messages);
+            broadcast(channels, cast_slice(&opened)).await?;
+
+            // Open the masked scaled differences
+            let messages = receive_all(channels, len).await?;
+            let mut opened = vec![[0_u32; 31]; len];
+            open_masked(&mut opened, &messages);
+            broadcast(channels, cast_slice(&opened)).await?;
+            relay::<u32>(channels, blocks).await?
+        };
+        matches.extend(
+            (0..len)
+                .filter(|i| (opened[i / BLOCK] >> (i % BLOCK)) & 1 == 1)
+                .map(|i| start + i),
+        );
+        progress(len);
+    }
+    Ok(matches)
+}
+
+/// Receive a message of `len` values from every participant.
+async fn receive_all<T: Pod>(channels: &mut [Channel], len: usize) -> Result<Vec<Vec<T>>> {
+    try_join_all(channels.iter_mut().map(|channel| async move {
+        let mut message = vec![T::zeroed(); len];
+        channel
+            .receive_exact(Message::Exchange, cast_slice_mut(&mut message))
+            .await?;
+        Result::<_>::Ok(message)
+    }))
+    .await
+}
+
+/// Send the same opened values to every participant.
+async fn broadcast(channels: &[Channel], opened: &[u8]) -> Result<()> {
+    let payload = [opened];
+    try_join_all(
+        channels
+            .iter()
+            .map(|channel| channel.send(Message::Exchange, &payload)),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Relay the rounds of the circuit and return the opened match bits, one
+/// word per block.
+async fn relay<T: Ring>(channels: &mut [Channel], blocks: usize) -> Result<Vec<u64>> {
+    for round in 0..Comparator::<T>::ROUNDS {
+        let messages = receive_all(channels, Comparator::<T>::message_words(round, blocks)).await?;
+        broadcast(channels, cast_slice(&open_bits(&messages))).await?;
+    }
+
+    // Final round reveals the match bits
+    let messages = receive_all(channels, blocks).await?;
+    Ok(open_bits(&messages))
+}
+
+/// Write a comparisons file for each party with `slots` fresh slots, for
+/// plaintext or `shared` masks.
+pub fn deal(base: &Path, parties: usize, entries: usize, slots: usize, shared: bool) -> Result<()> {
+    let mut outputs = Vec::with_capacity(parties);
+    for party in 0..parties {
+        let path = base.with_extension(format!("compare-{party}"));
+        let file =
+            File::create(&path).with_context(|| format!("Failed to create file at {path:?}"))?;
+        let mut output = BufWriter::new(file);
+        let header = [party, parties, entries, slots, shared as usize].map(|v| v as u64);
+        output.write_all(cast_slice(&header))?;
+        outputs.push(output);
+    }
+    let mut rng = thread_rng();
+    for _ in 0..slots {
+        let seeds = (0..parties).map(|_| rng.gen()).collect::<Vec<[u8; 32]>>();
+        for (output, seed) in outputs.iter_mut().zip(seeds.iter()) {
+            output.write_all(seed)?;
+        }
+        let blocks = entries.div_ceil(BLOCK);
+        let last = outputs.last_mut().unwrap();
+        for start in (0..blocks).step_by(BATCH / BLOCK) {
+            let corrections = (start..min(start + BATCH / BLOCK, blocks))
+                .into_par_iter()
+                .flat_map_iter(|block| {
+                    if shared {
+                        deal_shared(&seeds, block)
+                    } else {
+                        Material::<u16>::deal(&seeds, block)
+                    }
+                })
+                .collect::<Vec<_>>();
+            last.write_all(cast_slice(&corrections))?;
+        }
+    }
+    for output in &mut outputs {
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let (parties, entries) = (3, 100);
+        for shared in [false, true] {
+            let base = dir.path().join(if shared { "shared" } else { "plain" });
+            deal(&base, parties, entries, 2, shared).unwrap();
+            let words = if shared {
+                SHARED_CORRECTION_WORDS
+            } else {
+                Material::<u16>::CORRECTION_WORDS
+            };
+            for party in 0..parties {
+                let path = base.with_extension(format!("compare-{party}"));
+                let comparisons = Comparisons::open(&path, entries, shared).unwrap();
+                assert_eq!(comparisons.party(), party);
+                let corrections = comparisons.corrections(1).map(|(_, range)| range.len());
+                let expected = entries.div_ceil(BLOCK) * words * size_of::<u64>();
+                assert_eq!(corrections, (party == parties - 1).then_some(expected));
+
+                // Material of the other mode is rejected, even without corrections.
+                let err = Comparisons::open(&path, entries, !shared).err().unwrap();
+                assert!(err.to_string().contains("masks"), "{err}");
+                assert!(Comparisons::open(&path, entries + 1, shared).is_err());
+            }
+        }
+    }
+}
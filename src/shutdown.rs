@@ -0,0 +1,97 @@
+use anyhow::Result;
+use std::{future::Future, time::Duration};
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    time::timeout,
+};
+
+/// SIGINT and SIGTERM, which ask a server to shut down.
+///
+/// The first lets requests in flight finish for a grace period, a second
+/// cancels them right away.
+pub struct Signals {
+    interrupt: Signal,
+    terminate: Signal,
+}
+
+impl Signals {
+    /// Handle the signals from now on, instead of being killed by them.
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Wait for either signal, returning its name.
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+
+    /// Wait for requests in flight to `finish`, for at most `grace` or until
+    /// another signal. Returns whether they finished.
+    pub async fn drain(&mut self, grace: Duration, finish: impl Future<Output = ()>) -> bool {
+        drain(grace, finish, self.recv()).await
+    }
+}
+
+/// Wait for `finish`, for at most `grace` or until `signalled` returns the
+/// name of a signal. Returns whether it finished.
+pub async fn drain(
+    grace: Duration,
+    finish: impl Future<Output = ()>,
+    signalled: impl Future<Output = &'static str>,
+) -> bool {
+    tokio::select! {
+        finished = timeout(grace, finish) => {
+            if finished.is_err() {
+                eprintln!("Requests still in flight after {grace:?}, cancelling them.");
+            }
+            finished.is_ok()
+        }
+        name = signalled => {
+            eprintln!("Received {name} again, cancelling requests in flight.");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::pending;
+    use tokio::time::{sleep, Instant};
+
+    const GRACE: Duration = Duration::from_secs(10);
+
+    /// A signal after `seconds`.
+    async fn signal_after(seconds: u64) -> &'static str {
+        sleep(Duration::from_secs(seconds)).await;
+        "SIGTERM"
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_finished() {
+        let start = Instant::now();
+        let finish = sleep(Duration::from_secs(3));
+        assert!(drain(GRACE, finish, signal_after(5)).await);
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_grace() {
+        let start = Instant::now();
+        assert!(!drain(GRACE, pending(), pending()).await);
+        assert_eq!(start.elapsed(), GRACE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_second_signal() {
+        let start = Instant::now();
+        assert!(!drain(GRACE, pending(), signal_after(2)).await);
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::OpenOptions,
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+
+/// The next unused slot of preprocessed material, persisted by the resolver.
+///
+/// Participants zero the material of a slot once it is used, so a resolver
+/// restarting from an earlier slot would only have its queries fail. The file
+/// holds the next slot as a `u64`, which is recorded before a slot is handed
+/// out. A query that fails afterwards skips its slot rather than reusing it.
+pub struct NextSlot {
+    path: PathBuf,
+    file: File,
+    next: usize,
+}
+
+impl NextSlot {
+    /// Continue from the slot recorded at `path`, or from `first` if that is
+    /// later or nothing is recorded yet.
+    pub fn open(path: &Path, first: usize) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open next slot file {path:?}"))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let recorded = match bytes.len() {
+            0 => 0,
+            8 => u64::from_le_bytes(bytes.try_into().unwrap()) as usize,
+            len => bail!("Next slot file {path:?} has {len} bytes, expected 8."),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: File::from_std(file),
+            next: recorded.max(first),
+        })
+    }
+
+    /// The slot the next query will use.
+    pub fn peek(&self) -> usize {
+        self.next
+    }
+
+    /// Hand out the next slot, once it is durably recorded as used.
+    pub async fn take(&mut self) -> Result<usize> {
+        let slot = self.next;
+        let recorded = async {
+            self.file.seek(SeekFrom::Start(0)).await?;
+            self.file
+                .write_all(&(slot as u64 + 1).to_le_bytes())
+                .await?;
+            self.file.sync_data().await
+        };
+        recorded
+            .await
+            .with_context(|| format!("Failed to record the next slot in {:?}", self.path))?;
+        self.next += 1;
+        Ok(slot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_next_slot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("next-slot");
+        let mut slots = NextSlot::open(&path, 2).unwrap();
+        assert_eq!(slots.peek(), 2);
+        assert_eq!(slots.take().await.unwrap(), 2);
+        assert_eq!(slots.take().await.unwrap(), 3);
+        drop(slots);
+
+        // A restart continues after the slots handed out, unless told to
+        // start later.
+        let mut slots = NextSlot::open(&path, 0).unwrap();
+        assert_eq!(slots.take().await.unwrap(), 4);
+        drop(slots);
+        let slots = NextSlot::open(&path, 9).unwrap();
+        assert_eq!(slots.peek(), 9);
+
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(NextSlot::open(&path, 0).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{Bits, Template, COLS, ROWS};
+use rand::Rng;
+
+/// Radial positions per filter channel. Rows are taken to be four channels
+/// of this many radial positions each, from the pupil outwards.
+const RADII: usize = 16;
+
+/// Generator of synthetic iris codes resembling real captures.
+///
+/// Every identity has a random iris code. A capture of it is rotated by a
+/// random offset, has runs of flipped bits along the rows, and is masked by
+/// eyelids reaching in from the outer radius at the top and bottom of the
+/// iris. Captures of one identity are at a distance of around
+/// `2 * flip_rate * (1 - flip_rate)`, of different identities just below
+/// one half.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Synthetic {
+    /// Fraction of bits flipped in a capture, in `[0, 1]`.
+    pub flip_rate:        f64,
+    /// Correlation of flips with the previous bit in the row, in `[0, 1)`.
+    /// Higher values give fewer but longer runs of flips.
+    pub flip_correlation: f64,
+    /// Largest rotation between two captures in columns, within the range
+    /// searched by [`Template::distance`] at the default of 15.
+    pub max_rotation:     i32,
+    /// Average fraction of bits masked by the eyelids, in `[0, 0.2]` to
+    /// be accurate.
+    pub occlusion:        f64,
+}
+
+impl Default for Synthetic {
+    fn default() -> Self {
+        Self {
+            flip_rate:        0.15,
+            flip_correlation: 0.6,
+            max_rotation:     15,
+            occlusion:        0.2,
+        }
+    }
+}
+
+impl Synthetic {
+    /// The iris code of a new identity.
+    pub fn iris<R: Rng + ?Sized>(&self, rng: &mut R) -> Bits {
+        rng.gen()
+    }
+
+    /// A noisy capture of the identity with code `iris`.
+    pub fn capture<R: Rng + ?Sized>(&self, iris: &Bits, rng: &mut R) -> Template {
+        // Captures are rotated by half the range either way of the iris.
+        let rotation = rng.gen_range(-self.max_rotation / 2..=self.max_rotation / 2);
+        let mut pattern = iris.rotated(rotation);
+
+        // Flips follow a Markov chain along each row whose stationary rate is
+        // the flip rate.
+        let start = self.flip_rate * (1.0 - self.flip_correlation);
+        let stay = self.flip_rate + self.flip_correlation * (1.0 - self.flip_rate);
+        for row in 0..ROWS {
+            let mut flipped = false;
+            for col in 0..COLS {
+                flipped = rng.gen_bool(if flipped { stay } else { start });
+                if flipped {
+                    let index = row * COLS + col;
+                    pattern.set(index, !pattern[index]);
+                }
+            }
+        }
+
+        Template {
+            pattern,
+            mask: self.eyelids(rng),
+        }
+    }
+
+    /// Captures of a new identity.
+    pub fn identity<R: Rng + ?Sized>(&self, captures: usize, rng: &mut R) -> Vec<Template> {
+        let iris = self.iris(rng);
+        (0..captures).map(|_| self.capture(&iris, rng)).collect()
+    }
+
+    /// Mask of the eyelids, parabolas centered on the top and bottom of the
+    /// iris spanning half its circumference.
+    ///
+    /// An eyelid reaching in by a fraction `d` of the radius covers about
+    /// `d / 3` of the bits. The upper eyelid accounts for three quarters of
+    /// the occlusion on average.
+    fn eyelids<R: Rng + ?Sized>(&self, rng: &mut R) -> Bits {
+        let upper = rng.gen_range(0.0..=(4.5 * self.occlusion).min(1.0));
+        let lower = rng.gen_range(0.0..=(1.5 * self.occlusion).min(1.0));
+        let mut mask = !&Bits::default();
+        for (center, depth) in [(COLS / 4, upper), (3 * COLS / 4, lower)] {
+            for col in 0..COLS {
+                let x = (col as f64 - center as f64) / (COLS / 4) as f64;
+                let reach = (depth * (1.0 - x * x).max(0.0) * RADII as f64).round() as usize;
+                for radius in RADII - reach..RADII {
+                    for channel in 0..ROWS / RADII {
+                        mask.set((channel * RADII + radius) * COLS + col, false);
+                    }
+                }
+            }
+        }
+        mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BITS;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn mean(values: impl Iterator<Item = f64>) -> f64 {
+        let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+        sum / count as f64
+    }
+
+    #[test]
+    fn test_distances() {
+        let mut rng = ChaCha20Rng::seed_from_u64(1);
+        let synthetic = Synthetic::default();
+        let identities = (0..20)
+            .map(|_| synthetic.identity(2, &mut rng))
+            .collect::<Vec<_>>();
+        let genuine = mean(identities.iter().map(|c| c[0].distance(&c[1])));
+        let impostor = mean(identities.windows(2).map(|w| w[0][0].distance(&w[1][1])));
+        assert!((0.2..0.3).contains(&genuine), "genuine {genuine}");
+        assert!((0.42..0.5).contains(&impostor), "impostor {impostor}");
+    }
+
+    #[test]
+    fn test_occlusion() {
+        let mut rng = ChaCha20Rng::seed_from_u64(2);
+        let synthetic = Synthetic::default();
+        let iris = synthetic.iris(&mut rng);
+        let occlusion = mean((0..200).map(|_| {
+            let capture = synthetic.capture(&iris, &mut rng);
+            1.0 - capture.mask.count_ones() as f64 / BITS as f64
+        }));
+        assert!(
+            (occlusion - synthetic.occlusion).abs() < 0.03,
+            "{occlusion}"
+        );
+    }
+
+    #[test]
+    fn test_flip_rate() {
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let synthetic = Synthetic {
+            max_rotation: 0,
+            ..Synthetic::default()
+        };
+        let iris = synthetic.iris(&mut rng);
+        let flips = mean((0..50).map(|_| {
+            let capture = synthetic.capture(&iris, &mut rng);
+            let flips = (0..BITS).filter(|&i| capture.pattern[i] != iris[i]).count();
+            flips as f64 / BITS as f64
+        }));
+        assert!((flips - synthetic.flip_rate).abs() < 0.01, "{flips}");
+    }
+}
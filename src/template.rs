@@ -76,30 +76,61 @@ impl Distribution<Template> for Standard {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::{Synthetic, COLS, ROWS};
     use float_eq::assert_float_eq;
-    use std::fs::File;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
 
-    #[derive(Deserialize)]
     pub struct Distance {
         pub left:     usize,
         pub right:    usize,
         pub distance: f64,
     }
 
-    pub fn test_data() -> (Vec<Template>, Vec<Distance>) {
-        let file = File::open("data/templates.json").unwrap();
-        let data: Vec<Template> = serde_json::from_reader(file).unwrap();
-        assert_eq!(data.len(), 2092);
-
-        let file = File::open("data/distances.json").unwrap();
-        let distances: Vec<Distance> = serde_json::from_reader(file).unwrap();
-        assert_eq!(distances.len(), 1000);
+    /// Fractional Hamming distance minimized over rotations, bit by bit.
+    fn reference_distance(a: &Template, b: &Template) -> f64 {
+        (-15..=15_i32)
+            .map(|rotation| {
+                let (mut num, mut den) = (0, 0);
+                for row in 0..ROWS {
+                    for col in 0..COLS {
+                        let i = row * COLS + col;
+                        let j =
+                            row * COLS + (col as i32 + rotation).rem_euclid(COLS as i32) as usize;
+                        if a.mask[j] && b.mask[i] {
+                            den += 1;
+                            if a.pattern[j] != b.pattern[i] {
+                                num += 1;
+                            }
+                        }
+                    }
+                }
+                num as f64 / den as f64
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
 
+    /// Synthetic captures of 50 identities, with a genuine and an impostor
+    /// pair for each identity.
+    pub fn test_data() -> (Vec<Template>, Vec<Distance>) {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let synthetic = Synthetic::default();
+        let data = (0..50)
+            .flat_map(|_| synthetic.identity(2, &mut rng))
+            .collect::<Vec<_>>();
+        let distances = (0..data.len())
+            .step_by(2)
+            .flat_map(|left| [(left, left + 1), (left, (left + 3) % data.len())])
+            .map(|(left, right)| Distance {
+                left,
+                right,
+                distance: reference_distance(&data[left], &data[right]),
+            })
+            .collect::<Vec<_>>();
         (data, distances)
     }
 
     #[test]
-    // #[ignore] // Requires test data
     fn test_distance_ref() {
         let (data, distances) = test_data();
 
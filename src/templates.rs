@@ -0,0 +1,310 @@
+use anyhow::{bail, ensure, Context, Result};
+use bytemuck::{bytes_of, bytes_of_mut, cast_slice, Pod, Zeroable};
+use crc32fast::Hasher;
+use memmap::{Mmap, MmapOptions};
+use mpc_iris_code::{Template, COLS, ROWS};
+use rayon::prelude::*;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Read},
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+
+/// Identifies binary template files written by `generate` and `convert`.
+const MAGIC: [u8; 8] = *b"MPCTMPL\0";
+
+/// Current version of the header layout.
+const VERSION: u32 = 1;
+
+/// Written in native byte order, so it reads differently on a machine with
+/// the other byte order.
+const ENDIANNESS: u32 = 0x0102_0304;
+
+/// Size of the header in bytes. Templates follow it, which keeps them aligned.
+pub const HEADER_SIZE: usize = size_of::<Header>();
+
+/// Size of the checksummed chunks when verifying in parallel.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Header of a binary template file, followed by packed [`Template`]s.
+///
+/// Unlike JSON, templates can be read without parsing, so the file is
+/// memory mapped and split among threads.
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct Header {
+    magic:           [u8; 8],
+    version:         u32,
+    endianness:      u32,
+    rows:            u32,
+    cols:            u32,
+    count:           u64,
+    /// CRC32 of the templates.
+    data_checksum:   u32,
+    /// CRC32 of the header with this field zeroed.
+    header_checksum: u32,
+    reserved:        [u8; 24],
+}
+
+impl Header {
+    pub fn new() -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            endianness: ENDIANNESS,
+            rows: ROWS as u32,
+            cols: COLS as u32,
+            ..Self::zeroed()
+        }
+    }
+
+    /// Number of templates.
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
+
+    /// Account for `data`, whole templates, appended to the file.
+    pub fn append(&mut self, data: &[u8]) {
+        debug_assert!(data.len().is_multiple_of(size_of::<Template>()));
+        let mut hasher = Hasher::new_with_initial(self.data_checksum);
+        hasher.update(data);
+        self.data_checksum = hasher.finalize();
+        self.count += (data.len() / size_of::<Template>()) as u64;
+    }
+
+    /// Header as written to disk, with its checksum.
+    fn sealed(&self) -> Self {
+        Self {
+            header_checksum: self.compute_checksum(),
+            ..*self
+        }
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        let header = Self {
+            header_checksum: 0,
+            ..*self
+        };
+        crc32fast::hash(bytes_of(&header))
+    }
+
+    fn validate(&self, path: &Path) -> Result<()> {
+        ensure!(
+            self.magic == MAGIC,
+            "File {path:?} is not a binary template file."
+        );
+        ensure!(
+            self.version == VERSION,
+            "File {path:?} has format version {}, expected {VERSION}.",
+            self.version
+        );
+        ensure!(
+            self.endianness == ENDIANNESS,
+            "File {path:?} was written on a machine with a different byte order."
+        );
+        ensure!(
+            self.header_checksum == self.compute_checksum(),
+            "File {path:?} has a corrupted header."
+        );
+        ensure!(
+            self.rows as usize == ROWS && self.cols as usize == COLS,
+            "File {path:?} has templates of {}x{}, expected {ROWS}x{COLS}.",
+            self.rows,
+            self.cols
+        );
+        Ok(())
+    }
+}
+
+/// Whether the file at `path` is a binary template file rather than JSON.
+pub fn is_binary(path: &Path) -> Result<bool> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open file at {path:?}"))?;
+    let mut magic = [0_u8; MAGIC.len()];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == MAGIC),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replace the placeholder header of a new file once all templates are
+/// written. Until then the file has no valid header.
+pub async fn write_header(file: &mut tokio::fs::File, header: &Header) -> Result<()> {
+    file.seek(SeekFrom::Start(0)).await?;
+    file.write_all(bytes_of(&header.sealed())).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// A memory mapped binary template file.
+pub struct TemplateFile {
+    mmap: Mmap,
+}
+
+impl TemplateFile {
+    /// Map the file at `path`, checking the header and, in parallel, the
+    /// checksum of the templates.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open file at {path:?}"))?;
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+        if mmap.len() < HEADER_SIZE {
+            bail!("File {path:?} is too short for a header.");
+        }
+        let mut header = Header::zeroed();
+        bytes_of_mut(&mut header).copy_from_slice(&mmap[..HEADER_SIZE]);
+        header.validate(path)?;
+        let size = header.count() * size_of::<Template>();
+        ensure!(
+            mmap.len() - HEADER_SIZE == size,
+            "File {path:?} has {} bytes of templates, header says {size}. It may be truncated.",
+            mmap.len() - HEADER_SIZE
+        );
+        let checksum = mmap[HEADER_SIZE..]
+            .par_chunks(CHUNK_SIZE)
+            .map(|chunk| {
+                let mut hasher = Hasher::new();
+                hasher.update(chunk);
+                hasher
+            })
+            .reduce(Hasher::new, |mut left, right| {
+                left.combine(&right);
+                left
+            })
+            .finalize();
+        ensure!(
+            checksum == header.data_checksum,
+            "File {path:?} has corrupted templates."
+        );
+        Ok(Self { mmap })
+    }
+
+    pub fn templates(&self) -> &[Template] {
+        cast_slice(&self.mmap[HEADER_SIZE..])
+    }
+}
+
+/// Path of the identifiers of the templates in a binary template file, one
+/// line per template like the index written by `prepare`.
+pub fn ids_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".ids");
+    path.into()
+}
+
+/// Read the identifiers of the templates in the file at `path`, if any.
+///
+/// Templates past the end of the file or with an empty line have no
+/// identifier.
+pub fn read_ids(path: &Path) -> Result<Option<impl Iterator<Item = Result<Option<String>>>>> {
+    let path = ids_path(path);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to open identifiers {path:?}"))
+        }
+    };
+    Ok(Some(BufReader::new(file).lines().map(|line| {
+        let line = line?;
+        Ok((!line.is_empty()).then_some(line))
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+    use std::fs;
+
+    /// Write a file of `templates` with `header`, as sealed by `seal`.
+    fn write(path: &Path, templates: &[Template], seal: impl FnOnce(Header) -> Header) {
+        let mut header = Header::new();
+        header.append(cast_slice(templates));
+        let header = seal(header);
+        fs::write(path, [bytes_of(&header), cast_slice(templates)].concat()).unwrap();
+    }
+
+    fn open_err(path: &Path) -> String {
+        TemplateFile::open(path).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("templates");
+
+        // Spans several checksummed chunks, the last of them partial.
+        let count = 3 * CHUNK_SIZE / size_of::<Template>() + 7;
+        let templates = (0..count)
+            .map(|_| thread_rng().gen())
+            .collect::<Vec<Template>>();
+        write(&path, &templates, |header| header.sealed());
+        assert!(is_binary(&path).unwrap());
+        assert_eq!(TemplateFile::open(&path).unwrap().templates(), templates);
+
+        // Any corrupted chunk is noticed.
+        for offset in [0, CHUNK_SIZE + 5, count * size_of::<Template>() - 1] {
+            let mut bytes = fs::read(&path).unwrap();
+            bytes[HEADER_SIZE + offset] ^= 1;
+            let corrupted = dir.path().join("corrupted");
+            fs::write(&corrupted, bytes).unwrap();
+            assert!(open_err(&corrupted).contains("corrupted templates"));
+        }
+
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len((HEADER_SIZE + size_of::<Template>()) as u64)
+            .unwrap();
+        assert!(open_err(&path).contains("truncated"));
+        file.set_len(HEADER_SIZE as u64 - 1).unwrap();
+        assert!(open_err(&path).contains("too short"));
+    }
+
+    #[test]
+    fn test_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("templates");
+        let templates = [thread_rng().gen::<Template>()];
+        type Corrupt = fn(&mut Header);
+        let corruptions: [(Corrupt, &str); 6] = [
+            (|h| h.magic[0] = b'X', "not a binary template file"),
+            (|h| h.version = 2, "format version 2"),
+            (|h| h.endianness = ENDIANNESS.swap_bytes(), "byte order"),
+            (|h| h.rows += 1, "templates of"),
+            (|h| h.cols = 1, "templates of"),
+            (|h| h.data_checksum ^= 1, "corrupted templates"),
+        ];
+        for (corrupt, expected) in corruptions {
+            write(&path, &templates, |mut header| {
+                corrupt(&mut header);
+                header.sealed()
+            });
+            let err = open_err(&path);
+            assert!(err.contains(expected), "{err}");
+        }
+
+        // Fields changed after sealing fail the header checksum.
+        write(&path, &templates, |header| Header {
+            count: 2,
+            ..header.sealed()
+        });
+        assert!(open_err(&path).contains("corrupted header"));
+    }
+
+    #[test]
+    fn test_is_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input");
+        for (contents, binary) in [
+            (&b""[..], false),
+            (&MAGIC[..4], false),
+            (&b"[{\"pattern\": \"\"}]"[..], false),
+            (&MAGIC[..], true),
+        ] {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(is_binary(&path).unwrap(), binary, "{contents:?}");
+        }
+        assert!(is_binary(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use crate::{
+    format::{self, HEADER_SIZE},
+    ids,
+    merkle::{self, Builder},
+    protocol::{Channel, Message},
+    MAX_ENROLL,
+};
+use anyhow::{bail, ensure, Context, Result};
+use bytemuck::{bytes_of, cast_slice};
+use std::{
+    collections::BTreeSet,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+
+/// Set of deleted entries, persisted next to the data files.
+///
+/// The file starts with the size of an entry in bytes in the data files it
+/// applies to, followed by the indices of deleted entries, all as `u64`.
+/// Deleted entries remain in the data files until they are compacted.
+pub struct Tombstones {
+    path:       PathBuf,
+    entry_size: usize,
+    entries:    BTreeSet<usize>,
+}
+
+impl Tombstones {
+    /// Read tombstones from `path`, or start empty if it does not exist.
+    pub fn open(path: &Path, entry_size: usize) -> Result<Self> {
+        let entries = match read(path)? {
+            Some((size, entries)) => {
+                ensure!(
+                    size == entry_size,
+                    "Tombstone file {path:?} is for entries of {size} bytes, expected \
+                     {entry_size}."
+                );
+                entries
+            }
+            None => BTreeSet::new(),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            entry_size,
+            entries,
+        })
+    }
+
+    pub fn contains(&self, entry: usize) -> bool {
+        self.entries.contains(&entry)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Durably add entries, returning those that were not deleted before.
+    pub fn insert(&mut self, entries: &[usize]) -> Result<Vec<usize>> {
+        let new = entries
+            .iter()
+            .copied()
+            .filter(|&entry| !self.entries.contains(&entry))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        if new.is_empty() {
+            return Ok(new);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open tombstones {:?}", self.path))?;
+        // An interrupted insert may have left part of a value, which would
+        // misalign those appended after it.
+        let len = file.metadata()?.len();
+        let torn = len % size_of::<u64>() as u64;
+        if torn != 0 {
+            eprintln!(
+                "Discarding {torn} bytes of tombstones {:?} left by an interrupted deletion.",
+                self.path
+            );
+            file.set_len(len - torn)?;
+        }
+        if len < size_of::<u64>() as u64 {
+            file.write_all(bytes_of(&(self.entry_size as u64)))?;
+        }
+        let values = new.iter().map(|&i| i as u64).collect::<Vec<_>>();
+        file.write_all(cast_slice(&values))?;
+        file.sync_data()?;
+        self.entries.extend(&new);
+        Ok(new)
+    }
+}
+
+/// Read the entry size and deleted entries, if the file exists.
+///
+/// Part of a value at the end is left by an interrupted insert and ignored,
+/// as the deletion was not acknowledged.
+fn read(path: &Path) -> Result<Option<(usize, BTreeSet<usize>)>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read tombstones {path:?}")),
+    };
+    let torn = bytes.len() % size_of::<u64>();
+    if torn != 0 {
+        eprintln!("Ignoring {torn} bytes of tombstones {path:?} left by an interrupted deletion.");
+    }
+    if bytes.len() < size_of::<u64>() {
+        return Ok(None);
+    }
+    let mut values = bytes
+        .chunks_exact(size_of::<u64>())
+        .map(|chunk| u64::from_ne_bytes(chunk.try_into().unwrap()) as usize);
+    let entry_size = values.next().unwrap();
+    Ok(Some((entry_size, values.collect())))
+}
+
+/// Add the entries in the `payload` of a delete frame to the tombstones at
+/// `path`.
+///
+/// The resolver sends the number of records per entry, so the tombstones can
+/// be applied to files of `count` records of `record_size` bytes. Replies on
+/// `channel` with the total number of deleted entries.
+pub async fn receive(
+    channel: &Channel,
+    payload: &[u8],
+    path: &Path,
+    count: usize,
+    record_size: usize,
+) -> Result<usize> {
+    let (records, entries) = parse(payload, count)?;
+    let path = path.to_path_buf();
+    let total = tokio::task::spawn_blocking(move || {
+        let mut tombstones = Tombstones::open(&path, records * record_size)?;
+        tombstones.insert(&entries)?;
+        Result::<_>::Ok(tombstones.len())
+    })
+    .await??;
+    channel
+        .send(Message::Deleted, &[bytes_of(&(total as u64))])
+        .await?;
+    Ok(total)
+}
+
+/// Parse the `payload` of a delete frame for files of `count` records,
+/// returning the records per entry and the entries to delete.
+fn parse(payload: &[u8], count: usize) -> Result<(usize, Vec<usize>)> {
+    let length = payload.len();
+    if length < size_of::<u64>() || !length.is_multiple_of(size_of::<u64>()) {
+        bail!("Deletion of {length} bytes, expected records per entry and entries.");
+    }
+    let mut values = payload
+        .chunks_exact(size_of::<u64>())
+        .map(|chunk| u64::from_ne_bytes(chunk.try_into().unwrap()) as usize);
+    let records = values.next().unwrap();
+    let entries = values.collect::<Vec<_>>();
+    if entries.is_empty() || entries.len() > MAX_ENROLL {
+        bail!(
+            "Deletion of {} entries, at most {MAX_ENROLL} allowed.",
+            entries.len()
+        );
+    }
+    if records == 0 || !count.is_multiple_of(records) {
+        bail!("{count} records are not a multiple of {records} records per entry.");
+    }
+    if let Some(entry) = entries.iter().find(|&&e| e >= count / records) {
+        bail!(
+            "Entry {entry} out of range, there are {} entries.",
+            count / records
+        );
+    }
+    Ok((records, entries))
+}
+
+/// Remove deleted entries from data files and an optional identifier index,
+/// then clear the tombstones.
+///
+/// Deleted entries are first overwritten with zeros in place. The remaining
+/// entries are then copied to a new file which replaces the original, so
+/// indices shift down past every deleted entry. All parties must compact with
+/// the same tombstones to keep indices consistent. Returns the number of
+/// remaining entries.
+///
+/// Overwriting in place does not reach copies the file system or storage
+/// device may keep elsewhere, e.g. on copy-on-write file systems or SSDs.
+pub fn compact(
+    tombstones: &Path,
+    files: &[PathBuf],
+    ids: Option<&Path>,
+    legacy: bool,
+) -> Result<usize> {
+    let (entry_size, entries) =
+        read(tombstones)?.with_context(|| format!("No tombstones at {tombstones:?}"))?;
+    ensure!(entry_size > 0, "Tombstone file {tombstones:?} invalid.");
+    let tombstones = Tombstones {
+        path: tombstones.to_path_buf(),
+        entry_size,
+        entries,
+    };
+
+    let mut remaining = None;
+    for path in files {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {path:?}"))?;
+        let (offset, mut header) = if legacy {
+            (0, None)
+        } else {
+            let header = format::read_header(&mut file, path)?;
+            format::discard_uncommitted(&mut file, path, &header)?;
+            ensure!(
+                header.expanded() == 0,
+                "File {path:?} is a {}, whose records can not be compacted without changing those \
+                 expanded from its seed.",
+                header.kind()
+            );
+            let size = header.records() * header.kind().record_size();
+            ensure!(
+                size == entry_size,
+                "File {path:?} has entries of {size} bytes, the tombstones are for {entry_size}."
+            );
+            (HEADER_SIZE, Some(header))
+        };
+        let size = file.metadata()?.len() as usize - offset;
+        ensure!(
+            size.is_multiple_of(entry_size),
+            "File {path:?} is not a multiple of the {entry_size} byte entries."
+        );
+        let count = size / entry_size;
+        if let Some(last) = tombstones.entries.last() {
+            ensure!(
+                *last < count,
+                "Entry {last} out of range, {path:?} has {count}."
+            );
+        }
+        let kept = count - tombstones.len();
+        if *remaining.get_or_insert(kept) != kept {
+            bail!("File {path:?} has a different number of entries.");
+        }
+
+        // Overwrite deleted entries in place.
+        let zeros = vec![0_u8; entry_size];
+        for &entry in &tombstones.entries {
+            file.seek(SeekFrom::Start((offset + entry * entry_size) as u64))?;
+            file.write_all(&zeros)?;
+        }
+        file.sync_data()?;
+
+        // Copy remaining entries to a new file and replace the original.
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".compacting");
+        let temp = PathBuf::from(temp);
+        {
+            let mut input = BufReader::new(File::open(path)?);
+            input.seek(SeekFrom::Start(offset as u64))?;
+            let mut output = BufWriter::new(File::create(&temp)?);
+            output.write_all(&vec![0_u8; offset])?;
+            if let Some(header) = &mut header {
+                header.clear();
+            }
+            let mut tree = Builder::new();
+            let mut buffer = vec![0_u8; entry_size];
+            for entry in 0..count {
+                input.read_exact(&mut buffer)?;
+                if !tombstones.contains(entry) {
+                    output.write_all(&buffer)?;
+                    if let Some(header) = &mut header {
+                        header.append(&buffer);
+                        tree.update(&buffer);
+                    }
+                }
+            }
+            let mut output = output.into_inner()?;
+            if let Some(header) = &mut header {
+                let leaves = tree.finish();
+                merkle::write_leaves(&temp, &leaves)?;
+                header.set_root(&leaves);
+                format::write_header(&mut output, header)?;
+            }
+            output.sync_all()?;
+        }
+        fs::rename(&temp, path)?;
+        if let Some(header) = &header {
+            fs::rename(merkle::tree_path(&temp), merkle::tree_path(path))?;
+            eprintln!("Compacted {path:?} has Merkle root {}.", header.root());
+        }
+        eprintln!("Compacted {path:?} from {count} to {kept} entries of {entry_size} bytes.");
+    }
+
+    if let Some(path) = ids {
+        ids::compact(path, |entry| tombstones.contains(entry))?;
+    }
+
+    // Only clear the tombstones once all files are compacted.
+    fs::remove_file(&tombstones.path)?;
+    Ok(remaining.unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{DataFile, Dataset, Header, Kind, Writer},
+        ids::{IdWriter, Ids},
+    };
+    use mpc_iris_code::EncodedBits;
+    use rand::{thread_rng, Rng};
+
+    const ENTRY_SIZE: usize = size_of::<EncodedBits>();
+
+    fn payload(records: u64, entries: &[u64]) -> Vec<u8> {
+        cast_slice(&[&[records], entries].concat()).to_vec()
+    }
+
+    #[test]
+    fn test_insert() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tombstones");
+        let mut tombstones = Tombstones::open(&path, ENTRY_SIZE).unwrap();
+        assert_eq!(tombstones.insert(&[5, 2, 5]).unwrap(), [2, 5]);
+        assert!(tombstones.insert(&[2, 5]).unwrap().is_empty());
+        assert_eq!(tombstones.insert(&[7, 2]).unwrap(), [7]);
+
+        let tombstones = Tombstones::open(&path, ENTRY_SIZE).unwrap();
+        assert_eq!(tombstones.len(), 3);
+        assert!(tombstones.contains(7) && !tombstones.contains(3));
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            4 * size_of::<u64>() as u64
+        );
+    }
+
+    #[test]
+    fn test_entry_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tombstones");
+        Tombstones::open(&path, ENTRY_SIZE)
+            .unwrap()
+            .insert(&[1])
+            .unwrap();
+        let err = Tombstones::open(&path, 2 * ENTRY_SIZE).err().unwrap();
+        assert!(err.to_string().contains("entries of"), "{err}");
+    }
+
+    #[test]
+    fn test_torn() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tombstones");
+        Tombstones::open(&path, ENTRY_SIZE)
+            .unwrap()
+            .insert(&[1, 4])
+            .unwrap();
+
+        // An insert interrupted mid-value is ignored, and discarded by the
+        // next one.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0xff; 3]).unwrap();
+        drop(file);
+        let mut tombstones = Tombstones::open(&path, ENTRY_SIZE).unwrap();
+        assert_eq!(tombstones.len(), 2);
+        tombstones.insert(&[3]).unwrap();
+        let tombstones = Tombstones::open(&path, ENTRY_SIZE).unwrap();
+        assert_eq!(tombstones.entries, BTreeSet::from([1, 3, 4]));
+
+        // Also when it was the first.
+        fs::write(&path, [0x10; 5]).unwrap();
+        let mut tombstones = Tombstones::open(&path, ENTRY_SIZE).unwrap();
+        assert_eq!(tombstones.len(), 0);
+        tombstones.insert(&[2]).unwrap();
+        let tombstones = Tombstones::open(&path, ENTRY_SIZE).unwrap();
+        assert_eq!(tombstones.entries, BTreeSet::from([2]));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&payload(2, &[0, 3]), 8).unwrap(), (2, vec![0, 3]));
+        let err = parse(&payload(2, &[4]), 8).unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{err}");
+        assert!(parse(&payload(3, &[0]), 8).is_err());
+        assert!(parse(&payload(2, &[]), 8).is_err());
+        assert!(parse(&payload(2, &[0])[1..], 8).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("share");
+        let records = (0..6)
+            .map(|_| thread_rng().gen())
+            .collect::<Vec<EncodedBits>>();
+        let header = Header::new(Kind::Share, 0, 2, 2, 1, Dataset::default());
+        let mut writer = Writer::create(&path, header).await.unwrap();
+        writer.write(cast_slice(&records)).await.unwrap();
+        writer.finish().await.unwrap();
+        let ids_path = dir.path().join("ids");
+        let mut ids = IdWriter::create(&ids_path).unwrap();
+        for id in ["a", "", "c", "d", "", "f"] {
+            ids.push(Some(id).filter(|id| !id.is_empty())).unwrap();
+        }
+        ids.finish().unwrap();
+        let tombstones = dir.path().join("tombstones");
+        Tombstones::open(&tombstones, ENTRY_SIZE)
+            .unwrap()
+            .insert(&[0, 3, 4])
+            .unwrap();
+
+        let files = [path.clone()];
+        assert_eq!(
+            compact(&tombstones, &files, Some(&ids_path), false).unwrap(),
+            3
+        );
+        assert!(!tombstones.exists());
+
+        // Identifiers stay with their records, at the shifted indices.
+        let data_file = DataFile::open(&path, Kind::Share, false).unwrap();
+        let kept = [records[1], records[2], records[5]];
+        assert_eq!(cast_slice::<_, EncodedBits>(&data_file), kept);
+        let header = data_file.header().unwrap();
+        assert!(merkle::check(&path, header).unwrap().is_empty());
+        let ids = Ids::open(&ids_path).unwrap();
+        assert_eq!(ids.get(0), None);
+        assert_eq!(ids.find("c"), Some(1));
+        assert_eq!(ids.find("f"), Some(2));
+        assert_eq!(ids.find("a"), None);
+        assert_eq!(ids.find("d"), None);
+    }
+
+    #[tokio::test]
+    async fn test_compact_out_of_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("share");
+        let header = Header::new(Kind::Share, 0, 2, 2, 1, Dataset::default());
+        let mut writer = Writer::create(&path, header).await.unwrap();
+        writer
+            .write(cast_slice(&[EncodedBits::default(); 2]))
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+        let tombstones = dir.path().join("tombstones");
+        Tombstones::open(&tombstones, ENTRY_SIZE)
+            .unwrap()
+            .insert(&[2])
+            .unwrap();
+
+        let err = compact(&tombstones, &[path], None, false).unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{err}");
+        assert!(tombstones.exists());
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::{bail, ensure, Context, Result};
+use bytemuck::{bytes_of, cast_slice};
+use memmap::{Mmap, MmapOptions};
+use mpc_iris_code::{share_distances, DistanceEngine, EncodedBits};
+use rand::{thread_rng, Rng};
+use rayon::prelude::*;
+use std::{
+    cmp::min, fs::File, io::Write, mem::size_of, ops::Range, os::unix::fs::FileExt, path::Path,
+    sync::Arc,
+};
+
+/// Preprocessed multiplication material for secret-shared queries.
+///
+/// The file is a sequence of slots, one per query. Each slot contains this
+/// party's shares of a random mask for each of the `parts` query parts,
+/// followed by its shares of the dot products of the masks with every record,
+/// interleaved per record like the participant's results. A slot is consumed
+/// by overwriting its mask shares with zeros, so it can never be used twice.
+pub struct Triples {
+    file:  File,
+    mmap:  Arc<Mmap>,
+    count: usize,
+    parts: usize,
+    slots: usize,
+}
+
+impl Triples {
+    pub fn open(path: &Path, count: usize, parts: usize) -> Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open triples at {path:?}"))?;
+        let mmap = Arc::new(unsafe { MmapOptions::new().map(&file)? });
+        let slot_size = Self::slot_size(count, parts);
+        ensure!(
+            mmap.len() % slot_size == 0,
+            "Triples file {path:?} invalid for {count} records of {parts} parts."
+        );
+        let slots = mmap.len() / slot_size;
+        Ok(Self {
+            file,
+            mmap,
+            count,
+            parts,
+            slots,
+        })
+    }
+
+    pub fn slot_size(count: usize, parts: usize) -> usize {
+        parts * (size_of::<EncodedBits>() + count * size_of::<[u16; 31]>())
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// This party's shares of the random masks for `slot`, one per part.
+    pub fn mask_shares(&self, slot: usize) -> Result<&[EncodedBits]> {
+        if slot >= self.slots {
+            bail!("Slot {slot} out of range, only {} available.", self.slots);
+        }
+        let start = slot * Self::slot_size(self.count, self.parts);
+        let shares: &[EncodedBits] =
+            cast_slice(&self.mmap[start..start + self.parts * size_of::<EncodedBits>()]);
+        if shares[0].0.iter().all(|&v| v == 0) {
+            bail!("Slot {slot} has already been used.");
+        }
+        Ok(shares)
+    }
+
+    /// Mark `slot` as used by durably zeroing its first mask share.
+    pub fn consume(&self, slot: usize) -> Result<()> {
+        let start = slot * Self::slot_size(self.count, self.parts);
+        self.file
+            .write_all_at(bytes_of(&EncodedBits::default()), start as u64)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Shared handle and byte range of the product shares for `slot`.
+    pub fn products(&self, slot: usize) -> (Arc<Mmap>, Range<usize>) {
+        let start =
+            slot * Self::slot_size(self.count, self.parts) + self.parts * size_of::<EncodedBits>();
+        (
+            self.mmap.clone(),
+            start..start + self.parts * self.count * size_of::<[u16; 31]>(),
+        )
+    }
+}
+
+/// Deal `slots` slots of triples to the `outputs` of every party, for the
+/// `count` records of each part. The dealer sees the combined records, given
+/// by `record(part, index)`. Reports the bytes written to each output.
+pub fn deal<W: Write>(
+    outputs: &mut [W],
+    parts: usize,
+    count: usize,
+    slots: usize,
+    record: impl Fn(usize, usize) -> EncodedBits + Sync,
+    progress: impl Fn(u64),
+) -> Result<()> {
+    const BATCH_SIZE: usize = 1000;
+    let parties = outputs.len();
+    let mut rng = thread_rng();
+    for _ in 0..slots {
+        // Random query mask for each part
+        let masks = (0..parts).map(|_| rng.gen()).collect::<Vec<EncodedBits>>();
+        let mask_shares = masks
+            .iter()
+            .map(|mask| mask.share(parties))
+            .collect::<Vec<_>>();
+        for (i, output) in outputs.iter_mut().enumerate() {
+            for mask_shares in &mask_shares {
+                output.write_all(bytes_of(&mask_shares[i]))?;
+            }
+        }
+        progress((parts * parties * size_of::<EncodedBits>()) as u64);
+
+        // Shares of the products of the masks with all records, interleaved
+        let engines = masks.iter().map(DistanceEngine::new).collect::<Vec<_>>();
+        for start in (0..count).step_by(BATCH_SIZE) {
+            let end = min(start + BATCH_SIZE, count);
+            let mut products = vec![[0_u16; 31]; (end - start) * parts];
+            for (part, engine) in engines.iter().enumerate() {
+                let entries = (start..end)
+                    .into_par_iter()
+                    .map(|i| record(part, i))
+                    .collect::<Vec<EncodedBits>>();
+                let mut part_products = vec![[0_u16; 31]; entries.len()];
+                engine.batch_process(&mut part_products, &entries);
+                for (i, product) in part_products.into_iter().enumerate() {
+                    products[i * parts + part] = product;
+                }
+            }
+            let product_shares = products
+                .par_iter()
+                .map(|product| share_distances(product, parties))
+                .collect::<Vec<_>>();
+            for product_shares in product_shares.iter() {
+                for (output, share) in outputs.iter_mut().zip(product_shares.iter()) {
+                    output.write_all(bytes_of(share))?;
+                }
+            }
+            progress((products.len() * parties * size_of::<[u16; 31]>()) as u64);
+        }
+    }
+    Ok(())
+}
+
+/// Add preprocessed product shares to a batch of results.
+pub fn add_products(out: &mut [[u16; 31]], products: &[u8]) {
+    let products: &[[u16; 31]] = cast_slice(products);
+    for (result, product) in out.iter_mut().zip(products.iter()) {
+        for (r, &p) in result.iter_mut().zip(product.iter()) {
+            *r = r.wrapping_add(p);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Deal triples for random records to files of `parties` parties.
+    fn dealt(
+        dir: &Path,
+        parties: usize,
+        parts: usize,
+        count: usize,
+        slots: usize,
+    ) -> (Vec<Vec<EncodedBits>>, Vec<Triples>) {
+        let mut rng = thread_rng();
+        let records = (0..parts)
+            .map(|_| (0..count).map(|_| rng.gen()).collect::<Vec<EncodedBits>>())
+            .collect::<Vec<_>>();
+        let mut outputs = vec![Vec::new(); parties];
+        deal(
+            &mut outputs,
+            parts,
+            count,
+            slots,
+            |part, i| records[part][i],
+            |_| (),
+        )
+        .unwrap();
+        let triples = outputs
+            .iter()
+            .enumerate()
+            .map(|(party, output)| {
+                let path = dir.join(format!("triples-{party}"));
+                fs::write(&path, output).unwrap();
+                Triples::open(&path, count, parts).unwrap()
+            })
+            .collect();
+        (records, triples)
+    }
+
+    #[test]
+    fn test_products() {
+        let dir = tempfile::tempdir().unwrap();
+        let (parts, count) = (2, 20);
+        let (records, triples) = dealt(dir.path(), 3, parts, count, 2);
+        for slot in 0..2 {
+            // The mask shares add up to the mask, and the product shares to
+            // its products with every record.
+            let masks = (0..parts)
+                .map(|part| {
+                    triples
+                        .iter()
+                        .map(|t| t.mask_shares(slot).unwrap()[part])
+                        .sum::<EncodedBits>()
+                })
+                .collect::<Vec<_>>();
+            let mut products = vec![[0_u16; 31]; count * parts];
+            for t in &triples {
+                let (mmap, range) = t.products(slot);
+                add_products(&mut products, &mmap[range]);
+            }
+            for (part, mask) in masks.iter().enumerate() {
+                let mut expected = vec![[0_u16; 31]; count];
+                DistanceEngine::new(mask).batch_process(&mut expected, &records[part]);
+                for (i, expected) in expected.iter().enumerate() {
+                    assert_eq!(&products[i * parts + part], expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_consume() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, triples) = dealt(dir.path(), 2, 1, 10, 2);
+        let triples = &triples[0];
+        assert_eq!(triples.slots(), 2);
+        triples.mask_shares(0).unwrap();
+        triples.consume(0).unwrap();
+        assert!(triples.mask_shares(0).is_err());
+        assert!(triples.mask_shares(1).is_ok());
+        assert!(triples.mask_shares(2).is_err());
+
+        // Consumption is durable.
+        let reopened = Triples::open(&dir.path().join("triples-0"), 10, 1).unwrap();
+        assert!(reopened.mask_shares(0).is_err());
+        assert!(reopened.mask_shares(1).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_size() {
+        let dir = tempfile::tempdir().unwrap();
+        dealt(dir.path(), 2, 1, 10, 1);
+        assert!(Triples::open(&dir.path().join("triples-0"), 11, 1).is_err());
+    }
+}
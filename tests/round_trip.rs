@@ -0,0 +1,149 @@
+//! Templates prepared from JSON, newline-delimited JSON and binary input are
+//! shared alike, and decrypt back to the input, except for deleted entries.
+//! Generated templates can be piped into preparation.
+
+use mpc_iris_code::{Bits, Template};
+use rand::{thread_rng, Rng};
+use serde_json::json;
+use std::{
+    fs,
+    io::Write,
+    mem::size_of,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+/// More than the batches the input is read in.
+const COUNT: usize = 1100;
+
+fn run(args: &[&str], dir: &Path) {
+    run_with_input(args, dir, &[]);
+}
+
+/// Run with `input` on standard input, returning the standard output.
+fn run_with_input(args: &[&str], dir: &Path, input: &[u8]) -> Vec<u8> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mpc-iris-code"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let Output {
+        status,
+        stdout,
+        stderr,
+    } = child.wait_with_output().unwrap();
+    assert!(
+        status.success(),
+        "{args:?} failed: {}",
+        String::from_utf8_lossy(&stderr)
+    );
+    stdout
+}
+
+/// Decrypt the shares prepared from `templates` and check they are unchanged.
+fn check_decrypted(templates: &[Template], dir: &Path, input: &str) {
+    run(&["decrypt", "--replace", "output.json", "shared", "2"], dir);
+    let output: Vec<Template> =
+        serde_json::from_slice(&fs::read(dir.join("output.json")).unwrap()).unwrap();
+    assert_eq!(output.len(), templates.len(), "{input}");
+    for (output, template) in output.iter().zip(templates) {
+        // Pattern bits outside the mask are not shared.
+        assert_eq!(output.mask, template.mask, "{input}");
+        assert_eq!(
+            output.pattern,
+            &template.pattern & &template.mask,
+            "{input}"
+        );
+    }
+}
+
+fn id(entry: usize) -> Option<String> {
+    (entry % 3 != 1).then(|| format!("entry-{entry}"))
+}
+
+#[test]
+fn test_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let templates = (0..COUNT)
+        .map(|_| thread_rng().gen())
+        .collect::<Vec<Template>>();
+    let entries = templates
+        .iter()
+        .enumerate()
+        .map(|(entry, template)| {
+            let mut value = serde_json::to_value(template).unwrap();
+            if let Some(id) = id(entry) {
+                value["id"] = json!(id);
+            }
+            value
+        })
+        .collect::<Vec<_>>();
+    fs::write(
+        dir.join("input.json"),
+        serde_json::to_vec(&entries).unwrap(),
+    )
+    .unwrap();
+    let lines = entries
+        .iter()
+        .map(|entry| format!("{entry}\n"))
+        .collect::<String>();
+    fs::write(dir.join("input.ndjson"), lines).unwrap();
+    run(&["convert", "input.json", "input.bin"], dir);
+
+    for input in ["input.json", "input.ndjson", "input.bin"] {
+        run(&["prepare", input, "2", "shared"], dir);
+        check_decrypted(&templates, dir, input);
+        let ids = fs::read_to_string(dir.join("shared.ids")).unwrap();
+        let expected = (0..COUNT)
+            .map(|entry| id(entry).unwrap_or_default() + "\n")
+            .collect::<String>();
+        assert_eq!(ids, expected, "{input}");
+    }
+
+    // The resolver's tombstones, the size of a mask followed by the deleted
+    // entries.
+    let deleted = [0, 5, COUNT - 1];
+    let tombstones = [size_of::<Bits>()]
+        .iter()
+        .chain(&deleted)
+        .flat_map(|&value| (value as u64).to_ne_bytes())
+        .collect::<Vec<_>>();
+    fs::write(dir.join("shared.tombstones"), tombstones).unwrap();
+    let remaining = templates
+        .iter()
+        .enumerate()
+        .filter(|(entry, _)| !deleted.contains(entry))
+        .map(|(_, template)| *template)
+        .collect::<Vec<_>>();
+    check_decrypted(&remaining, dir, "deleted");
+}
+
+#[test]
+fn test_standard_streams() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    for format in ["json", "ndjson"] {
+        let generated = run_with_input(
+            &["generate", "-", &COUNT.to_string(), "--format", format],
+            dir,
+            &[],
+        );
+        let templates: Vec<Template> = if format == "json" {
+            serde_json::from_slice(&generated).unwrap()
+        } else {
+            serde_json::Deserializer::from_slice(&generated)
+                .into_iter()
+                .map(Result::unwrap)
+                .collect()
+        };
+        assert_eq!(templates.len(), COUNT, "{format}");
+
+        run_with_input(&["prepare", "-", "2", "shared"], dir, &generated);
+        check_decrypted(&templates, dir, format);
+    }
+}